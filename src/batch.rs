@@ -0,0 +1,169 @@
+//! Apply a list of edits to a document as one change.
+//!
+//! Edit scripts can be written in JSON, as a list of objects:
+//!
+//! ```json
+//! [{"op": "set", "key": "package.version", "value": "1.2.0"},
+//!  {"op": "rename", "key": "dev-dependencies", "to": "dev_dependencies"}]
+//! ```
+//!
+//! or in TOML, as an array of tables called `edit`:
+//!
+//! ```toml
+//! [[edit]]
+//! op = "remove"
+//! key = "features.unstable"
+//! ```
+
+extern crate toml;
+
+use rustc_serialize::json::Json;
+
+use super::keypath::KeyPath;
+use super::error::Error;
+use super::document::Document;
+
+/// One change to make to a document.
+#[derive(Debug,PartialEq,Clone)]
+pub enum Edit {
+    Set(KeyPath, toml::Value),
+    Remove(KeyPath),
+    /// Change the last part of the key to the new name
+    Rename(KeyPath, String),
+    /// Add a value to the end of an array
+    Append(KeyPath, toml::Value),
+}
+
+/// A list of edits which are applied all together or not at all.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct EditBatch {
+    pub edits: Vec<Edit>,
+}
+
+pub fn json_to_toml(json: &Json) -> Result<toml::Value, String> {
+    Ok(match *json {
+        Json::I64(i) => toml::Value::Integer(i),
+        Json::U64(u) if u <= i64::MAX as u64 => toml::Value::Integer(u as i64),
+        Json::U64(u) => return Err(format!("{} is too big for a TOML integer", u)),
+        Json::F64(f) => toml::Value::Float(f),
+        Json::String(ref s) => toml::Value::String(s.clone()),
+        Json::Boolean(b) => toml::Value::Boolean(b),
+        Json::Array(ref items) => {
+            toml::Value::Array(items.iter().map(json_to_toml).collect::<Result<_, _>>()?)
+        },
+        Json::Object(ref obj) => {
+            let mut table = toml::Table::new();
+            for (k, v) in obj {
+                table.insert(k.clone(), json_to_toml(v)?);
+            }
+            toml::Value::Table(table)
+        },
+        Json::Null => return Err(String::from("null has no TOML equivalent")),
+    })
+}
+
+fn edit_from_table(i: usize, value: &toml::Value) -> Result<Edit, Error> {
+    let invalid = |why: &str| Error::InvalidScript(format!("edit {}: {}", i + 1, why));
+    let table = match *value {
+        toml::Value::Table(ref t) => t,
+        _ => return Err(invalid("expected a table")),
+    };
+    let string_field = |name: &str| match table.get(name) {
+        Some(toml::Value::String(s)) => Ok(s.clone()),
+        Some(_) => Err(invalid(&format!("\"{}\" should be a string", name))),
+        None => Err(invalid(&format!("missing \"{}\"", name))),
+    };
+    let value_field = || table.get("value").cloned().ok_or_else(|| invalid("missing \"value\""));
+    let key = KeyPath::parse(&string_field("key")?)?;
+    match string_field("op")?.as_str() {
+        "set" => Ok(Edit::Set(key, value_field()?)),
+        "remove" => Ok(Edit::Remove(key)),
+        "rename" => Ok(Edit::Rename(key, string_field("to")?)),
+        "append" => Ok(Edit::Append(key, value_field()?)),
+        op => Err(invalid(&format!("unknown op \"{}\"", op))),
+    }
+}
+
+impl EditBatch {
+    pub fn new() -> EditBatch {
+        EditBatch::default()
+    }
+
+    pub fn push(&mut self, edit: Edit) {
+        self.edits.push(edit);
+    }
+
+    fn from_values(values: &[toml::Value]) -> Result<EditBatch, Error> {
+        let edits = values.iter().enumerate().map(|(i, v)| edit_from_table(i, v)).collect::<Result<_, _>>()?;
+        Ok(EditBatch{edits})
+    }
+
+    /// Read edits from a JSON list of objects.
+    pub fn from_json(text: &str) -> Result<EditBatch, Error> {
+        let json = Json::from_str(text).map_err(|e| Error::InvalidScript(e.to_string()))?;
+        match json_to_toml(&json).map_err(Error::InvalidScript)? {
+            toml::Value::Array(values) => EditBatch::from_values(&values),
+            _ => Err(Error::InvalidScript(String::from("expected a list of edits"))),
+        }
+    }
+
+    /// Read edits from a TOML document with an `[[edit]]` table for each.
+    pub fn from_toml(text: &str) -> Result<EditBatch, Error> {
+        let doc = Document::parse(text)?;
+        match doc.get(&KeyPath::from_string("edit")) {
+            Some(toml::Value::Array(values)) => EditBatch::from_values(values),
+            _ => Err(Error::InvalidScript(String::from("expected [[edit]] tables"))),
+        }
+    }
+
+    /// Make every edit in turn. If one fails, the document is left as it was
+    /// and the error says which edit it was.
+    pub fn apply(&self, doc: &mut Document) -> Result<(), Error> {
+        let mut working = doc.clone();
+        for (i, edit) in self.edits.iter().enumerate() {
+            let res = match *edit {
+                Edit::Set(ref key, ref value) => working.set(key, value.clone()),
+                Edit::Remove(ref key) => working.remove(key).map(|_| ()),
+                Edit::Rename(ref key, ref name) => working.rename(key, name),
+                Edit::Append(ref key, ref value) => working.append(key, value.clone()),
+            };
+            res.map_err(|e| Error::Operation(i, Box::new(e)))?;
+        }
+        *doc = working;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_edit_batch_from_json_and_toml() {
+    let json = r#"[{"op": "set", "key": "a.b", "value": [1, 2.5]},
+                   {"op": "rename", "key": "c", "to": "d"}]"#;
+    let toml_script = "[[edit]]\nop = \"set\"\nkey = \"a.b\"\nvalue = [1, 2.5]\n\n\
+                       [[edit]]\nop = \"rename\"\nkey = \"c\"\nto = \"d\"\n";
+    let expected = EditBatch{edits: vec![
+        Edit::Set(KeyPath::from_string("a.b"),
+                  toml::Value::Array(vec![toml::Value::Integer(1), toml::Value::Float(2.5)])),
+        Edit::Rename(KeyPath::from_string("c"), String::from("d")),
+    ]};
+    assert_eq!(EditBatch::from_json(json), Ok(expected.clone()));
+    assert_eq!(EditBatch::from_toml(toml_script), Ok(expected));
+
+    assert_eq!(EditBatch::from_json(r#"[{"op": "set", "key": "a"}]"#),
+               Err(Error::InvalidScript(String::from("edit 1: missing \"value\""))));
+}
+
+#[test]
+fn test_edit_batch_apply() {
+    let mut doc = Document::parse("[a]\nx = 1\ny = [1]\n").unwrap();
+    let mut batch = EditBatch::new();
+    batch.push(Edit::Set(KeyPath::from_string("a.x"), toml::Value::Integer(2)));
+    batch.push(Edit::Append(KeyPath::from_string("a.y"), toml::Value::Integer(2)));
+    batch.apply(&mut doc).unwrap();
+    assert_eq!(doc.to_string(), "[a]\nx = 2\ny = [1, 2]\n");
+
+    // A failing edit leaves the document untouched
+    batch.push(Edit::Remove(KeyPath::from_string("a.z")));
+    assert_eq!(batch.apply(&mut doc),
+               Err(Error::Operation(2, Box::new(Error::KeyNotFound(KeyPath::from_string("a.z"))))));
+    assert_eq!(doc.to_string(), "[a]\nx = 2\ny = [1, 2]\n");
+}
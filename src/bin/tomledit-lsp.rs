@@ -0,0 +1,21 @@
+//! A language server for TOML files, talking LSP over standard input and
+//! output.
+
+extern crate tomledit;
+
+use std::io;
+use std::process;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match tomledit::lsp::run(&mut stdin.lock(), &mut stdout.lock()) {
+        Ok(true) => (),
+        // Exiting without a shutdown request first is an error
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("tomledit-lsp: {}", e);
+            process::exit(1);
+        },
+    }
+}
@@ -0,0 +1,172 @@
+//! Compare the values of two documents, ignoring how they're written.
+
+extern crate toml;
+
+use std::collections::HashMap;
+
+use super::keypath::{KeyPath, KeyPathComponent};
+use super::document::Document;
+
+/// A difference between two documents.
+#[derive(Debug,PartialEq,Clone)]
+pub enum Change {
+    Added(KeyPath, toml::Value),
+    Removed(KeyPath, toml::Value),
+    /// The old value, then the new one
+    Changed(KeyPath, toml::Value, toml::Value),
+}
+
+impl Change {
+    pub fn key(&self) -> &KeyPath {
+        match *self {
+            Change::Added(ref k, _) | Change::Removed(ref k, _) | Change::Changed(ref k, _, _) => k
+        }
+    }
+}
+
+/// Options for `compare`.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct CompareOptions {
+    /// Match the tables in these arrays by the value of a key, rather than by
+    /// position; e.g. `bin` → `name` pairs up `[[bin]]` entries by name. The
+    /// paths leave out array indices, so `package.dependencies` applies to
+    /// every `package[i].dependencies`.
+    pub match_by: HashMap<KeyPath, String>,
+}
+
+/// Compare the values of two documents.
+pub fn compare(old: &Document, new: &Document, options: &CompareOptions) -> Vec<Change> {
+    compare_values(old.values(), new.values(), options)
+}
+
+/// Compare two values, listing the differences in key order.
+///
+/// Elements of arrays are matched by position unless the options give a key
+/// to match them by. Changes are reported at the deepest path where the two
+/// values still have the same shape.
+pub fn compare_values(old: &toml::Value, new: &toml::Value, options: &CompareOptions) -> Vec<Change> {
+    let mut res = Vec::new();
+    compare_at(&KeyPath::new(), old, new, options, &mut res);
+    res
+}
+
+fn without_indices(key: &KeyPath) -> KeyPath {
+    KeyPath{parts: key.parts.iter().filter(|p| matches!(p, KeyPathComponent::Key(_))).cloned().collect()}
+}
+
+fn same_scalar(old: &toml::Value, new: &toml::Value) -> bool {
+    match (old, new) {
+        (toml::Value::Float(a), toml::Value::Float(b)) if a.is_nan() && b.is_nan() => true,
+        _ => old == new
+    }
+}
+
+fn compare_at(key: &KeyPath, old: &toml::Value, new: &toml::Value, options: &CompareOptions,
+              res: &mut Vec<Change>) {
+    match (old, new) {
+        (toml::Value::Table(a), toml::Value::Table(b)) => {
+            // Both maps are sorted, so merge the keys in order
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for k in keys {
+                let child = key.clone().append_key(k.clone());
+                match (a.get(k), b.get(k)) {
+                    (Some(x), Some(y)) => compare_at(&child, x, y, options, res),
+                    (Some(x), None) => res.push(Change::Removed(child, x.clone())),
+                    (None, Some(y)) => res.push(Change::Added(child, y.clone())),
+                    (None, None) => unreachable!(),
+                }
+            }
+        },
+        (toml::Value::Array(a), toml::Value::Array(b)) => {
+            match options.match_by.get(&without_indices(key)) {
+                Some(field) if keyed(a, field) && keyed(b, field) => {
+                    compare_keyed(key, a, b, field, options, res)
+                },
+                _ => {
+                    for i in 0..a.len().max(b.len()) {
+                        let child = key.clone().append_index(i);
+                        match (a.get(i), b.get(i)) {
+                            (Some(x), Some(y)) => compare_at(&child, x, y, options, res),
+                            (Some(x), None) => res.push(Change::Removed(child, x.clone())),
+                            (None, Some(y)) => res.push(Change::Added(child, y.clone())),
+                            (None, None) => unreachable!(),
+                        }
+                    }
+                }
+            }
+        },
+        _ => {
+            if !same_scalar(old, new) {
+                res.push(Change::Changed(key.clone(), old.clone(), new.clone()));
+            }
+        }
+    }
+}
+
+/// Are these all tables with a distinct value for the field?
+fn keyed(items: &[toml::Value], field: &str) -> bool {
+    let mut seen = Vec::new();
+    for item in items {
+        match item.lookup(field) {
+            Some(v) if !seen.contains(&v) => seen.push(v),
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Compare arrays of tables, pairing them up by a field. Removed elements
+/// are given by their index in the old array, others by the new index.
+fn compare_keyed(key: &KeyPath, a: &[toml::Value], b: &[toml::Value], field: &str,
+                 options: &CompareOptions, res: &mut Vec<Change>) {
+    for (i, x) in a.iter().enumerate() {
+        if !b.iter().any(|y| y.lookup(field) == x.lookup(field)) {
+            res.push(Change::Removed(key.clone().append_index(i), x.clone()));
+        }
+    }
+    for (j, y) in b.iter().enumerate() {
+        let child = key.clone().append_index(j);
+        match a.iter().find(|x| x.lookup(field) == y.lookup(field)) {
+            Some(x) => compare_at(&child, x, y, options, res),
+            None => res.push(Change::Added(child, y.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+fn changes(old: &str, new: &str, options: &CompareOptions) -> Vec<String> {
+    let old = Document::parse(old).unwrap();
+    let new = Document::parse(new).unwrap();
+    compare(&old, &new, options).iter().map(|c| match *c {
+        Change::Added(ref k, ref v) => format!("+{} {}", k.stringify(), v),
+        Change::Removed(ref k, ref v) => format!("-{} {}", k.stringify(), v),
+        Change::Changed(ref k, ref a, ref b) => format!("~{} {} -> {}", k.stringify(), a, b),
+    }).collect()
+}
+
+#[test]
+fn test_compare() {
+    let old = "# Defaults\n[a]\nx = 1\ny = [1, 2]\nz = \"same\"\n";
+    let new = "[a]\nz = 'same'\nx = 2\ny = [1]\nw = true\n";
+    assert_eq!(changes(old, new, &CompareOptions::default()), vec![
+        "+.a.w true", "~.a.x 1 -> 2", "-.a.y[1] 2",
+    ]);
+    assert!(changes(old, "a = {x = 1, y = [1, 2], z = \"same\"}", &CompareOptions::default()).is_empty());
+}
+
+#[test]
+fn test_compare_keyed() {
+    let old = "[[bin]]\nname = \"a\"\npath = \"a.rs\"\n[[bin]]\nname = \"b\"\n";
+    let new = "[[bin]]\nname = \"b\"\n[[bin]]\nname = \"c\"\n[[bin]]\nname = \"a\"\npath = \"src/a.rs\"\n";
+    assert_eq!(changes(old, new, &CompareOptions::default()).len(), 4);
+
+    let mut options = CompareOptions::default();
+    options.match_by.insert(KeyPath::from_string("bin"), String::from("name"));
+    let res = compare(&Document::parse(old).unwrap(), &Document::parse(new).unwrap(), &options);
+    assert_eq!(res.len(), 2);
+    assert_eq!(res[0].key(), &KeyPath::from_string("bin[1]"));
+    assert!(matches!(res[0], Change::Added(..)));
+    assert_eq!(changes(old, new, &options)[1], "~.bin[2].path \"a.rs\" -> \"src/a.rs\"");
+}
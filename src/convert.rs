@@ -0,0 +1,299 @@
+//! Convert documents to JSON, and JSON or YAML data to TOML.
+
+extern crate toml;
+
+use rustc_serialize::json::Json;
+use yaml_rust::{Yaml, YamlLoader};
+
+use super::keypath::KeyPath;
+use super::error::Error;
+use super::document::Document;
+use super::style::{Style, format_value};
+use super::tableix::make_key_token;
+use super::batch::json_to_toml;
+
+/// Values longer than this are put on lines of their own.
+const MAX_LINE: usize = 80;
+
+/// How to write TOML values in JSON.
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub enum JsonFormat {
+    /// As the nearest JSON value, with dates and times as strings
+    #[default]
+    Plain,
+    /// The format used by toml-test: every value that isn't a table or an
+    /// array is an object like `{"type": "integer", "value": "1"}`, so dates
+    /// and times keep their type.
+    Tagged,
+}
+
+/// The toml-test type name for a date or time.
+fn datetime_type(text: &str) -> &'static str {
+    let has_date = text.len() >= 10 && text.as_bytes()[4] == b'-';
+    let has_time = text.contains(':');
+    let has_offset = text.ends_with(['Z', 'z']) ||
+                     text.get(19..).is_some_and(|rest| rest.contains(['+', '-']));
+    match (has_date, has_time) {
+        (true, true) if has_offset => "datetime",
+        (true, true) => "datetime-local",
+        (true, false) => "date-local",
+        _ => "time-local",
+    }
+}
+
+fn tagged(kind: &str, value: String) -> Json {
+    let mut obj = ::std::collections::BTreeMap::new();
+    obj.insert(String::from("type"), Json::String(String::from(kind)));
+    obj.insert(String::from("value"), Json::String(value));
+    Json::Object(obj)
+}
+
+fn tagged_float(f: f64) -> String {
+    if f.is_nan() {
+        String::from("nan")
+    } else if f.is_infinite() {
+        String::from(if f > 0.0 { "inf" } else { "-inf" })
+    } else {
+        format!("{:?}", f)
+    }
+}
+
+fn value_to_json(key: &KeyPath, value: &toml::Value, format: JsonFormat) -> Result<Json, Error> {
+    Ok(match (value, format) {
+        (toml::Value::Table(t), _) => {
+            let mut obj = ::std::collections::BTreeMap::new();
+            for (k, v) in t {
+                obj.insert(k.clone(), value_to_json(&key.clone().append_key(k.clone()), v, format)?);
+            }
+            Json::Object(obj)
+        },
+        (toml::Value::Array(items), _) => {
+            let mut res = Vec::new();
+            for (i, v) in items.iter().enumerate() {
+                res.push(value_to_json(&key.clone().append_index(i), v, format)?);
+            }
+            Json::Array(res)
+        },
+        (toml::Value::String(s), JsonFormat::Plain) => Json::String(s.clone()),
+        (toml::Value::Integer(i), JsonFormat::Plain) => Json::I64(*i),
+        (toml::Value::Float(f), JsonFormat::Plain) if f.is_finite() => Json::F64(*f),
+        (toml::Value::Float(f), JsonFormat::Plain) => {
+            return Err(Error::NotRepresentable(key.clone(), format!("JSON has no {}", tagged_float(*f))));
+        },
+        (toml::Value::Boolean(b), JsonFormat::Plain) => Json::Boolean(*b),
+        (toml::Value::Datetime(d), JsonFormat::Plain) => Json::String(d.clone()),
+        (toml::Value::String(s), JsonFormat::Tagged) => tagged("string", s.clone()),
+        (toml::Value::Integer(i), JsonFormat::Tagged) => tagged("integer", i.to_string()),
+        (toml::Value::Float(f), JsonFormat::Tagged) => tagged("float", tagged_float(*f)),
+        (toml::Value::Boolean(b), JsonFormat::Tagged) => tagged("bool", b.to_string()),
+        (toml::Value::Datetime(d), JsonFormat::Tagged) => tagged(datetime_type(d), d.clone()),
+    })
+}
+
+/// The contents of a document as JSON.
+pub fn to_json(doc: &Document, format: JsonFormat) -> Result<Json, Error> {
+    value_to_json(&KeyPath::new(), doc.values(), format)
+}
+
+fn yaml_to_toml(key: &KeyPath, yaml: &Yaml) -> Result<toml::Value, Error> {
+    let unsupported = |what: &str| Error::NotRepresentable(key.clone(), format!("{} has no TOML equivalent", what));
+    Ok(match *yaml {
+        Yaml::String(ref s) => toml::Value::String(s.clone()),
+        Yaml::Integer(i) => toml::Value::Integer(i),
+        Yaml::Real(ref s) => match yaml.as_f64() {
+            Some(f) => toml::Value::Float(f),
+            None => return Err(Error::InvalidInput(format!("invalid number {}", s))),
+        },
+        Yaml::Boolean(b) => toml::Value::Boolean(b),
+        Yaml::Array(ref items) => {
+            let mut res = Vec::new();
+            for (i, item) in items.iter().enumerate() {
+                res.push(yaml_to_toml(&key.clone().append_index(i), item)?);
+            }
+            toml::Value::Array(res)
+        },
+        Yaml::Hash(ref hash) => {
+            let mut table = toml::Table::new();
+            for (k, v) in hash {
+                let name = match *k {
+                    Yaml::String(ref s) => s.clone(),
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Boolean(b) => b.to_string(),
+                    _ => return Err(unsupported("a key which isn't a string")),
+                };
+                let value = yaml_to_toml(&key.clone().append_key(name.clone()), v)?;
+                table.insert(name, value);
+            }
+            toml::Value::Table(table)
+        },
+        Yaml::Null => return Err(unsupported("null")),
+        Yaml::Alias(_) | Yaml::BadValue => return Err(unsupported("this value")),
+    })
+}
+
+fn key_text(name: &str) -> String {
+    make_key_token(name).text
+}
+
+fn header_text(path: &[String]) -> String {
+    path.iter().map(|p| key_text(p)).collect::<Vec<_>>().join(".")
+}
+
+fn is_table_array(value: &toml::Value) -> bool {
+    match *value {
+        toml::Value::Array(ref items) => {
+            !items.is_empty() && items.iter().all(|v| matches!(v, toml::Value::Table(_)))
+        },
+        _ => false,
+    }
+}
+
+/// Does a table, or any table in an array, have tables inside it?
+fn has_nested_tables(value: &toml::Value) -> bool {
+    match *value {
+        toml::Value::Table(ref t) => t.values().any(|v| matches!(v, toml::Value::Table(_)) || is_table_array(v)),
+        toml::Value::Array(ref items) => items.iter().any(has_nested_tables),
+        _ => false,
+    }
+}
+
+/// Should this value get sections of its own, rather than a `key = value`
+/// line? Tables and arrays of tables at the top level always do; deeper
+/// down, only if they hold more tables or won't fit on a line.
+fn needs_section(name: &str, value: &toml::Value, depth: usize) -> bool {
+    if !matches!(value, toml::Value::Table(_)) && !is_table_array(value) {
+        return false;
+    }
+    let line_len = key_text(name).len() + 3 + format_value(value, &Style::default(), "\n").len();
+    depth == 0 || has_nested_tables(value) || line_len > MAX_LINE
+}
+
+fn write_entry(out: &mut String, name: &str, value: &toml::Value) {
+    let key = key_text(name);
+    let mut text = format_value(value, &Style::default(), "\n");
+    if key.len() + 3 + text.len() > MAX_LINE && matches!(value, toml::Value::Array(_)) {
+        let style = Style{array_multiline: true, array_indent: String::from("    "),
+                          array_trailing_comma: true, ..Style::default()};
+        text = format_value(value, &style, "\n");
+    }
+    out.push_str(&format!("{} = {}\n", key, text));
+}
+
+fn start_section(out: &mut String, header: &str) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(header);
+    out.push('\n');
+}
+
+fn write_table(out: &mut String, path: &mut Vec<String>, table: &toml::Table) {
+    let depth = path.len();
+    for (k, v) in table {
+        if !needs_section(k, v, depth) {
+            write_entry(out, k, v);
+        }
+    }
+    for (k, v) in table {
+        if !needs_section(k, v, depth) {
+            continue;
+        }
+        path.push(k.clone());
+        match *v {
+            toml::Value::Table(ref sub) => {
+                // Tables holding only other tables don't need a header
+                let only_sections = !sub.is_empty() && sub.iter().all(|(k, v)| needs_section(k, v, depth + 1));
+                if !only_sections {
+                    start_section(out, &format!("[{}]", header_text(path)));
+                }
+                write_table(out, path, sub);
+            },
+            toml::Value::Array(ref items) => {
+                for item in items {
+                    start_section(out, &format!("[[{}]]", header_text(path)));
+                    if let toml::Value::Table(ref sub) = *item {
+                        write_table(out, path, sub);
+                    }
+                }
+            },
+            _ => unreachable!(),
+        }
+        path.pop();
+    }
+}
+
+/// Write a table as a new TOML document. Tables and arrays of tables get
+/// `[table]` and `[[array]]` sections, except for small, flat ones below the
+/// top level, which are written inline.
+pub fn from_value(value: &toml::Value) -> Result<Document, Error> {
+    let table = match *value {
+        toml::Value::Table(ref t) => t,
+        _ => return Err(Error::NotRepresentable(KeyPath::new(), String::from("a document must be a table"))),
+    };
+    let mut out = String::new();
+    write_table(&mut out, &mut Vec::new(), table);
+    Document::parse(&out)
+}
+
+/// Make a TOML document from JSON text.
+pub fn from_json(text: &str) -> Result<Document, Error> {
+    let json = Json::from_str(text).map_err(|e| Error::InvalidInput(e.to_string()))?;
+    from_value(&json_to_toml(&json).map_err(|e| Error::NotRepresentable(KeyPath::new(), e))?)
+}
+
+/// Make a TOML document from the first document in YAML text.
+pub fn from_yaml(text: &str) -> Result<Document, Error> {
+    let docs = YamlLoader::load_from_str(text).map_err(|e| Error::InvalidInput(e.to_string()))?;
+    match docs.first() {
+        Some(yaml) => from_value(&yaml_to_toml(&KeyPath::new(), yaml)?),
+        None => Document::parse(""),
+    }
+}
+
+#[test]
+fn test_to_json() {
+    let doc = Document::parse("a = 1\nb = [1.5, \"x\"]\n[t]\nd = 1979-05-27\n").unwrap();
+    assert_eq!(to_json(&doc, JsonFormat::Plain).unwrap().to_string(),
+               r#"{"a":1,"b":[1.5,"x"],"t":{"d":"1979-05-27"}}"#);
+    assert_eq!(to_json(&doc, JsonFormat::Tagged).unwrap().to_string(),
+               concat!(r#"{"a":{"type":"integer","value":"1"},"#,
+                       r#""b":[{"type":"float","value":"1.5"},{"type":"string","value":"x"}],"#,
+                       r#""t":{"d":{"type":"date-local","value":"1979-05-27"}}}"#));
+    assert_eq!(datetime_type("1979-05-27T07:32:00Z"), "datetime");
+    assert_eq!(datetime_type("1979-05-27T07:32:00-07:00"), "datetime");
+    assert_eq!(datetime_type("1979-05-27 07:32:00"), "datetime-local");
+    assert_eq!(datetime_type("07:32:00"), "time-local");
+
+    let doc = Document::parse("x = nan\n").unwrap();
+    assert!(to_json(&doc, JsonFormat::Plain).is_err());
+}
+
+#[test]
+fn test_from_json() {
+    let doc = from_json(r#"{"name": "x", "deps": {"serde": {"version": "1"}},
+                            "bin": [{"name": "a"}, {"name": "b"}],
+                            "target": {"unix": {"deps": {"libc": "0.2"}}}}"#).unwrap();
+    assert_eq!(doc.to_string(), "\
+name = \"x\"
+
+[[bin]]
+name = \"a\"
+
+[[bin]]
+name = \"b\"
+
+[deps]
+serde = { version = \"1\" }
+
+[target.unix]
+deps = { libc = \"0.2\" }
+");
+    assert!(from_json("[1]").is_err());
+    assert!(from_json("{\"a\": null}").is_err());
+}
+
+#[test]
+fn test_from_yaml() {
+    let doc = from_yaml("package:\n  name: demo\n  authors: [a, b]\n  version: 1.5\n").unwrap();
+    assert_eq!(doc.to_string(), "[package]\nauthors = [\"a\", \"b\"]\nname = \"demo\"\nversion = 1.5\n");
+}
@@ -0,0 +1,585 @@
+//! Decode a token stream into values, checking that it's valid TOML.
+
+extern crate toml;
+
+use std::collections::HashSet;
+
+use super::tokenise::{Token, TokenType, line_col};
+use super::keypath::{KeyPath, KeyPathComponent};
+use super::error::Error;
+
+struct Decoder<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    root: toml::Table,
+    /// Tables opened with a `[header]`
+    headers: HashSet<KeyPath>,
+    /// Tables made by dotted keys
+    dotted: HashSet<KeyPath>,
+    /// Arrays made by `[[header]]`s
+    table_arrays: HashSet<KeyPath>,
+    /// Inline tables and arrays, which can't be added to later
+    closed: HashSet<KeyPath>,
+}
+
+/// Decode a whole document.
+pub fn decode(tokens: &[Token]) -> Result<toml::Value, Error> {
+    let mut d = Decoder{tokens, pos: 0, root: toml::Table::new(), headers: HashSet::new(),
+                        dotted: HashSet::new(), table_arrays: HashSet::new(), closed: HashSet::new()};
+    let mut current = KeyPath::new();
+    loop {
+        d.skip_whitespace();
+        match d.peek() {
+            None => break,
+            Some(tok) if tok.kind == TokenType::Newline || tok.kind == TokenType::Comment => {
+                d.pos += 1;
+                continue;
+            },
+            Some(tok) if *tok == Token::from("[") => current = d.header()?,
+            Some(_) => d.keyval(&current)?,
+        }
+        d.end_of_line()?;
+    }
+    Ok(toml::Value::Table(d.root))
+}
+
+fn table_at<'t>(table: &'t mut toml::Table, parts: &[KeyPathComponent]) -> &'t mut toml::Table {
+    match parts.split_first() {
+        None => table,
+        Some((KeyPathComponent::Key(k), rest)) => {
+            match (table.get_mut(k), rest.split_first()) {
+                (Some(toml::Value::Array(items)), Some((KeyPathComponent::Ix(i), rest))) => {
+                    match items[*i] {
+                        toml::Value::Table(ref mut t) => table_at(t, rest),
+                        _ => panic!("Not a table in array")
+                    }
+                },
+                (Some(toml::Value::Table(t)), _) => table_at(t, rest),
+                _ => panic!("No table at {:?}", k)
+            }
+        },
+        Some((KeyPathComponent::Ix(_), _)) => panic!("Unexpected index"),
+    }
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn error(&self, pos: usize, msg: &str) -> Error {
+        let mut text = String::new();
+        for tok in self.tokens {
+            text.push_str(&tok.text);
+        }
+        let offset = self.tokens[..pos.min(self.tokens.len())].iter().map(|t| t.text.len()).sum();
+        let (line, col) = line_col(&text, offset);
+        Error::Parse(line, col, String::from(msg))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(|t| t.kind == TokenType::Whitespace).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_blank(&mut self) {
+        while self.peek().map(|t| {
+            matches!(t.kind, TokenType::Whitespace | TokenType::Newline | TokenType::Comment)
+        }).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), Error> {
+        if self.peek() == Some(&Token::from(text)) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(self.pos, &format!("expected `{}`", text)))
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_whitespace();
+        if self.peek().map(|t| t.kind == TokenType::Comment).unwrap_or(false) {
+            self.pos += 1;
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some(t) if t.kind == TokenType::Newline => {
+                self.pos += 1;
+                Ok(())
+            },
+            Some(_) => Err(self.error(self.pos, "expected a new line")),
+        }
+    }
+
+    /// Read a possibly dotted key.
+    fn key(&mut self) -> Result<Vec<String>, Error> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let part = match self.peek() {
+                Some(t) if t.kind == TokenType::BareKey => Some(t.text.clone()),
+                Some(t) if t.kind == TokenType::BasicString || t.kind == TokenType::LiteralString => {
+                    match decode_string(t) {
+                        Some(s) => Some(s),
+                        None => return Err(self.error(self.pos, "invalid string")),
+                    }
+                },
+                _ => None,
+            };
+            match part {
+                Some(p) => parts.push(p),
+                None => return Err(self.error(self.pos, "expected a key")),
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            if self.peek() != Some(&Token::from(".")) {
+                return Ok(parts);
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// Read a `[table]` or `[[array]]` header, returning the path of the table.
+    fn header(&mut self) -> Result<KeyPath, Error> {
+        let start = self.pos;
+        self.pos += 1;
+        let array = self.peek() == Some(&Token::from("["));
+        if array {
+            self.pos += 1;
+        }
+        let parts = self.key()?;
+        self.expect("]")?;
+        if array {
+            self.expect("]")?;
+        }
+        let mut path = KeyPath::new();
+        for (i, part) in parts.iter().enumerate() {
+            let last = i + 1 == parts.len();
+            let child = path.clone().append_key(part.clone());
+            if self.closed.contains(&child) {
+                return Err(self.error(start, "can't add to a table or array written inline"));
+            }
+            let table = table_at(&mut self.root, &path.parts);
+            match table.get_mut(part) {
+                None if last && array => {
+                    table.insert(part.clone(), toml::Value::Array(vec![toml::Value::Table(toml::Table::new())]));
+                    self.table_arrays.insert(child.clone());
+                    path = child.append_index(0);
+                },
+                None => {
+                    table.insert(part.clone(), toml::Value::Table(toml::Table::new()));
+                    if last {
+                        self.headers.insert(child.clone());
+                    }
+                    path = child;
+                },
+                Some(toml::Value::Table(_)) => {
+                    if last && (array || self.headers.contains(&child) || self.dotted.contains(&child)) {
+                        return Err(self.error(start, "table defined more than once"));
+                    }
+                    if last {
+                        self.headers.insert(child.clone());
+                    }
+                    path = child;
+                },
+                Some(toml::Value::Array(items)) if self.table_arrays.contains(&child) => {
+                    if last && !array {
+                        return Err(self.error(start, "table defined more than once"));
+                    }
+                    if last {
+                        items.push(toml::Value::Table(toml::Table::new()));
+                    }
+                    path = child.append_index(items.len() - 1);
+                },
+                Some(_) => return Err(self.error(start, "key already has a value")),
+            }
+        }
+        Ok(path)
+    }
+
+    /// Read a `key = value` line into the table at `current`.
+    fn keyval(&mut self, current: &KeyPath) -> Result<(), Error> {
+        let start = self.pos;
+        let parts = self.key()?;
+        self.expect("=")?;
+        self.skip_whitespace();
+        let value = self.value()?;
+        let (last, init) = parts.split_last().unwrap();
+        let mut path = current.clone();
+        for part in init {
+            let child = path.clone().append_key(part.clone());
+            let table = table_at(&mut self.root, &path.parts);
+            match table.get(part) {
+                None => {
+                    table.insert(part.clone(), toml::Value::Table(toml::Table::new()));
+                    self.dotted.insert(child.clone());
+                },
+                Some(toml::Value::Table(_)) if self.dotted.contains(&child) => (),
+                Some(_) => return Err(self.error(start, "key already has a value")),
+            }
+            path = child;
+        }
+        let child = path.clone().append_key(last.clone());
+        let table = table_at(&mut self.root, &path.parts);
+        if table.contains_key(last) {
+            return Err(self.error(start, "key defined more than once"));
+        }
+        if let toml::Value::Table(_) | toml::Value::Array(_) = value {
+            self.closed.insert(child);
+        }
+        table.insert(last.clone(), value);
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<toml::Value, Error> {
+        let start = self.pos;
+        let tok = match self.peek() {
+            Some(t) => t,
+            None => return Err(self.error(start, "expected a value")),
+        };
+        if *tok == Token::from("[") {
+            return self.array();
+        } else if *tok == Token::from("{") {
+            return self.inline_table();
+        }
+        self.pos += 1;
+        let value = match tok.kind {
+            TokenType::Integer => decode_integer(&tok.text).map(toml::Value::Integer),
+            TokenType::Float => decode_float(&tok.text).map(toml::Value::Float),
+            TokenType::Boolean => match tok.text.as_str() {
+                "true" => Some(toml::Value::Boolean(true)),
+                "false" => Some(toml::Value::Boolean(false)),
+                _ => None
+            },
+            TokenType::Datetime => {
+                if valid_datetime(&tok.text) {
+                    Some(toml::Value::Datetime(tok.text.clone()))
+                } else {
+                    None
+                }
+            },
+            TokenType::BasicString | TokenType::LiteralString
+            | TokenType::MultilineBasicString | TokenType::MultilineLiteralString => {
+                decode_string(tok).map(toml::Value::String)
+            },
+            _ => return Err(self.error(start, "expected a value")),
+        };
+        value.ok_or_else(|| self.error(start, &format!("invalid value `{}`", tok.text)))
+    }
+
+    fn array(&mut self) -> Result<toml::Value, Error> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(&Token::from("]")) {
+                self.pos += 1;
+                return Ok(toml::Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            if self.peek() == Some(&Token::from(",")) {
+                self.pos += 1;
+            } else if self.peek() != Some(&Token::from("]")) {
+                return Err(self.error(self.pos, "expected `,` or `]`"));
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<toml::Value, Error> {
+        self.pos += 1;
+        let mut table = toml::Table::new();
+        // Subtables made by dotted keys, which can have more keys added
+        let mut dotted = HashSet::new();
+        self.skip_whitespace();
+        if self.peek() == Some(&Token::from("}")) {
+            self.pos += 1;
+            return Ok(toml::Value::Table(table));
+        }
+        loop {
+            let start = self.pos;
+            let parts = self.key()?;
+            self.expect("=")?;
+            self.skip_whitespace();
+            let value = self.value()?;
+            if !insert_dotted(&mut table, &parts, value, &mut dotted) {
+                return Err(self.error(start, "key defined more than once"));
+            }
+            self.skip_whitespace();
+            if self.peek() == Some(&Token::from("}")) {
+                self.pos += 1;
+                return Ok(toml::Value::Table(table));
+            }
+            self.expect(",")?;
+        }
+    }
+}
+
+/// Insert a value under a dotted key in an inline table. Returns false if the
+/// key clashes with one already there.
+fn insert_dotted(table: &mut toml::Table, parts: &[String], value: toml::Value,
+                 dotted: &mut HashSet<Vec<String>>) -> bool {
+    let mut table = table;
+    for i in 0..parts.len() - 1 {
+        let prefix = parts[..i+1].to_vec();
+        let entry = table.entry(parts[i].clone()).or_insert_with(|| {
+            dotted.insert(prefix.clone());
+            toml::Value::Table(toml::Table::new())
+        });
+        table = match entry {
+            toml::Value::Table(t) if dotted.contains(&prefix) => t,
+            _ => return false,
+        };
+    }
+    let last = parts.last().unwrap();
+    if table.contains_key(last) {
+        return false;
+    }
+    table.insert(last.clone(), value);
+    true
+}
+
+/// Digits with single underscores allowed between them.
+fn valid_digits(s: &str, radix: u32) -> bool {
+    !s.is_empty() && !s.starts_with('_') && !s.ends_with('_') && !s.contains("__")
+        && s.chars().all(|c| c == '_' || c.is_digit(radix))
+}
+
+fn decode_integer(text: &str) -> Option<i64> {
+    let (radix, digits) = match text.get(..2) {
+        Some("0x") => (16, &text[2..]),
+        Some("0o") => (8, &text[2..]),
+        Some("0b") => (2, &text[2..]),
+        _ => (10, text),
+    };
+    let (sign, unsigned) = match digits.chars().next() {
+        Some(c @ '+') | Some(c @ '-') if radix == 10 => (c, &digits[1..]),
+        _ => ('+', digits),
+    };
+    if !valid_digits(unsigned, radix) || (radix == 10 && unsigned.len() > 1 && unsigned.starts_with('0')) {
+        return None;
+    }
+    let cleaned = format!("{}{}", sign, unsigned.replace('_', ""));
+    i64::from_str_radix(&cleaned, radix).ok()
+}
+
+fn decode_float(text: &str) -> Option<f64> {
+    let (negative, unsigned) = match text.chars().next() {
+        Some('+') => (false, &text[1..]),
+        Some('-') => (true, &text[1..]),
+        _ => (false, text),
+    };
+    let value = match unsigned {
+        "inf" => f64::INFINITY,
+        "nan" => f64::NAN,
+        _ => {
+            let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+                Some(i) => (&unsigned[..i], Some(&unsigned[i+1..])),
+                None => (unsigned, None),
+            };
+            let (int, frac) = match mantissa.find('.') {
+                Some(i) => (&mantissa[..i], Some(&mantissa[i+1..])),
+                None => (mantissa, None),
+            };
+            let exponent_ok = match exponent {
+                Some(e) => valid_digits(e.trim_start_matches(['+', '-']), 10)
+                           && e.len() - e.trim_start_matches(['+', '-']).len() <= 1,
+                None => true,
+            };
+            let ok = valid_digits(int, 10) && (int == "0" || !int.starts_with('0'))
+                     && frac.map(|f| valid_digits(f, 10)).unwrap_or(true)
+                     && exponent_ok && (frac.is_some() || exponent.is_some());
+            if !ok {
+                return None;
+            }
+            unsigned.replace('_', "").parse::<f64>().ok()?
+        }
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn number_in(s: &str, len: usize, min: u32, max: u32) -> bool {
+    s.len() == len && s.chars().all(|c| c.is_ascii_digit())
+        && s.parse::<u32>().map(|n| n >= min && n <= max).unwrap_or(false)
+}
+
+fn valid_date(s: &str) -> bool {
+    let parts: Vec<&str> = s.split('-').collect();
+    if parts.len() != 3 || !number_in(parts[0], 4, 0, 9999) || !number_in(parts[1], 2, 1, 12) {
+        return false;
+    }
+    let year: u32 = parts[0].parse().unwrap();
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    let days = match parts[1] {
+        "02" if leap => 29,
+        "02" => 28,
+        "04" | "06" | "09" | "11" => 30,
+        _ => 31,
+    };
+    number_in(parts[2], 2, 1, days)
+}
+
+fn valid_time(s: &str) -> bool {
+    let (hms, frac) = match s.find('.') {
+        Some(i) => (&s[..i], Some(&s[i+1..])),
+        None => (s, None),
+    };
+    let parts: Vec<&str> = hms.split(':').collect();
+    parts.len() == 3 && number_in(parts[0], 2, 0, 23) && number_in(parts[1], 2, 0, 59)
+        && number_in(parts[2], 2, 0, 60)
+        && frac.map(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_digit())).unwrap_or(true)
+}
+
+fn valid_offset(s: &str) -> bool {
+    if s == "Z" || s == "z" {
+        return true;
+    }
+    let parts: Vec<&str> = s[1..].split(':').collect();
+    (s.starts_with('+') || s.starts_with('-')) && parts.len() == 2
+        && number_in(parts[0], 2, 0, 23) && number_in(parts[1], 2, 0, 59)
+}
+
+/// Check an offset or local date-time, date, or time.
+fn valid_datetime(s: &str) -> bool {
+    if !s.is_ascii() {
+        return false;
+    }
+    if s.len() <= 10 && !s.contains(':') {
+        return valid_date(s);
+    }
+    if !s.contains('-') || s.find(':') < s.find('-') {
+        return valid_time(s);
+    }
+    if s.len() < 11 || !valid_date(&s[..10]) || !matches!(&s[10..11], "T" | "t" | " ") {
+        return false;
+    }
+    let time = &s[11..];
+    let offset_at = time.find(['Z', 'z', '+', '-']);
+    match offset_at {
+        Some(i) => valid_time(&time[..i]) && valid_offset(&time[i..]),
+        None => valid_time(time),
+    }
+}
+
+/// Decode the escapes in the body of a basic string. In multiline strings, a
+/// backslash at the end of a line trims the line ending and any whitespace
+/// after it.
+fn unescape(body: &str, multiline: bool) -> Option<String> {
+    let mut res = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        let decoded = match chars.next()? {
+            'b' => '\u{8}',
+            't' => '\t',
+            'n' => '\n',
+            'f' => '\u{c}',
+            'r' => '\r',
+            '"' => '"',
+            '\\' => '\\',
+            u @ ('u' | 'U') => {
+                let len = if u == 'u' { 4 } else { 8 };
+                let hex: String = chars.by_ref().take(len).collect();
+                if hex.len() != len || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+                // Surrogates and values past U+10FFFF aren't characters
+                char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+            },
+            c @ (' ' | '\t' | '\r' | '\n') if multiline => {
+                // Only whitespace may come between the backslash and the
+                // end of the line
+                let mut seen_newline = c == '\n';
+                while let Some(&next) = chars.peek() {
+                    match next {
+                        '\n' => seen_newline = true,
+                        ' ' | '\t' | '\r' => (),
+                        _ => break,
+                    }
+                    chars.next();
+                }
+                if !seen_newline {
+                    return None;
+                }
+                continue;
+            },
+            _ => return None,
+        };
+        res.push(decoded);
+    }
+    Some(res)
+}
+
+/// The value of a string token, or `None` if it's malformed.
+pub fn decode_string(tok: &Token) -> Option<String> {
+    let (quotes, multiline) = match tok.kind {
+        TokenType::BasicString => ("\"", false),
+        TokenType::LiteralString => ("'", false),
+        TokenType::MultilineBasicString => ("\"\"\"", true),
+        TokenType::MultilineLiteralString => ("'''", true),
+        _ => return None,
+    };
+    if tok.text.len() < 2 * quotes.len() || !tok.text.ends_with(quotes) {
+        return None;
+    }
+    let mut body = &tok.text[quotes.len()..tok.text.len() - quotes.len()];
+    if multiline {
+        // A line ending straight after the opening quotes isn't part of it
+        body = body.strip_prefix('\n').or_else(|| body.strip_prefix("\r\n")).unwrap_or(body);
+    }
+    let body = body.replace("\r\n", "\n");
+    match tok.kind {
+        TokenType::BasicString | TokenType::MultilineBasicString => unescape(&body, multiline),
+        _ => Some(body),
+    }
+}
+
+#[cfg(test)]
+use super::tokenise::try_tokenise;
+
+#[cfg(test)]
+fn decode_str(s: &str) -> Result<toml::Value, Error> {
+    decode(&try_tokenise(s)?)
+}
+
+#[test]
+fn test_decode() {
+    let value = decode_str("a.b = 1\nc = {d.e = [0x1f, 1_000, 1.5e3, inf]}\n[t]\nx = 1979-05-27 07:32:00Z\n[[u]]\n[[u]]\ny = 'z'\n").unwrap();
+    let expected = decode_str("a = {b = 1}\nc = {d = {e = [31, 1000, 1500.0, inf]}}\nt = {x = 1979-05-27 07:32:00Z}\nu = [{}, {y = \"z\"}]\n").unwrap();
+    assert_eq!(value, expected);
+}
+
+#[test]
+fn test_decode_errors() {
+    let err = |s| match decode_str(s) {
+        Err(Error::Parse(line, col, msg)) => (line, col, msg),
+        other => panic!("Expected an error, got {:?}", other)
+    };
+    assert_eq!(err("a = 1\na = 2\n"), (2, 1, String::from("key defined more than once")));
+    assert_eq!(err("[a]\n[a]\n"), (2, 1, String::from("table defined more than once")));
+    assert_eq!(err("a.b.c = 1\n[a.b]\n"), (2, 1, String::from("table defined more than once")));
+    assert_eq!(err("a = {}\n[a.b]\n"), (2, 1, String::from("can't add to a table or array written inline")));
+    assert_eq!(err("a = 1 # c\n[t] b = 2\n"), (2, 5, String::from("expected a new line")));
+    assert_eq!(err("a = 01\n"), (1, 5, String::from("invalid value `01`")));
+    assert_eq!(err("a = 2021-02-29\n"), (1, 5, String::from("invalid value `2021-02-29`")));
+    assert_eq!(err("a = [1 2]\n"), (1, 8, String::from("expected `,` or `]`")));
+}
+
+#[test]
+fn test_decode_multiline_strings() {
+    let value = |s| decode_str(s).unwrap().lookup("a").cloned().unwrap();
+    let string = |s: &str| toml::Value::String(String::from(s));
+    assert_eq!(value("a = \"\"\"\nx\r\ny\"\"\"\n"), string("x\ny"));
+    assert_eq!(value("a = \"\"\"x \\\n   \n  y\\t\"\"\"\n"), string("x y\t"));
+    assert_eq!(value("a = \"\"\"x \\  \r\n  y\"\"\"\n"), string("x y"));
+    assert_eq!(value("a = \"\"\"\"\"x\"\"\"\"\"\n"), string("\"\"x\"\""));
+    assert_eq!(value("a = '''\r\nx\\\n'''\n"), string("x\\\n"));
+    assert!(decode_str("a = \"\"\"x \\ y\"\"\"\n").is_err());
+}
@@ -0,0 +1,274 @@
+//! Line-based differences between two versions of a token stream, for
+//! showing what an edit will change.
+
+use std::fmt;
+
+use super::tokenise::Token;
+
+/// One line of a hunk. The text doesn't include the line ending.
+#[derive(Debug,PartialEq,Clone)]
+pub enum DiffLine {
+    Context(String),
+    Removed(String),
+    Added(String),
+    /// The line before this had no line ending, because it was the end of
+    /// the file
+    NoNewlineAtEnd,
+}
+
+/// A run of changed lines, with unchanged lines around them for context.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Hunk {
+    /// First line of the hunk in the old text, counting from 1
+    pub old_start: usize,
+    pub old_len: usize,
+    /// First line of the hunk in the new text, counting from 1
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+/// Number of unchanged lines shown either side of a change.
+const CONTEXT: usize = 3;
+
+#[derive(Debug,PartialEq,Clone,Copy)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Split tokens into lines, each including its line ending if it has one.
+/// Multiline strings are split too, so these are the lines of the text.
+fn lines(tokens: &[Token]) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut line = String::new();
+    for tok in tokens {
+        for piece in tok.text.split_inclusive('\n') {
+            line.push_str(piece);
+            if piece.ends_with('\n') {
+                res.push(line);
+                line = String::new();
+            }
+        }
+    }
+    if !line.is_empty() {
+        res.push(line);
+    }
+    res
+}
+
+/// The shortest edit script turning a into b (Myers' algorithm).
+fn edit_script(a: &[String], b: &[String]) -> Vec<Op> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace = Vec::new();
+    'outer: for d in 0..=(max as isize) {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i-1] < v[i+1]) { v[i+1] } else { v[i-1] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+            k += 2;
+        }
+    }
+
+    // Walk back through the trace to recover the edits
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let i = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[i-1] < v[i+1]) { k + 1 } else { k - 1 };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            ops.push(if x == prev_x { Op::Insert } else { Op::Delete });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+fn push_line(lines: &mut Vec<DiffLine>, text: &str, make: fn(String) -> DiffLine) {
+    let stripped = text.trim_end_matches('\n').trim_end_matches('\r');
+    lines.push(make(String::from(stripped)));
+    if stripped.len() == text.len() {
+        lines.push(DiffLine::NoNewlineAtEnd);
+    }
+}
+
+/// Find the lines which differ between two token streams, grouped into hunks.
+pub fn line_diff(old: &[Token], new: &[Token]) -> Vec<Hunk> {
+    let a = lines(old);
+    let b = lines(new);
+    let ops = edit_script(&a, &b);
+
+    // Position in a and b before each op
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut x, mut y) = (0, 0);
+    for op in &ops {
+        positions.push((x, y));
+        match *op {
+            Op::Equal => { x += 1; y += 1; },
+            Op::Delete => x += 1,
+            Op::Insert => y += 1,
+        }
+    }
+
+    let changes: Vec<usize> = (0..ops.len()).filter(|&i| ops[i] != Op::Equal).collect();
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while i < changes.len() {
+        // Extend the hunk while the next change is close enough to share context
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j+1] - changes[j] <= 2 * CONTEXT + 1 {
+            j += 1;
+        }
+        let start = changes[i].saturating_sub(CONTEXT);
+        let end = (changes[j] + CONTEXT + 1).min(ops.len());
+        let (old_start, new_start) = positions[start];
+        let mut hunk = Hunk{old_start: old_start + 1, old_len: 0, new_start: new_start + 1,
+                            new_len: 0, lines: Vec::new()};
+        for k in start..end {
+            let (x, y) = positions[k];
+            match ops[k] {
+                Op::Equal => {
+                    push_line(&mut hunk.lines, &a[x], DiffLine::Context);
+                    hunk.old_len += 1;
+                    hunk.new_len += 1;
+                },
+                Op::Delete => {
+                    push_line(&mut hunk.lines, &a[x], DiffLine::Removed);
+                    hunk.old_len += 1;
+                },
+                Op::Insert => {
+                    push_line(&mut hunk.lines, &b[y], DiffLine::Added);
+                    hunk.new_len += 1;
+                },
+            }
+        }
+        hunks.push(hunk);
+        i = j + 1;
+    }
+    hunks
+}
+
+pub fn range(start: usize, len: usize) -> String {
+    match len {
+        // An empty range is given by the line before it
+        0 => format!("{},0", start - 1),
+        1 => format!("{}", start),
+        _ => format!("{},{}", start, len),
+    }
+}
+
+impl fmt::Display for Hunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "@@ -{} +{} @@", range(self.old_start, self.old_len),
+                 range(self.new_start, self.new_len))?;
+        for line in &self.lines {
+            match *line {
+                DiffLine::Context(ref s) => writeln!(f, " {}", s)?,
+                DiffLine::Removed(ref s) => writeln!(f, "-{}", s)?,
+                DiffLine::Added(ref s) => writeln!(f, "+{}", s)?,
+                DiffLine::NoNewlineAtEnd => writeln!(f, "\\ No newline at end of file")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A unified diff between two versions of a file, or an empty string if
+/// they're the same.
+pub fn unified_diff(old: &[Token], new: &[Token], path: &str) -> String {
+    let hunks = line_diff(old, new);
+    if hunks.is_empty() {
+        return String::new();
+    }
+    let mut res = format!("--- a/{}\n+++ b/{}\n", path, path);
+    for hunk in hunks {
+        res.push_str(&hunk.to_string());
+    }
+    res
+}
+
+#[cfg(test)]
+use super::tokenise::tokenise;
+
+#[test]
+fn test_line_diff() {
+    let old = tokenise("a = 1\nb = 2\nc = 3\nd = 4\ne = 5\nf = 6\ng = 7\nh = 8\ni = 9\n");
+    let new = tokenise("a = 1\nb = 2\nc = 3\nd = 4\ne = 50\nf = 6\ng = 7\nh = 8\ni = 9\nj = 10\n");
+    let hunks = line_diff(&old, &new);
+    assert_eq!(hunks.len(), 1);
+    assert_eq!((hunks[0].old_start, hunks[0].old_len, hunks[0].new_start, hunks[0].new_len),
+               (2, 8, 2, 9));
+    assert_eq!(unified_diff(&old, &new, "x.toml"), "\
+--- a/x.toml
++++ b/x.toml
+@@ -2,8 +2,9 @@
+ b = 2
+ c = 3
+ d = 4
+-e = 5
++e = 50
+ f = 6
+ g = 7
+ h = 8
+ i = 9
++j = 10
+");
+    assert_eq!(unified_diff(&old, &old, "x.toml"), "");
+}
+
+#[test]
+fn test_line_diff_edges() {
+    assert_eq!(line_diff(&[], &tokenise("a = 1\n"))[0].to_string(), "@@ -0,0 +1 @@\n+a = 1\n");
+    // Separate hunks, and a missing final newline
+    let old = tokenise("a = 1\nb = 2\nc = 3\nd = 4\ne = 5\nf = 6\ng = 7\nh = 8\ni = 9");
+    let new = tokenise("z = 0\na = 1\nb = 2\nc = 3\nd = 4\ne = 5\nf = 6\ng = 7\nh = 8\ni = 9\n");
+    assert_eq!(line_diff(&old, &new).iter().map(|h| h.to_string()).collect::<Vec<_>>(), vec![
+        String::from("@@ -1,3 +1,4 @@\n+z = 0\n a = 1\n b = 2\n c = 3\n"),
+        String::from("@@ -6,4 +7,4 @@\n f = 6\n g = 7\n h = 8\n-i = 9\n\\ No newline at end of file\n+i = 9\n"),
+    ]);
+}
+
+#[test]
+fn test_line_diff_multiline_string() {
+    let old = tokenise("s = '''\nx\ny'''\nb = 1\n");
+    let new = tokenise("s = '''\nx\ny'''\nb = 2\n");
+    assert_eq!(unified_diff(&old, &new, "x.toml"), "\
+--- a/x.toml
++++ b/x.toml
+@@ -1,4 +1,4 @@
+ s = '''
+ x
+ y'''
+-b = 1
++b = 2
+");
+    let old = tokenise("s = '''\r\nx\r\n'''\r\n");
+    let new = tokenise("s = '''\r\nz\r\n'''\r\n");
+    assert_eq!(line_diff(&old, &new)[0].to_string(), "@@ -1,3 +1,3 @@\n s = '''\n-x\n+z\n '''\n");
+}
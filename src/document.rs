@@ -0,0 +1,904 @@
+extern crate toml;
+
+use std::sync::OnceLock;
+use std::fmt;
+
+use super::tokenise::{Token, TokenType, try_tokenise, tokenise_value, detect_newline};
+use super::decode::decode;
+use super::keypath::{KeyPath, KeyPathComponent};
+use super::error::Error;
+use super::locate::{Place, Location, locate, locate_all};
+use super::index::Index;
+use super::style::{infer_style, format_value};
+use super::compare::{CompareOptions, compare_values};
+use super::tableix::{TablePos, InsertOptions, InsertPolicy, find_tables, find_table, find_entries,
+                     insert_kv_with, insert_line, insert_section, insert_table, remove_entry,
+                     remove_sections, entry_comments, entry_key_parts, header_tokens, make_key_token, skip_value};
+
+/// A TOML document which can be read and edited while keeping its layout.
+///
+/// The decoded values are kept alongside the tokens, and every edit is checked
+/// by decoding the result, so a `Document` always holds valid TOML.
+#[derive(Debug,Clone)]
+pub struct Document {
+    tokens: Vec<Token>,
+    values: toml::Value,
+    /// Whether the text started with a byte order mark, which isn't a token
+    bom: bool,
+    /// Built on first use, and dropped by every edit
+    index: OnceLock<Index>,
+}
+
+/// Parse the text of a single value, as it would appear after `=`.
+pub fn parse_value(text: &str) -> Result<toml::Value, Error> {
+    let src = format!("x = {}", text);
+    let decoded = try_tokenise(&src).and_then(|tokens| decode(&tokens));
+    match decoded {
+        Ok(toml::Value::Table(ref table)) if table.len() == 1 => Ok(table["x"].clone()),
+        Ok(_) => Err(Error::Parse(1, 1, String::from("expected a single value"))),
+        // Report columns relative to the value
+        Err(Error::Parse(1, col, desc)) => Err(Error::Parse(1, col.saturating_sub(4).max(1), desc)),
+        Err(e) => Err(e),
+    }
+}
+
+fn lookup<'a>(value: &'a toml::Value, key: &KeyPath) -> Option<&'a toml::Value> {
+    let mut current = value;
+    for part in &key.parts {
+        current = match (part, current) {
+            (KeyPathComponent::Key(k), toml::Value::Table(t)) => t.get(k)?,
+            (KeyPathComponent::Ix(i), toml::Value::Array(a)) => a.get(*i)?,
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+fn is_key_token(tok: &Token) -> bool {
+    matches!(tok.kind, TokenType::BareKey | TokenType::BasicString | TokenType::LiteralString)
+}
+
+fn is_insignificant(tok: &Token) -> bool {
+    matches!(tok.kind, TokenType::Whitespace | TokenType::Newline | TokenType::Comment)
+}
+
+/// The section which token pos is in.
+fn containing_table(tables: &[TablePos], pos: usize) -> &TablePos {
+    tables.iter().rev().find(|t| t.start <= pos).unwrap_or(&tables[0])
+}
+
+/// Remove a member of an inline table or an element of an array, along with
+/// the comma separating it from its neighbours.
+fn remove_member(tokens: &[Token], item_start: usize, end: usize) -> Vec<Token> {
+    let mut start = item_start;
+    let mut stop = end;
+    let mut p = stop;
+    while p < tokens.len() && tokens[p].kind == TokenType::Whitespace {
+        p += 1;
+    }
+    if p < tokens.len() && tokens[p] == Token::from(",") {
+        stop = p + 1;
+        while stop < tokens.len() && tokens[stop].kind == TokenType::Whitespace {
+            stop += 1;
+        }
+        if stop < tokens.len() && tokens[stop].kind == TokenType::Comment {
+            stop += 1;
+        }
+    } else {
+        let mut q = start;
+        while q > 0 && is_insignificant(&tokens[q-1]) && tokens[q-1].kind != TokenType::Comment {
+            q -= 1;
+        }
+        if q > 0 && tokens[q-1] == Token::from(",") {
+            start = q - 1;
+        }
+    }
+    // Take the whole line if the item had it to itself
+    let mut line_start = start;
+    while line_start > 0 && tokens[line_start-1].kind == TokenType::Whitespace {
+        line_start -= 1;
+    }
+    let alone = (line_start == 0 || tokens[line_start-1].kind == TokenType::Newline)
+                && stop < tokens.len() && tokens[stop].kind == TokenType::Newline;
+    if alone {
+        start = line_start;
+        stop += 1;
+    }
+    let mut res = tokens[..start].to_vec();
+    res.extend_from_slice(&tokens[stop..]);
+    res
+}
+
+/// Splice `new` into tokens in place of the range start..end.
+fn replace_range(tokens: &[Token], start: usize, end: usize, new: Vec<Token>) -> Vec<Token> {
+    let mut res = tokens[..start].to_vec();
+    res.extend(new);
+    res.extend_from_slice(&tokens[end..]);
+    res
+}
+
+impl Document {
+    /// Parse a document, reporting where it's malformed if it can't be decoded.
+    pub fn parse(text: &str) -> Result<Document, Error> {
+        let tokens = try_tokenise(text)?;
+        let values = decode(&tokens)?;
+        let bom = text.starts_with('\u{feff}');
+        Ok(Document{tokens, values, bom, index: OnceLock::new()})
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// The decoded contents of the whole document.
+    pub fn values(&self) -> &toml::Value {
+        &self.values
+    }
+
+    /// Where each value is written, for fast lookups while the document
+    /// isn't being edited.
+    pub fn index(&self) -> &Index {
+        self.index.get_or_init(|| Index::build(&self.tokens))
+    }
+
+    /// The value at a key path; the empty path gives the root table.
+    pub fn get(&self, key: &KeyPath) -> Option<&toml::Value> {
+        lookup(&self.values, key)
+    }
+
+    pub fn get_str(&self, key: &KeyPath) -> Option<&str> {
+        match self.get(key) {
+            Some(toml::Value::String(s)) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn get_integer(&self, key: &KeyPath) -> Option<i64> {
+        match self.get(key) {
+            Some(toml::Value::Integer(i)) => Some(*i),
+            _ => None
+        }
+    }
+
+    pub fn get_float(&self, key: &KeyPath) -> Option<f64> {
+        match self.get(key) {
+            Some(toml::Value::Float(f)) => Some(*f),
+            _ => None
+        }
+    }
+
+    pub fn get_bool(&self, key: &KeyPath) -> Option<bool> {
+        match self.get(key) {
+            Some(toml::Value::Boolean(b)) => Some(*b),
+            _ => None
+        }
+    }
+
+    /// Replace the tokens, if they make a valid document.
+    fn commit(&mut self, tokens: Vec<Token>) -> Result<(), Error> {
+        self.values = decode(&tokens)?;
+        self.tokens = tokens;
+        self.index = OnceLock::new();
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &KeyPath, value: toml::Value) -> Result<(), Error> {
+        self.set_with(key, value, &InsertOptions::default())
+    }
+
+    /// Set the value at a key path, replacing it where it's written if it
+    /// exists and inserting it otherwise. Missing tables are created, and an
+    /// index one past the end of an array appends to it.
+    ///
+    /// If this fails, the document is left unchanged.
+    pub fn set_with(&mut self, key: &KeyPath, value: toml::Value,
+                    options: &InsertOptions) -> Result<(), Error> {
+        let saved = self.clone();
+        let res = self.set_inner(key, value, options);
+        if res.is_err() {
+            *self = saved;
+        }
+        res
+    }
+
+    /// Add a new key with comment lines, each starting with `#`, directly
+    /// above it. Tables go in `[sections]` where their parent is the root or
+    /// a section. Where the key can't have comments of its own, like inside
+    /// an inline table, it's added without them.
+    ///
+    /// If this fails, the document is left unchanged.
+    pub fn insert_commented(&mut self, key: &KeyPath, value: toml::Value, comments: &[String],
+                            options: &InsertOptions) -> Result<(), Error> {
+        if self.get(key).is_some() {
+            return Err(Error::KeyExists(key.clone()));
+        }
+        let (parent, name) = match (key.parent(), key.parts.last()) {
+            (Some(parent), Some(KeyPathComponent::Key(name))) => (parent, name.clone()),
+            _ => return self.set_with(key, value, options),
+        };
+        if find_table(&self.tokens, &parent).is_none() {
+            return self.set_with(key, value, options);
+        }
+        let saved = self.clone();
+        let res = match value {
+            toml::Value::Table(table) if key.parts.iter().all(|p| matches!(p, KeyPathComponent::Key(_))) => {
+                let tokens = insert_section(&self.tokens, key, false, comments, Vec::new(), &options.policy);
+                self.commit(tokens).and_then(|_| {
+                    table.into_iter().try_for_each(|(k, v)| self.set_with(&key.clone().append_key(k), v, options))
+                })
+            },
+            value => {
+                let newline = detect_newline(&self.tokens);
+                let style = infer_style(&self.tokens, &parent).with_overrides(&options.style);
+                let value_tokens = tokenise_value(&format_value(&value, &style, newline));
+                let tokens = insert_line(&self.tokens, &parent, &style, vec![make_key_token(&name)],
+                                         value_tokens, comments, &options.policy);
+                self.commit(tokens)
+            },
+        };
+        if res.is_err() {
+            *self = saved;
+        }
+        res
+    }
+
+    fn set_inner(&mut self, key: &KeyPath, value: toml::Value,
+                 options: &InsertOptions) -> Result<(), Error> {
+        if key.parts.is_empty() {
+            return Err(Error::WrongType(key.clone(), "key"));
+        }
+        if self.get(key).is_some() {
+            match locate(&self.tokens, key) {
+                Some(ref loc) if loc.place == Place::Section => {
+                    if let toml::Value::Table(table) = value {
+                        return self.refill_section(key, table, options);
+                    }
+                    self.remove(key)?;
+                },
+                Some(loc) => return self.replace_value(&loc, &value),
+                None => {
+                    self.remove(key)?;
+                },
+            }
+        }
+        self.insert(key, value, options)
+    }
+
+    fn replace_value(&mut self, loc: &Location, value: &toml::Value) -> Result<(), Error> {
+        let tables = find_tables(&self.tokens);
+        let table_key = containing_table(&tables, loc.start).key.clone();
+        let style = infer_style(&self.tokens, &table_key);
+        let newline = detect_newline(&self.tokens);
+        let new = tokenise_value(&format_value(value, &style, newline));
+        let tokens = replace_range(&self.tokens, loc.start, loc.end, new);
+        self.commit(tokens)
+    }
+
+    /// Replace the contents of a `[table]` section, keeping it in place.
+    fn refill_section(&mut self, key: &KeyPath, table: toml::Table,
+                      options: &InsertOptions) -> Result<(), Error> {
+        let tables = find_tables(&self.tokens);
+        let subtables: Vec<usize> = (0..tables.len())
+            .filter(|&i| tables[i].key.starts_with(key) && tables[i].key != *key)
+            .collect();
+        let mut tokens = remove_sections(&self.tokens, &tables, &subtables);
+        let section = find_table(&tokens, key).unwrap();
+        for entry in find_entries(&tokens, &section).iter().rev() {
+            tokens = remove_entry(&tokens, &section, entry);
+        }
+        self.commit(tokens)?;
+        for (k, v) in table {
+            self.insert(&key.clone().append_key(k), v, options)?;
+        }
+        Ok(())
+    }
+
+    /// Add a value which doesn't exist yet.
+    fn insert(&mut self, key: &KeyPath, value: toml::Value,
+              options: &InsertOptions) -> Result<(), Error> {
+        let parent = key.parent().unwrap();
+        let name = match *key.parts.last().unwrap() {
+            KeyPathComponent::Key(ref name) => name.clone(),
+            KeyPathComponent::Ix(i) => return self.insert_element(key, i, value, options),
+        };
+        let parent_is_table = match self.get(&parent) {
+            Some(toml::Value::Table(_)) => true,
+            Some(_) => return Err(Error::WrongType(parent, "table")),
+            None => false,
+        };
+        if !parent_is_table {
+            self.create_table(&parent, options)?;
+        }
+        if parent.parts.is_empty() {
+            let tokens = insert_kv_with(&self.tokens, key, value, options);
+            return self.commit(tokens);
+        }
+        match locate(&self.tokens, &parent) {
+            Some(ref loc) if loc.place == Place::Section => {
+                let tokens = insert_kv_with(&self.tokens, key, value, options);
+                self.commit(tokens)
+            },
+            Some(loc) => self.insert_member(&loc, &name, &value),
+            None => self.insert_dotted(key, value, options),
+        }
+    }
+
+    /// Create an empty table which doesn't exist yet.
+    fn create_table(&mut self, key: &KeyPath, options: &InsertOptions) -> Result<(), Error> {
+        let has_index = key.parts.iter().any(|p| matches!(p, KeyPathComponent::Ix(_)));
+        // Tables under sections get their own [header]; elsewhere they're
+        // written as values.
+        let mut ancestor = key.clone();
+        while self.get(&ancestor).is_none() {
+            ancestor = ancestor.parent().unwrap();
+        }
+        let under_section = ancestor.parts.is_empty() || match locate(&self.tokens, &ancestor) {
+            Some(loc) => loc.place == Place::Section,
+            None => !self.has_dotted_entries(&ancestor),
+        };
+        if under_section && !has_index {
+            let tokens = insert_table(&self.tokens, key, options);
+            self.commit(tokens)
+        } else {
+            self.set_inner(key, toml::Value::Table(toml::Table::new()), options)
+        }
+    }
+
+    /// Is this implicit table defined by dotted keys rather than headers?
+    fn has_dotted_entries(&self, key: &KeyPath) -> bool {
+        locate_all(&self.tokens).iter().any(|loc| {
+            loc.place == Place::Entry && loc.key.starts_with(key) && loc.key != *key
+        })
+    }
+
+    /// Add a `key = value` pair to an inline table.
+    fn insert_member(&mut self, loc: &Location, name: &str, value: &toml::Value) -> Result<(), Error> {
+        if self.tokens[loc.start] != Token::from("{") {
+            return Err(Error::WrongType(loc.key.clone(), "table"));
+        }
+        let tables = find_tables(&self.tokens);
+        let table_key = containing_table(&tables, loc.start).key.clone();
+        let style = infer_style(&self.tokens, &table_key);
+        let member = format!("{}{}={}{}", make_key_token(name).text, style.before_eq,
+                             style.after_eq, format_value(value, &style, ""));
+        let close = loc.end - 1;
+        let last = (loc.start + 1..close).rev().find(|&p| !is_insignificant(&self.tokens[p]));
+        let tokens = match last {
+            None => {
+                let text = if style.inline_table_padding {
+                    format!("{{ {} }}", member)
+                } else {
+                    format!("{{{}}}", member)
+                };
+                replace_range(&self.tokens, loc.start, loc.end, tokenise_value(&text))
+            },
+            Some(p) => {
+                let sep = if self.tokens[p] == Token::from(",") { " " } else { ", " };
+                let new = tokenise_value(&format!("{{{}{}}}", sep, member));
+                // Drop the braces we wrapped it in to tokenise it
+                let new = new[1..new.len()-1].to_vec();
+                replace_range(&self.tokens, p + 1, p + 1, new)
+            }
+        };
+        self.commit(tokens)
+    }
+
+    /// Add a dotted key to the section holding the other dotted keys for its table.
+    fn insert_dotted(&mut self, key: &KeyPath, value: toml::Value,
+                     options: &InsertOptions) -> Result<(), Error> {
+        let mut table_key = key.parent().unwrap();
+        while !table_key.parts.is_empty() && find_table(&self.tokens, &table_key).is_none() {
+            table_key = table_key.parent().unwrap();
+        }
+        let mut key_tokens = Vec::new();
+        for part in &key.parts[table_key.parts.len()..] {
+            match *part {
+                KeyPathComponent::Key(ref k) => {
+                    if !key_tokens.is_empty() {
+                        key_tokens.push(Token::from("."));
+                    }
+                    key_tokens.push(make_key_token(k));
+                },
+                KeyPathComponent::Ix(_) => return Err(Error::NotRepresentable(
+                    key.clone(), String::from("array inside dotted key"))),
+            }
+        }
+        let newline = detect_newline(&self.tokens);
+        let style = infer_style(&self.tokens, &table_key).with_overrides(&options.style);
+        let value_tokens = tokenise_value(&format_value(&value, &style, newline));
+        let tokens = insert_line(&self.tokens, &table_key, &style, key_tokens, value_tokens,
+                                 &[], &options.policy);
+        self.commit(tokens)
+    }
+
+    /// Add an array element, at the index one past the end.
+    fn insert_element(&mut self, key: &KeyPath, index: usize, value: toml::Value,
+                      options: &InsertOptions) -> Result<(), Error> {
+        let parent = key.parent().unwrap();
+        let len = match self.get(&parent) {
+            Some(toml::Value::Array(items)) => items.len(),
+            Some(_) => return Err(Error::WrongType(parent, "array")),
+            None if index == 0 => return self.set_inner(&parent, toml::Value::Array(vec![value]), options),
+            None => return Err(Error::KeyNotFound(parent)),
+        };
+        if index != len {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+        match locate(&self.tokens, &parent) {
+            Some(loc) => self.append_to_array(&loc, &value),
+            None => {
+                // An array of [[tables]]
+                let table = match value {
+                    toml::Value::Table(t) => t,
+                    _ => return Err(Error::WrongType(key.clone(), "table")),
+                };
+                let tokens = insert_section(&self.tokens, &parent, true, &[], Vec::new(),
+                                            &options.policy);
+                self.commit(tokens)?;
+                for (k, v) in table {
+                    self.insert(&key.clone().append_key(k), v, options)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn append_to_array(&mut self, loc: &Location, value: &toml::Value) -> Result<(), Error> {
+        let tables = find_tables(&self.tokens);
+        let table_key = containing_table(&tables, loc.start).key.clone();
+        let style = infer_style(&self.tokens, &table_key);
+        let newline = detect_newline(&self.tokens);
+        let new = tokenise_value(&format_value(value, &style, newline));
+        let close = skip_value(&self.tokens, loc.start) - 1;
+        let tokens = &self.tokens;
+        let last = (loc.start + 1..close).rev().find(|&p| !is_insignificant(&tokens[p]));
+        let multiline = tokens[loc.start..close].iter().any(|t| t.kind == TokenType::Newline);
+        let res = match last {
+            None => {
+                let mut array = vec![Token::from("[")];
+                array.extend(new);
+                array.push(Token::from("]"));
+                replace_range(tokens, loc.start, loc.end, array)
+            },
+            Some(p) if multiline => {
+                let had_comma = tokens[p] == Token::from(",");
+                // The new element goes on its own line after the last one,
+                // and any comment following it
+                let mut line_end = p + 1;
+                while line_end < close && tokens[line_end].kind != TokenType::Newline {
+                    line_end += 1;
+                }
+                let mut elem_start = p;
+                while elem_start > loc.start && tokens[elem_start-1].kind != TokenType::Newline {
+                    elem_start -= 1;
+                }
+                let mut line = vec![Token::from(newline)];
+                if tokens[elem_start].kind == TokenType::Whitespace {
+                    line.push(tokens[elem_start].clone());
+                }
+                line.extend(new);
+                if had_comma {
+                    line.push(Token::from(","));
+                }
+                let res = replace_range(tokens, line_end, line_end, line);
+                if had_comma {
+                    res
+                } else {
+                    replace_range(&res, p + 1, p + 1, vec![Token::from(",")])
+                }
+            },
+            Some(p) => {
+                let mut elem = Vec::new();
+                if tokens[p] != Token::from(",") {
+                    elem.push(Token::from(","));
+                }
+                elem.push(Token::from(" "));
+                elem.extend(new);
+                replace_range(tokens, p + 1, p + 1, elem)
+            }
+        };
+        self.commit(res)
+    }
+
+    /// Remove the value at a key path, returning it.
+    pub fn remove(&mut self, key: &KeyPath) -> Result<toml::Value, Error> {
+        let value = match self.get(key) {
+            Some(v) if !key.parts.is_empty() => v.clone(),
+            _ => return Err(Error::KeyNotFound(key.clone())),
+        };
+        let saved = self.clone();
+        let res = self.remove_inner(key);
+        if res.is_err() {
+            *self = saved;
+        }
+        res.map(|_| value)
+    }
+
+    fn remove_inner(&mut self, key: &KeyPath) -> Result<(), Error> {
+        match locate(&self.tokens, key) {
+            Some(loc) => self.remove_located(&loc),
+            None => {
+                // An implicit table or array of tables: remove everything
+                // written inside it, outermost first
+                loop {
+                    let next = locate_all(&self.tokens).into_iter().find(|loc| {
+                        loc.key.starts_with(key)
+                    });
+                    match next {
+                        Some(loc) => self.remove_located(&loc)?,
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_located(&mut self, loc: &Location) -> Result<(), Error> {
+        let tables = find_tables(&self.tokens);
+        let tokens = match loc.place {
+            Place::Section => {
+                let indices: Vec<usize> = (0..tables.len())
+                    .filter(|&i| tables[i].key.starts_with(&loc.key))
+                    .collect();
+                remove_sections(&self.tokens, &tables, &indices)
+            },
+            Place::Entry => {
+                let table = containing_table(&tables, loc.start);
+                let entry = find_entries(&self.tokens, table).into_iter()
+                                .find(|e| e.value_start == loc.start).unwrap();
+                remove_entry(&self.tokens, table, &entry)
+            },
+            Place::Member | Place::Element => remove_member(&self.tokens, loc.item_start, loc.end),
+        };
+        self.commit(tokens)
+    }
+
+    /// Add a value to the end of an array, creating the array if needed.
+    pub fn append(&mut self, key: &KeyPath, value: toml::Value) -> Result<(), Error> {
+        let len = match self.get(key) {
+            Some(toml::Value::Array(items)) => items.len(),
+            Some(_) => return Err(Error::WrongType(key.clone(), "array")),
+            None => 0,
+        };
+        self.set(&key.clone().append_index(len), value)
+    }
+
+    /// Change the last part of a key. Only key tokens are rewritten, so the
+    /// value keeps its place, layout and comments.
+    pub fn rename(&mut self, key: &KeyPath, new_name: &str) -> Result<(), Error> {
+        let parent = match key.parts.last() {
+            Some(KeyPathComponent::Key(_)) => key.parent().unwrap(),
+            _ => return Err(Error::WrongType(key.clone(), "key")),
+        };
+        if self.get(key).is_none() {
+            return Err(Error::KeyNotFound(key.clone()));
+        }
+        let new_key = parent.append_key(String::from(new_name));
+        if new_key == *key {
+            return Ok(());
+        }
+        if self.get(&new_key).is_some() {
+            return Err(Error::KeyExists(new_key));
+        }
+        let part = key.parts.len() - 1;
+        let mut tokens = self.tokens.clone();
+        for loc in locate_all(&self.tokens) {
+            if !loc.key.starts_with(key) || loc.place == Place::Element {
+                continue;
+            }
+            let key_end = if loc.place == Place::Section {
+                loc.start
+            } else {
+                (loc.item_start..loc.start).find(|&p| self.tokens[p] == Token::from("=")).unwrap()
+            };
+            let written: Vec<usize> = (loc.item_start..key_end)
+                .filter(|&p| is_key_token(&self.tokens[p]))
+                .collect();
+            let key_parts: Vec<usize> = loc.key.parts.iter().enumerate()
+                .filter(|&(_, p)| matches!(p, KeyPathComponent::Key(_)))
+                .map(|(i, _)| i)
+                .collect();
+            // What's written is the end of the full key; the rest comes from
+            // the enclosing table
+            let written_parts = &key_parts[key_parts.len() - written.len()..];
+            if let Some(j) = written_parts.iter().position(|&i| i == part) {
+                tokens[written[j]] = make_key_token(new_name);
+            }
+        }
+        self.commit(tokens)
+    }
+}
+
+impl Document {
+    /// Move a value to another key, which may be in a different table.
+    ///
+    /// Entries and sections are moved along with the comments attached to
+    /// them. An entry moved to a table with no section of its own is written
+    /// with a dotted key in the nearest table that has one, unless the table
+    /// doesn't exist yet and the entry wasn't dotted, in which case a section
+    /// is made for it. Values which can't be moved
+    /// that way, like members of inline tables, are removed and set again.
+    ///
+    /// If this fails, the document is left unchanged.
+    pub fn move_to(&mut self, from: &KeyPath, to: &KeyPath) -> Result<(), Error> {
+        for key in &[from, to] {
+            if !matches!(key.parts.last(), Some(KeyPathComponent::Key(_))) {
+                return Err(Error::WrongType((*key).clone(), "key"));
+            }
+        }
+        let value = match self.get(from) {
+            Some(v) => v.clone(),
+            None => return Err(Error::KeyNotFound(from.clone())),
+        };
+        if to == from {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(Error::NotRepresentable(to.clone(), String::from("can't move a value inside itself")));
+        }
+        if self.get(to).is_some() {
+            return Err(Error::KeyExists(to.clone()));
+        }
+        let original = self.clone();
+        match self.move_inner(from, to) {
+            Ok(()) => {
+                debug_assert!(self.get(from).is_none() && self.get(to).is_some_and(|moved| {
+                    compare_values(moved, &value, &CompareOptions::default()).is_empty()
+                }), "moving {} to {} changed the value", from.stringify(), to.stringify());
+                Ok(())
+            },
+            Err(Error::NotRepresentable(..)) => {
+                *self = original.clone();
+                let res = self.remove(from).and_then(|_| self.set(to, value));
+                if res.is_err() {
+                    *self = original;
+                }
+                res
+            },
+            Err(e) => {
+                *self = original;
+                Err(e)
+            },
+        }
+    }
+
+    fn move_inner(&mut self, from: &KeyPath, to: &KeyPath) -> Result<(), Error> {
+        match locate(&self.tokens, from) {
+            Some(ref loc) if loc.place == Place::Entry => self.move_entry(loc, to),
+            Some(ref loc) if loc.place == Place::Section => self.move_sections(from, to),
+            Some(_) => Err(Error::NotRepresentable(from.clone(), String::from("not on a line of its own"))),
+            None => {
+                // An implicit table or array of tables: rename the sections
+                // inside it, then move any dotted keys one at a time
+                self.move_sections(from, to)?;
+                loop {
+                    let next = locate_all(&self.tokens).into_iter().find(|loc| {
+                        loc.key.starts_with(from) && loc.place == Place::Entry
+                    });
+                    match next {
+                        Some(loc) => {
+                            let mut dest = to.clone();
+                            dest.parts.extend_from_slice(&loc.key.parts[from.parts.len()..]);
+                            self.move_entry(&loc, &dest)?;
+                        },
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rewrite the headers of the sections under `from` to be under `to`,
+    /// leaving them where they are.
+    fn move_sections(&mut self, from: &KeyPath, to: &KeyPath) -> Result<(), Error> {
+        let mut tokens = self.tokens.clone();
+        let sections: Vec<Location> = locate_all(&self.tokens).into_iter()
+            .filter(|loc| loc.place == Place::Section && loc.key.starts_with(from))
+            .collect();
+        for loc in sections.iter().rev() {
+            let mut key = to.clone();
+            key.parts.extend_from_slice(&loc.key.parts[from.parts.len()..]);
+            let array = matches!(key.parts.last(), Some(KeyPathComponent::Ix(_)));
+            let names = KeyPath{parts: key.parts.iter()
+                .filter(|p| matches!(p, KeyPathComponent::Key(_))).cloned().collect()};
+            if names.parts.len() + usize::from(array) != key.parts.len() {
+                return Err(Error::NotRepresentable(key, String::from("table header inside an array")));
+            }
+            tokens = replace_range(&tokens, loc.item_start, loc.start, header_tokens(&names, array));
+        }
+        self.commit(tokens)
+    }
+
+    /// Move a `key = value` line, with the comments above it and any comment
+    /// after it on the same line.
+    fn move_entry(&mut self, loc: &Location, to: &KeyPath) -> Result<(), Error> {
+        let tables = find_tables(&self.tokens);
+        let table = containing_table(&tables, loc.start);
+        let entry = find_entries(&self.tokens, table).into_iter()
+                        .find(|e| e.value_start == loc.start).unwrap();
+        let mut line_end = entry.end;
+        if line_end > entry.value_end && self.tokens[line_end-1].kind == TokenType::Newline {
+            line_end -= 1;
+        }
+        let value = self.tokens[entry.value_start..line_end].to_vec();
+        let trailing = value.iter().filter(|t| t.kind == TokenType::Comment).count();
+        let mut comments = entry_comments(&self.tokens, table, &entry);
+        comments.truncate(comments.len() - trailing);
+        let mut res = remove_entry(&self.tokens, table, &entry);
+
+        let parent = to.parent().unwrap();
+        let mut table_key = parent.clone();
+        let dotted = entry_key_parts(&self.tokens, &entry).len() > 1;
+        if find_table(&res, &parent).is_none() {
+            if dotted || self.get(&parent).is_some() {
+                // Dotted keys stay dotted, and go with any others for the
+                // same table, in the nearest section
+                while find_table(&res, &table_key).is_none() {
+                    table_key = table_key.parent().unwrap();
+                }
+            } else {
+                res = insert_table(&res, &parent, &InsertOptions::default());
+            }
+        }
+        let mut key = Vec::new();
+        for part in &to.parts[table_key.parts.len()..] {
+            match *part {
+                KeyPathComponent::Key(ref name) => {
+                    if !key.is_empty() {
+                        key.push(Token::from("."));
+                    }
+                    key.push(make_key_token(name));
+                },
+                KeyPathComponent::Ix(_) => {
+                    return Err(Error::NotRepresentable(to.clone(), String::from("dotted key inside an array")));
+                },
+            }
+        }
+        let style = infer_style(&res, &table_key);
+        let res = insert_line(&res, &table_key, &style, key, value, &comments, &InsertPolicy::Append);
+        self.commit(res)
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bom {
+            f.write_str("\u{feff}")?;
+        }
+        for tok in &self.tokens {
+            f.write_str(&tok.text)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn edited(inp: &str, f: &dyn Fn(&mut Document) -> Result<(), Error>) -> String {
+    let mut doc = Document::parse(inp).unwrap();
+    f(&mut doc).unwrap();
+    doc.to_string()
+}
+
+#[test]
+fn test_document_parse_and_get() {
+    let doc = Document::parse("[a]\nb = \"x\" # c\nn = [1, 2]\n").unwrap();
+    assert_eq!(doc.get_str(&KeyPath::from_string("a.b")), Some("x"));
+    assert_eq!(doc.get_integer(&KeyPath::from_string("a.n[1]")), Some(2));
+    assert_eq!(doc.get(&KeyPath::from_string("a.z")), None);
+    assert_eq!(doc.to_string(), "[a]\nb = \"x\" # c\nn = [1, 2]\n");
+
+    assert_eq!(Document::parse("a = 1\nb = \n").unwrap_err(),
+               Error::Parse(2, 5, String::from("expected a value")));
+
+    // Unicode keys and escapes, and a byte order mark
+    let doc = Document::parse("\u{feff}\"ʎǝʞ\" = \"\\u00e9\\U0001F600\"\n").unwrap();
+    assert_eq!(doc.get_str(&KeyPath::from_string("\"ʎǝʞ\"")), Some("é😀"));
+    assert_eq!(doc.to_string(), "\u{feff}\"ʎǝʞ\" = \"\\u00e9\\U0001F600\"\n");
+    assert_eq!(Document::parse("a = \"\\uD800\"\n").unwrap_err(),
+               Error::Parse(1, 5, String::from("invalid value `\"\\uD800\"`")));
+    assert_eq!(Document::parse("a = 1\né = 2\n").unwrap_err(),
+               Error::Parse(2, 1, String::from("unexpected character 'é'")));
+}
+
+#[test]
+fn test_document_set() {
+    let set = |key: &'static str, value: &'static str| {
+        move |doc: &mut Document| doc.set(&KeyPath::from_string(key), parse_value(value).unwrap())
+    };
+    // Replace in place, keeping comments
+    assert_eq!(edited("[a]\nb = 1 # c\n", &set("a.b", "2")), "[a]\nb = 2 # c\n");
+    assert_eq!(edited("a = {b = 1}\n", &set("a.b", "\"x\"")), "a = {b = \"x\"}\n");
+    // Insert, creating tables
+    assert_eq!(edited("x = 1\n", &set("a.b", "2")), "x = 1\n\n[a]\nb = 2\n");
+    assert_eq!(edited("a = {b = 1}\n", &set("a.c", "2")), "a = {b = 1, c = 2}\n");
+    assert_eq!(edited("a.b = 1\n", &set("a.c", "2")), "a.b = 1\na.c = 2\n");
+    // Append to arrays
+    assert_eq!(edited("a = [1]\n", &set("a[1]", "2")), "a = [1, 2]\n");
+    assert_eq!(edited("a = [\n  1,\n]\n", &set("a[1]", "2")), "a = [\n  1,\n  2,\n]\n");
+    assert_eq!(edited("[[t]]\nx = 1\n", &set("t[1]", "{x = 2}")), "[[t]]\nx = 1\n\n[[t]]\nx = 2\n");
+    // A space between date and time doesn't split the value
+    assert_eq!(edited("a = 1979-05-27 07:32:00Z\n", &set("b", "1")), "a = 1979-05-27 07:32:00Z\nb = 1\n");
+    assert_eq!(edited("a = [1979-05-27 07:32:00Z]\n", &set("a[0]", "2")), "a = [2]\n");
+
+    // Values with CRLF in them come back as they were set
+    for text in &["x = 1\n", "x = 1\r\n"] {
+        let mut doc = Document::parse(text).unwrap();
+        let key = KeyPath::from_string("s");
+        doc.set(&key, toml::Value::String(String::from("x\r\ny\n"))).unwrap();
+        assert_eq!(doc.get_str(&key), Some("x\r\ny\n"));
+        let reparsed = Document::parse(&doc.to_string()).unwrap();
+        assert_eq!(reparsed.get_str(&key), Some("x\r\ny\n"));
+    }
+
+    let mut doc = Document::parse("a = 1\n").unwrap();
+    assert_eq!(doc.set(&KeyPath::from_string("a.b"), toml::Value::Integer(1)),
+               Err(Error::WrongType(KeyPath::from_string("a"), "table")));
+    assert_eq!(doc.to_string(), "a = 1\n");
+}
+
+#[test]
+fn test_document_index_invalidated() {
+    // The cached index doesn't stop documents being shared between threads
+    fn assert_sync<T: Sync + Send>() {}
+    assert_sync::<Document>();
+
+    let mut doc = Document::parse("a = 1\n").unwrap();
+    assert!(!doc.index().contains(&KeyPath::from_string("b")));
+    doc.set(&KeyPath::from_string("b"), toml::Value::Integer(2)).unwrap();
+    assert!(doc.index().contains(&KeyPath::from_string("b")));
+}
+
+#[test]
+fn test_document_remove() {
+    let remove = |key: &'static str| {
+        move |doc: &mut Document| doc.remove(&KeyPath::from_string(key)).map(|_| ())
+    };
+    assert_eq!(edited("# about a\na = 1\nb = 2\n", &remove("a")), "b = 2\n");
+    assert_eq!(edited("a = {b = 1, c = 2}\n", &remove("a.b")), "a = {c = 2}\n");
+    assert_eq!(edited("a = [1, 2]\n", &remove("a[1]")), "a = [1]\n");
+    assert_eq!(edited("a = [\n  1,\n  2,\n]\n", &remove("a[0]")), "a = [\n  2,\n]\n");
+    assert_eq!(edited("x = 1\n\n[a]\nb = 1\n\n[a.c]\nd = 1\n", &remove("a")), "x = 1\n");
+    assert_eq!(edited("[[t]]\nx = 1\n[[t]]\nx = 2\n", &remove("t")), "");
+    assert_eq!(edited("a = [1979-05-27 07:32:00Z, 1]\n", &remove("a[0]")), "a = [1]\n");
+
+    let mut doc = Document::parse("a = 1\n").unwrap();
+    assert_eq!(doc.remove(&KeyPath::from_string("b")), Err(Error::KeyNotFound(KeyPath::from_string("b"))));
+}
+
+#[test]
+fn test_document_rename_append() {
+    assert_eq!(edited("[a.b]\nc = 1 # c\n[a.b.d]\n", &|doc| doc.rename(&KeyPath::from_string("a.b"), "x y")),
+               "[a.\"x y\"]\nc = 1 # c\n[a.\"x y\".d]\n");
+    assert_eq!(edited("a.b = 1\na.c = {b = 2}\n", &|doc| doc.rename(&KeyPath::from_string("a"), "z")),
+               "z.b = 1\nz.c = {b = 2}\n");
+    assert_eq!(edited("a = []\n", &|doc| doc.append(&KeyPath::from_string("a"), toml::Value::Integer(1))),
+               "a = [1]\n");
+}
+
+#[test]
+fn test_document_move_to() {
+    let move_to = |from: &'static str, to: &'static str| {
+        move |doc: &mut Document| doc.move_to(&KeyPath::from_string(from), &KeyPath::from_string(to))
+    };
+    // Into an existing table, keeping comments
+    assert_eq!(edited("[build]\n# Flags\nrustflags = [] # all\nj = 2\n\n[target]\nx = 1\n",
+                      &move_to("build.rustflags", "target.rustflags")),
+               "[build]\nj = 2\n\n[target]\nx = 1\n# Flags\nrustflags = [] # all\n");
+    // To a new table, and to one defined by dotted keys
+    assert_eq!(edited("[a]\nx = 1\n", &move_to("a.x", "b.y")), "[a]\n\n[b]\ny = 1\n");
+    assert_eq!(edited("b.z = 2\n\n[a]\nx = 1\n", &move_to("a.x", "b.y")), "b.z = 2\nb.y = 1\n\n[a]\n");
+    // Sections are renamed where they are
+    assert_eq!(edited("[a]\nx = 1\n[a.b]\ny = 2\n[[c]]\n", &move_to("a", "d.e")),
+               "[d.e]\nx = 1\n[d.e.b]\ny = 2\n[[c]]\n");
+    assert_eq!(edited("[[bin]]\nname = \"a\"\n[[bin]]\nname = \"b\"\n", &move_to("bin", "x")),
+               "[[x]]\nname = \"a\"\n[[x]]\nname = \"b\"\n");
+    // Dotted keys are moved one by one
+    assert_eq!(edited("a.x = 1\na.y = 2\n", &move_to("a", "b")), "b.x = 1\nb.y = 2\n");
+    // Inline table members are removed and set again
+    assert_eq!(edited("a = {x = 1, y = 2}\nb = {z = 3}\n", &move_to("a.x", "b.x")),
+               "a = {y = 2}\nb = {z = 3, x = 1}\n");
+
+    let mut doc = Document::parse("a = 1\nb = 2\n").unwrap();
+    assert_eq!(doc.move_to(&KeyPath::from_string("a"), &KeyPath::from_string("b")),
+               Err(Error::KeyExists(KeyPath::from_string("b"))));
+}
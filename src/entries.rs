@@ -0,0 +1,88 @@
+//! Walk every value written in a document, in document order.
+
+extern crate toml;
+
+use std::vec;
+
+use super::tokenise::TokenType;
+use super::keypath::KeyPath;
+use super::document::Document;
+use super::locate::{Place, Location, locate_all};
+use super::index::Span;
+
+/// One value written in a document.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Entry<'a> {
+    pub key: KeyPath,
+    pub value: &'a toml::Value,
+    /// The token type of a single token value, like a string or a number;
+    /// `None` for sections, arrays and inline tables
+    pub kind: Option<TokenType>,
+    pub span: Span,
+}
+
+/// Iterator returned by `Document::entries`.
+pub struct Entries<'a> {
+    doc: &'a Document,
+    locations: vec::IntoIter<Location>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        // Every location has a value; one without means the locator and the
+        // decoder disagree, which is a bug. Release builds skip it.
+        let (loc, value) = loop {
+            let loc = self.locations.next()?;
+            match self.doc.get(&loc.key) {
+                Some(value) => break (loc, value),
+                None => debug_assert!(false, "no value at located key {}", loc.key.stringify()),
+            }
+        };
+        let kind = match loc.place {
+            Place::Section => None,
+            _ => match self.doc.tokens()[loc.start].kind {
+                TokenType::Punctuation => None,
+                ref k => Some(k.clone()),
+            },
+        };
+        Some(Entry{key: loc.key, value, kind, span: Span{start: loc.item_start, end: loc.end}})
+    }
+}
+
+impl Document {
+    /// Every value written in the document, in the order it's written.
+    /// Sections, arrays and inline tables come before the values inside
+    /// them, with array elements and `[[array]]` sections keyed by index.
+    /// Tables which are only implied by dotted keys or the headers of their
+    /// subtables aren't included.
+    pub fn entries(&self) -> Entries<'_> {
+        Entries{doc: self, locations: locate_all(self.tokens()).into_iter()}
+    }
+}
+
+#[test]
+fn test_entries() {
+    let doc = Document::parse("a.b = \"x\"\n[[t]]\nn = [1, {p = 'y'}]\n").unwrap();
+    let found: Vec<(String, Option<TokenType>)> = doc.entries()
+        .map(|e| (e.key.stringify(), e.kind))
+        .collect();
+    assert_eq!(found, vec![
+        (String::from(".a.b"), Some(TokenType::BasicString)),
+        (String::from(".t[0]"), None),
+        (String::from(".t[0].n"), None),
+        (String::from(".t[0].n[0]"), Some(TokenType::Integer)),
+        (String::from(".t[0].n[1]"), None),
+        (String::from(".t[0].n[1].p"), Some(TokenType::LiteralString)),
+    ]);
+
+    // Finding all the strings
+    let strings: Vec<&toml::Value> = doc.entries().filter(|e| e.value.as_str().is_some()).map(|e| e.value).collect();
+    assert_eq!(strings, vec![&toml::Value::String(String::from("x")), &toml::Value::String(String::from("y"))]);
+
+    // A datetime with a space in it is one value
+    let doc = Document::parse("a = [1979-05-27 07:32:00Z]\n").unwrap();
+    let found: Vec<(String, Option<TokenType>)> = doc.entries().map(|e| (e.key.stringify(), e.kind)).collect();
+    assert_eq!(found, vec![(String::from(".a"), None), (String::from(".a[0]"), Some(TokenType::Datetime))]);
+}
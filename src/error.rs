@@ -0,0 +1,51 @@
+use std::error;
+use std::fmt;
+
+use super::keypath::KeyPath;
+
+#[derive(Debug,PartialEq,Clone)]
+pub enum Error {
+    /// Nothing exists at this key path
+    KeyNotFound(KeyPath),
+    /// The value at this key path isn't the kind the operation needs
+    WrongType(KeyPath, &'static str),
+    /// The result can't be written in TOML syntax
+    NotRepresentable(KeyPath, String),
+    /// Malformed TOML, with the 1-based line and column where it went wrong
+    Parse(usize, usize, String),
+    /// A key path string that couldn't be understood
+    InvalidKeyPath(String),
+    /// Something already exists at this key path
+    KeyExists(KeyPath),
+    /// A list of edits couldn't be read
+    InvalidScript(String),
+    /// An edit in a batch failed; the index counts from 0
+    Operation(usize, Box<Error>),
+    /// A patch's test found a different value at this key path
+    TestFailed(KeyPath),
+    /// JSON or YAML data that couldn't be read
+    InvalidInput(String),
+    /// A `${VAR}` placeholder in the string at this key path names a
+    /// variable which isn't set
+    Unresolved(KeyPath, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::KeyNotFound(ref key) => write!(f, "key not found: {}", key.stringify()),
+            Error::WrongType(ref key, expected) => write!(f, "expected {} at {}", expected, key.stringify()),
+            Error::NotRepresentable(ref key, ref why) => write!(f, "can't represent {}: {}", key.stringify(), why),
+            Error::Parse(line, col, ref msg) => write!(f, "line {}, column {}: {}", line, col, msg),
+            Error::InvalidKeyPath(ref s) => write!(f, "invalid key path: {}", s),
+            Error::KeyExists(ref key) => write!(f, "key already exists: {}", key.stringify()),
+            Error::InvalidScript(ref why) => write!(f, "invalid edit script: {}", why),
+            Error::Operation(i, ref e) => write!(f, "edit {} failed: {}", i + 1, e),
+            Error::TestFailed(ref key) => write!(f, "test failed: {} has a different value", key.stringify()),
+            Error::InvalidInput(ref why) => write!(f, "invalid input: {}", why),
+            Error::Unresolved(ref key, ref var) => write!(f, "variable {} not set, in {}", var, key.stringify()),
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -0,0 +1,401 @@
+//! Normalise the layout of a TOML document.
+//!
+//! Formatting only changes whitespace, blank lines and key quoting; values
+//! and comments are written back exactly as they were.
+
+use super::tokenise::{Token, TokenType, detect_newline};
+use super::tableix::{skip_value, key_positions, make_key_token};
+use super::key_token_to_string;
+
+/// How arrays should be laid out.
+#[derive(Debug,PartialEq,Clone)]
+pub enum ArrayWrap {
+    /// Leave arrays as they are
+    Preserve,
+    /// Put every array on one line
+    Inline,
+    /// Put each element of every non-empty array on its own line
+    OnePerLine,
+    /// Keep arrays on one line if the line fits in this many characters,
+    /// otherwise put each element on its own line
+    Width(usize),
+}
+
+/// Rules for `format`. The default applies all of them.
+#[derive(Debug,PartialEq,Clone)]
+pub struct FormatOptions {
+    /// Whitespace to put either side of `=`, or None to leave it alone
+    pub eq_spacing: Option<String>,
+    pub trim_trailing_whitespace: bool,
+    /// Collapse runs of blank lines longer than this, and remove blank lines
+    /// at the start of the document
+    pub max_blank_lines: Option<usize>,
+    /// Ensure there's a blank line before each table header (and the
+    /// comments directly above it)
+    pub blank_line_before_table: bool,
+    /// Write keys bare where possible, and with basic quotes otherwise
+    pub canonical_keys: bool,
+    pub array_wrap: ArrayWrap,
+    /// Indentation for array elements on their own lines
+    pub array_indent: String,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            eq_spacing: Some(String::from(" ")),
+            trim_trailing_whitespace: true,
+            max_blank_lines: Some(1),
+            blank_line_before_table: true,
+            canonical_keys: true,
+            array_wrap: ArrayWrap::Width(80),
+            array_indent: String::from("    "),
+        }
+    }
+}
+
+/// Normalise a token stream according to the options.
+pub fn format(tokens: &[Token], options: &FormatOptions) -> Vec<Token> {
+    let mut res = tokens.to_vec();
+    if options.canonical_keys {
+        res = canonicalise_keys(&res);
+    }
+    if let Some(ref spacing) = options.eq_spacing {
+        res = space_eq(&res, spacing);
+    }
+    if options.array_wrap != ArrayWrap::Preserve {
+        res = wrap_arrays(&res, options);
+    }
+    if options.trim_trailing_whitespace {
+        res = trim_trailing_whitespace(&res);
+    }
+    if options.blank_line_before_table {
+        res = blank_line_before_tables(&res);
+    }
+    if let Some(max) = options.max_blank_lines {
+        res = collapse_blank_lines(&res, max);
+    }
+    res
+}
+
+/// Returns true if `format` would change the document.
+pub fn check(tokens: &[Token], options: &FormatOptions) -> bool {
+    format(tokens, options).as_slice() != tokens
+}
+
+fn canonicalise_keys(tokens: &[Token]) -> Vec<Token> {
+    let is_key = key_positions(tokens);
+    tokens.iter().zip(is_key).map(|(tok, is_key)| {
+        if is_key && tok.kind != TokenType::BareKey {
+            make_key_token(&key_token_to_string(tok))
+        } else {
+            tok.clone()
+        }
+    }).collect()
+}
+
+fn space_eq(tokens: &[Token], spacing: &str) -> Vec<Token> {
+    let mut res: Vec<Token> = Vec::new();
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let tok = &tokens[pos];
+        pos += 1;
+        if *tok != Token::from("=") {
+            res.push(tok.clone());
+            continue;
+        }
+        if res.last().map(|t| t.kind == TokenType::Whitespace).unwrap_or(false) {
+            res.pop();
+        }
+        if !spacing.is_empty() {
+            res.push(Token::from(spacing));
+        }
+        res.push(tok.clone());
+        if !spacing.is_empty() {
+            res.push(Token::from(spacing));
+        }
+        if pos < tokens.len() && tokens[pos].kind == TokenType::Whitespace {
+            pos += 1;
+        }
+    }
+    res
+}
+
+fn trim_trailing_whitespace(tokens: &[Token]) -> Vec<Token> {
+    let mut res = Vec::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        if tok.kind == TokenType::Whitespace {
+            match tokens.get(i + 1) {
+                None => continue,
+                Some(next) if next.kind == TokenType::Newline => continue,
+                _ => ()
+            }
+        }
+        res.push(tok.clone());
+    }
+    res
+}
+
+/// Positions of the `[` starting each table header.
+fn header_starts(tokens: &[Token]) -> Vec<usize> {
+    let mut res = Vec::new();
+    let mut at_line_start = true;
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let tok = &tokens[pos];
+        match tok.kind {
+            TokenType::Newline => at_line_start = true,
+            TokenType::Whitespace | TokenType::Comment => (),
+            _ => {
+                if at_line_start && *tok == Token::from("[") {
+                    res.push(pos);
+                    // Skip to the end of the header
+                    while pos < tokens.len() && tokens[pos].kind != TokenType::Newline {
+                        pos += 1;
+                    }
+                    continue;
+                }
+                at_line_start = false;
+                if tok.kind == TokenType::Punctuation && (tok.text == "[" || tok.text == "{") {
+                    // Values can span lines; skip to the end of this one
+                    pos = skip_value(tokens, pos);
+                    continue;
+                }
+            }
+        }
+        pos += 1;
+    }
+    res
+}
+
+fn line_start(tokens: &[Token], pos: usize) -> usize {
+    let mut start = pos;
+    while start > 0 && tokens[start-1].kind != TokenType::Newline {
+        start -= 1;
+    }
+    start
+}
+
+fn is_blank(line: &[Token]) -> bool {
+    line.iter().all(|t| t.kind == TokenType::Whitespace)
+}
+
+fn is_comment_line(line: &[Token]) -> bool {
+    let mut significant = line.iter().filter(|t| t.kind != TokenType::Whitespace);
+    match (significant.next(), significant.next()) {
+        (Some(tok), None) => tok.kind == TokenType::Comment,
+        _ => false
+    }
+}
+
+fn blank_line_before_tables(tokens: &[Token]) -> Vec<Token> {
+    let newline = detect_newline(tokens);
+    let mut insert_at = Vec::new();
+    for header in header_starts(tokens) {
+        // Comments directly above the header go with it
+        let mut start = line_start(tokens, header);
+        while start > 0 {
+            let prev = line_start(tokens, start - 1);
+            if !is_comment_line(&tokens[prev..start-1]) {
+                break;
+            }
+            start = prev;
+        }
+        if start == 0 {
+            continue;
+        }
+        let prev = line_start(tokens, start - 1);
+        if !is_blank(&tokens[prev..start-1]) {
+            insert_at.push(start);
+        }
+    }
+    let mut res = Vec::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        if insert_at.contains(&i) {
+            res.push(Token::from(newline));
+        }
+        res.push(tok.clone());
+    }
+    res
+}
+
+fn collapse_blank_lines(tokens: &[Token], max: usize) -> Vec<Token> {
+    let mut res: Vec<Token> = Vec::new();
+    let mut blank_run = 0;
+    let mut line = Vec::new();
+    for tok in tokens {
+        line.push(tok.clone());
+        if tok.kind != TokenType::Newline {
+            continue;
+        }
+        // A newline token ends a line; is it blank?
+        if is_blank(&line[..line.len()-1]) {
+            blank_run += 1;
+            if res.is_empty() || blank_run > max {
+                line.clear();
+                continue;
+            }
+        } else {
+            blank_run = 0;
+        }
+        res.append(&mut line);
+    }
+    res.append(&mut line);
+    res
+}
+
+/// Split the contents of an array into the significant tokens of each
+/// element, or None if the array holds comments or multi-line elements.
+fn array_elements(tokens: &[Token], start: usize, end: usize) -> Option<Vec<Vec<Token>>> {
+    let mut elements = Vec::new();
+    let mut pos = start + 1;
+    while pos < end - 1 {
+        let tok = &tokens[pos];
+        match tok.kind {
+            TokenType::Comment => return None,
+            TokenType::Whitespace | TokenType::Newline => pos += 1,
+            _ if *tok == Token::from(",") => pos += 1,
+            _ => {
+                let elem_end = skip_value(tokens, pos);
+                let elem = &tokens[pos..elem_end];
+                if elem.iter().any(|t| t.kind == TokenType::Newline || t.kind == TokenType::Comment) {
+                    return None;
+                }
+                elements.push(elem.to_vec());
+                pos = elem_end;
+            }
+        }
+    }
+    Some(elements)
+}
+
+fn text_len(tokens: &[Token]) -> usize {
+    tokens.iter().map(|t| t.text.chars().count()).sum()
+}
+
+fn wrap_arrays(tokens: &[Token], options: &FormatOptions) -> Vec<Token> {
+    let newline = detect_newline(tokens);
+    let headers = header_starts(tokens);
+    let mut res = Vec::new();
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let tok = &tokens[pos];
+        if headers.contains(&pos) {
+            // Copy the whole header, including the second `[` of `[[array]]`
+            while pos < tokens.len() && tokens[pos].kind != TokenType::Newline {
+                res.push(tokens[pos].clone());
+                pos += 1;
+            }
+            continue;
+        }
+        if *tok != Token::from("[") {
+            res.push(tok.clone());
+            pos += 1;
+            continue;
+        }
+        let end = skip_value(tokens, pos);
+        let elements = match array_elements(tokens, pos, end) {
+            Some(elements) => elements,
+            None => {
+                res.extend_from_slice(&tokens[pos..end]);
+                pos = end;
+                continue;
+            }
+        };
+        let line_begin = line_start(&res, res.len());
+        let indent = match res.get(line_begin) {
+            Some(t) if t.kind == TokenType::Whitespace => t.text.clone(),
+            _ => String::new(),
+        };
+        // Length of the line if the array were written inline
+        let column = text_len(&res[line_begin..]);
+        let inline_len = 2 + elements.iter().map(|e| text_len(e) + 2).sum::<usize>()
+                         - if elements.is_empty() { 0 } else { 2 };
+        let rest_of_line = tokens[end..].iter()
+                            .take_while(|t| t.kind != TokenType::Newline).collect::<Vec<_>>();
+        let rest_len: usize = rest_of_line.iter().map(|t| t.text.chars().count()).sum();
+        let one_per_line = !elements.is_empty() && match options.array_wrap {
+            ArrayWrap::OnePerLine => true,
+            ArrayWrap::Width(width) => column + inline_len + rest_len > width,
+            _ => false
+        };
+        res.push(Token::from("["));
+        for (i, elem) in elements.iter().enumerate() {
+            if one_per_line {
+                res.push(Token::from(newline));
+                res.push(Token::from(&format!("{}{}", indent, options.array_indent)));
+            } else if i > 0 {
+                res.push(Token::from(" "));
+            }
+            res.extend_from_slice(elem);
+            if one_per_line || i + 1 < elements.len() {
+                res.push(Token::from(","));
+            }
+        }
+        if one_per_line {
+            res.push(Token::from(newline));
+            if !indent.is_empty() {
+                res.push(Token::from(&indent));
+            }
+        }
+        res.push(Token::from("]"));
+        pos = end;
+    }
+    res
+}
+
+#[cfg(test)]
+use super::tokenise::{tokenise, text_of};
+
+#[cfg(test)]
+fn format_str(s: &str, options: &FormatOptions) -> String {
+    text_of(&format(&tokenise(s), options))
+}
+
+#[test]
+fn test_format_default() {
+    let inp = "\
+\"plain\"=1   \n\n\n\n# about a
+[a]
+'quoted key'   =   [ 1,2,
+  3 ]
+# Comment  with  spaces
+[a.\"b\"]
+x={y=1}
+";
+    let exp = "\
+plain = 1
+
+# about a
+[a]
+\"quoted key\" = [1, 2, 3]
+
+# Comment  with  spaces
+[a.b]
+x = {y = 1}
+";
+    assert_eq!(format_str(inp, &FormatOptions::default()), exp);
+    assert!(!check(&tokenise(exp), &FormatOptions::default()));
+    assert!(check(&tokenise(inp), &FormatOptions::default()));
+}
+
+#[test]
+fn test_format_arrays() {
+    let options = FormatOptions{array_wrap: ArrayWrap::Width(20), ..Default::default()};
+    assert_eq!(format_str("a = [1, 2]\nb = [\"long\", \"strings\", \"here\"]\n", &options),
+               "a = [1, 2]\nb = [\n    \"long\",\n    \"strings\",\n    \"here\",\n]\n");
+    // Arrays with comments are left alone
+    let inp = "a = [\n  1, # one\n]\n";
+    assert_eq!(format_str(inp, &FormatOptions{array_wrap: ArrayWrap::Inline, ..Default::default()}), inp);
+    // Headers aren't arrays
+    let options = FormatOptions{array_wrap: ArrayWrap::OnePerLine, ..Default::default()};
+    assert_eq!(format_str("[[a]]\nx = [1]\n\n[[a]]\n", &options), "[[a]]\nx = [\n    1,\n]\n\n[[a]]\n");
+}
+
+#[test]
+fn test_format_leading_blank_lines() {
+    assert_eq!(format_str("\n  \n\n[a]\nx = 1\n", &FormatOptions::default()), "[a]\nx = 1\n");
+    assert!(check(&tokenise("\na = 1\n"), &FormatOptions::default()));
+    let options = FormatOptions{max_blank_lines: None, ..Default::default()};
+    assert_eq!(format_str("\n\na = 1\n", &options), "\n\na = 1\n");
+}
@@ -0,0 +1,278 @@
+//! Syntax highlighting for token streams, as ANSI-coloured text for
+//! terminals or as HTML.
+
+use super::tokenise::{Token, TokenType};
+use super::tableix::key_positions;
+use super::diff::{DiffLine, line_diff, range};
+
+/// What a token is, for colouring it.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Highlight {
+    /// Part of a key on the left of `=`
+    Key,
+    /// Anything in a `[table]` or `[[array]]` header, brackets included
+    Header,
+    String,
+    Number,
+    Boolean,
+    Datetime,
+    Comment,
+    Punctuation,
+    /// Text that couldn't be tokenised
+    Error,
+    /// Whitespace and line endings
+    Plain,
+}
+
+impl Highlight {
+    /// The CSS class used in HTML output, or None for plain text.
+    pub fn css_class(&self) -> Option<&'static str> {
+        Some(match *self {
+            Highlight::Key => "toml-key",
+            Highlight::Header => "toml-header",
+            Highlight::String => "toml-string",
+            Highlight::Number => "toml-number",
+            Highlight::Boolean => "toml-boolean",
+            Highlight::Datetime => "toml-datetime",
+            Highlight::Comment => "toml-comment",
+            Highlight::Punctuation => "toml-punctuation",
+            Highlight::Error => "toml-error",
+            Highlight::Plain => return None,
+        })
+    }
+
+    /// The SGR parameters to colour this in a terminal, or None for plain
+    /// text.
+    pub fn ansi_code(&self) -> Option<&'static str> {
+        match *self {
+            Highlight::Key => Some("34"),
+            Highlight::Header => Some("1;35"),
+            Highlight::String => Some("32"),
+            Highlight::Number | Highlight::Boolean => Some("36"),
+            Highlight::Datetime => Some("33"),
+            Highlight::Comment => Some("90"),
+            Highlight::Error => Some("4;31"),
+            Highlight::Punctuation | Highlight::Plain => None,
+        }
+    }
+}
+
+/// Classify every token. This works on any token stream, including ones
+/// with `Error` tokens from `tokenise_recovering`.
+pub fn highlight(tokens: &[Token]) -> Vec<Highlight> {
+    let is_key = key_positions(tokens);
+    let mut res = Vec::with_capacity(tokens.len());
+    let mut in_header = false;
+    let mut at_line_start = true;
+    let mut depth = 0;
+    for (i, tok) in tokens.iter().enumerate() {
+        let kind = match tok.kind {
+            TokenType::Newline => {
+                in_header = false;
+                at_line_start = depth == 0;
+                res.push(Highlight::Plain);
+                continue;
+            },
+            TokenType::Whitespace => Highlight::Plain,
+            TokenType::Comment => Highlight::Comment,
+            TokenType::Error => Highlight::Error,
+            _ if in_header || (at_line_start && *tok == Token::from("[")) => {
+                in_header = true;
+                Highlight::Header
+            },
+            _ if is_key[i] => Highlight::Key,
+            TokenType::Punctuation => {
+                match tok.text.as_str() {
+                    "[" | "{" => depth += 1,
+                    "]" | "}" if depth > 0 => depth -= 1,
+                    _ => (),
+                }
+                Highlight::Punctuation
+            },
+            TokenType::BareKey => Highlight::Key,
+            TokenType::BasicString | TokenType::LiteralString
+            | TokenType::MultilineBasicString | TokenType::MultilineLiteralString => Highlight::String,
+            TokenType::Integer | TokenType::Float => Highlight::Number,
+            TokenType::Boolean => Highlight::Boolean,
+            TokenType::Datetime => Highlight::Datetime,
+        };
+        if kind != Highlight::Plain && kind != Highlight::Comment {
+            at_line_start = false;
+        }
+        res.push(kind);
+    }
+    res
+}
+
+/// Colour a token's text. Each line is coloured separately, so every line
+/// of the output can be shown on its own.
+fn ansi_token(kind: Highlight, text: &str) -> String {
+    let code = match kind.ansi_code() {
+        Some(code) => code,
+        None => return String::from(text),
+    };
+    let lines: Vec<String> = text.split('\n').map(|line| {
+        let (body, cr) = match line.strip_suffix('\r') {
+            Some(body) => (body, "\r"),
+            None => (line, ""),
+        };
+        if body.is_empty() {
+            String::from(line)
+        } else {
+            format!("\x1b[{}m{}\x1b[0m{}", code, body, cr)
+        }
+    }).collect();
+    lines.join("\n")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn html_token(kind: Highlight, text: &str) -> String {
+    match kind.css_class() {
+        Some(class) => format!("<span class=\"{}\">{}</span>", class, escape_html(text)),
+        None => escape_html(text),
+    }
+}
+
+/// Render each token, split into lines the way `line_diff` splits them:
+/// at every line ending, including those inside multiline strings, leaving
+/// the line endings out.
+fn render_lines(tokens: &[Token], render: fn(Highlight, &str) -> String) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut line = String::new();
+    for (tok, kind) in tokens.iter().zip(highlight(tokens)) {
+        let text = render(kind, &tok.text);
+        let mut pieces = text.split('\n');
+        line.push_str(pieces.next().unwrap());
+        for piece in pieces {
+            if line.ends_with('\r') {
+                line.pop();
+            }
+            res.push(line);
+            line = String::from(piece);
+        }
+    }
+    if !line.is_empty() {
+        res.push(line);
+    }
+    res
+}
+
+fn render(tokens: &[Token], render: fn(Highlight, &str) -> String) -> String {
+    tokens.iter().zip(highlight(tokens)).map(|(tok, kind)| render(kind, &tok.text)).collect()
+}
+
+/// The text of the tokens, with ANSI escape codes to colour it.
+pub fn to_ansi(tokens: &[Token]) -> String {
+    render(tokens, ansi_token)
+}
+
+/// The text of the tokens as HTML, with each token that isn't whitespace
+/// in a `<span>` with a CSS class like `toml-key` or `toml-string` (see
+/// `Highlight::css_class`). The result is meant to go in a `<pre>` element.
+pub fn to_html(tokens: &[Token]) -> String {
+    render(tokens, html_token)
+}
+
+/// Like `unified_diff`, but with the lines highlighted and the `-` and `+`
+/// markers coloured, for showing in a terminal.
+pub fn ansi_diff(old: &[Token], new: &[Token], path: &str) -> String {
+    let hunks = line_diff(old, new);
+    if hunks.is_empty() {
+        return String::new();
+    }
+    let old_lines = render_lines(old, ansi_token);
+    let new_lines = render_lines(new, ansi_token);
+    let mut res = format!("\x1b[1m--- a/{}\n+++ b/{}\x1b[0m\n", path, path);
+    for hunk in hunks {
+        res.push_str(&format!("\x1b[36m@@ -{} +{} @@\x1b[0m\n", range(hunk.old_start, hunk.old_len),
+                              range(hunk.new_start, hunk.new_len)));
+        let (mut old_ix, mut new_ix) = (hunk.old_start - 1, hunk.new_start - 1);
+        for line in &hunk.lines {
+            match *line {
+                DiffLine::Context(ref s) => {
+                    res.push_str(&format!(" {}\n", new_lines.get(new_ix).unwrap_or(s)));
+                    old_ix += 1;
+                    new_ix += 1;
+                },
+                DiffLine::Removed(ref s) => {
+                    res.push_str(&format!("\x1b[31m-\x1b[0m{}\n", old_lines.get(old_ix).unwrap_or(s)));
+                    old_ix += 1;
+                },
+                DiffLine::Added(ref s) => {
+                    res.push_str(&format!("\x1b[32m+\x1b[0m{}\n", new_lines.get(new_ix).unwrap_or(s)));
+                    new_ix += 1;
+                },
+                DiffLine::NoNewlineAtEnd => res.push_str("\\ No newline at end of file\n"),
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+use super::tokenise::{tokenise, tokenise_recovering};
+
+#[test]
+fn test_highlight() {
+    let tokens = tokenise("# c\n[a.\"b\"]\nx.y = [1, true, 'z', 1979-05-27]\n[[t]]\n");
+    let kinds: Vec<(&str, Highlight)> = tokens.iter().map(|t| t.text.as_str()).zip(highlight(&tokens))
+        .filter(|&(_, k)| k != Highlight::Plain)
+        .collect();
+    assert_eq!(kinds, vec![
+        ("# c", Highlight::Comment),
+        ("[", Highlight::Header), ("a", Highlight::Header), (".", Highlight::Header),
+        ("\"b\"", Highlight::Header), ("]", Highlight::Header),
+        ("x", Highlight::Key), (".", Highlight::Punctuation), ("y", Highlight::Key), ("=", Highlight::Punctuation),
+        ("[", Highlight::Punctuation), ("1", Highlight::Number), (",", Highlight::Punctuation),
+        ("true", Highlight::Boolean), (",", Highlight::Punctuation), ("'z'", Highlight::String),
+        (",", Highlight::Punctuation), ("1979-05-27", Highlight::Datetime), ("]", Highlight::Punctuation),
+        ("[", Highlight::Header), ("[", Highlight::Header), ("t", Highlight::Header),
+        ("]", Highlight::Header), ("]", Highlight::Header),
+    ]);
+}
+
+#[test]
+fn test_render() {
+    let tokens = tokenise("a = \"<x>\" # &\n");
+    assert_eq!(to_html(&tokens), concat!(
+        "<span class=\"toml-key\">a</span> <span class=\"toml-punctuation\">=</span> ",
+        "<span class=\"toml-string\">&quot;&lt;x&gt;&quot;</span> <span class=\"toml-comment\"># &amp;</span>\n"));
+    assert_eq!(to_ansi(&tokens), "\x1b[34ma\x1b[0m = \x1b[32m\"<x>\"\x1b[0m \x1b[90m# &\x1b[0m\n");
+
+    // Each line of a multiline string is coloured on its own
+    let tokens = tokenise("s = '''\r\nx\r\n'''");
+    assert_eq!(to_ansi(&tokens), "\x1b[34ms\x1b[0m = \x1b[32m'''\x1b[0m\r\n\x1b[32mx\x1b[0m\r\n\x1b[32m'''\x1b[0m");
+
+    let (tokens, _) = tokenise_recovering("a = ?\n");
+    assert_eq!(to_ansi(&tokens), "\x1b[34ma\x1b[0m = \x1b[4;31m?\x1b[0m\n");
+}
+
+#[test]
+fn test_ansi_diff() {
+    let old = tokenise("a = 1\nb = 2\n");
+    let new = tokenise("a = 1\nb = 3\n");
+    assert_eq!(ansi_diff(&old, &new, "f.toml"), concat!(
+        "\x1b[1m--- a/f.toml\n+++ b/f.toml\x1b[0m\n",
+        "\x1b[36m@@ -1,2 +1,2 @@\x1b[0m\n",
+        " \x1b[34ma\x1b[0m = \x1b[36m1\x1b[0m\n",
+        "\x1b[31m-\x1b[0m\x1b[34mb\x1b[0m = \x1b[36m2\x1b[0m\n",
+        "\x1b[32m+\x1b[0m\x1b[34mb\x1b[0m = \x1b[36m3\x1b[0m\n"));
+    assert_eq!(ansi_diff(&old, &old, "f.toml"), "");
+}
+
+#[test]
+fn test_ansi_diff_multiline_string() {
+    let old = tokenise("s = '''\r\nx\r\n'''\r\nb = 1\r\n");
+    let new = tokenise("s = '''\r\nx\r\n'''\r\nb = 2\r\n");
+    assert_eq!(ansi_diff(&old, &new, "f.toml"), concat!(
+        "\x1b[1m--- a/f.toml\n+++ b/f.toml\x1b[0m\n",
+        "\x1b[36m@@ -1,4 +1,4 @@\x1b[0m\n",
+        " \x1b[34ms\x1b[0m = \x1b[32m'''\x1b[0m\n",
+        " \x1b[32mx\x1b[0m\n",
+        " \x1b[32m'''\x1b[0m\n",
+        "\x1b[31m-\x1b[0m\x1b[34mb\x1b[0m = \x1b[36m1\x1b[0m\n",
+        "\x1b[32m+\x1b[0m\x1b[34mb\x1b[0m = \x1b[36m2\x1b[0m\n"));
+}
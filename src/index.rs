@@ -0,0 +1,100 @@
+//! A lookup table from key paths to where their values are written.
+
+use std::collections::{HashMap, HashSet};
+
+use super::tokenise::Token;
+use super::keypath::KeyPath;
+use super::locate::locate_all;
+
+/// Token positions of a written value, from the start of its header or key
+/// (or the value itself, for array elements) to one past its end.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Every key, table, array element and inline table member in a document,
+/// found in one pass so that lookups don't need to scan the tokens.
+#[derive(Debug,Clone,Default)]
+pub struct Index {
+    spans: HashMap<KeyPath, Span>,
+    /// Written keys in document order
+    order: Vec<KeyPath>,
+    /// Positions in `order`, sorted by key, so the keys inside a key are
+    /// together
+    sorted: Vec<usize>,
+    /// Written keys and the tables they imply
+    present: HashSet<KeyPath>,
+}
+
+impl Index {
+    pub fn build(tokens: &[Token]) -> Index {
+        let mut index = Index::default();
+        for loc in locate_all(tokens) {
+            let mut ancestor = loc.key.parent();
+            while let Some(key) = ancestor {
+                if !index.present.insert(key.clone()) {
+                    break;
+                }
+                ancestor = key.parent();
+            }
+            index.present.insert(loc.key.clone());
+            index.spans.insert(loc.key.clone(), Span{start: loc.item_start, end: loc.end});
+            index.order.push(loc.key);
+        }
+        let order = &index.order;
+        let mut sorted: Vec<usize> = (0..order.len()).collect();
+        sorted.sort_by(|&a, &b| order[a].cmp(&order[b]));
+        index.sorted = sorted;
+        index
+    }
+
+    /// Does the document have this key, either written or implied by keys
+    /// inside it?
+    pub fn contains(&self, key: &KeyPath) -> bool {
+        self.present.contains(key)
+    }
+
+    /// Where a value is written. Tables which are only implied, by dotted
+    /// keys or the headers of their subtables, have no span.
+    pub fn span_of(&self, key: &KeyPath) -> Option<Span> {
+        self.spans.get(key).cloned()
+    }
+
+    /// The written keys inside a key, at any depth, in document order.
+    pub fn keys_under(&self, key: &KeyPath) -> Vec<&KeyPath> {
+        let first = self.sorted.partition_point(|&i| self.order[i] <= *key);
+        let mut found: Vec<usize> = self.sorted[first..].iter().cloned()
+                                        .take_while(|&i| self.order[i].starts_with(key)).collect();
+        found.sort();
+        found.into_iter().map(|i| &self.order[i]).collect()
+    }
+}
+
+#[test]
+fn test_index() {
+    use super::tokenise::tokenise;
+    let tokens = tokenise("a.b = 1\n[t]\nx = [1, {y = 2}]\n[[arr]]\n");
+    let index = Index::build(&tokens);
+    assert!(index.contains(&KeyPath::from_string("a")));
+    assert!(index.contains(&KeyPath::from_string("t.x[1].y")));
+    assert!(!index.contains(&KeyPath::from_string("t.y")));
+    assert_eq!(index.span_of(&KeyPath::from_string("a")), None);
+    assert_eq!(index.span_of(&KeyPath::from_string("a.b")), Some(Span{start: 0, end: 7}));
+    assert_eq!(index.span_of(&KeyPath::from_string("t.x[0]")), Some(Span{start: 17, end: 18}));
+    assert_eq!(index.keys_under(&KeyPath::from_string("t")), vec![
+        &KeyPath::from_string("t.x"), &KeyPath::from_string("t.x[0]"),
+        &KeyPath::from_string("t.x[1]"), &KeyPath::from_string("t.x[1].y"),
+    ]);
+    assert!(index.contains(&KeyPath::from_string("arr[0]")));
+
+    // Subtables written apart still come back in document order
+    let tokens = tokenise("[a.z]\nk = 1\n[b]\n[a]\nm = 2\n[a.c]\n[ab]\n");
+    let index = Index::build(&tokens);
+    assert_eq!(index.keys_under(&KeyPath::from_string("a")), vec![
+        &KeyPath::from_string("a.z"), &KeyPath::from_string("a.z.k"),
+        &KeyPath::from_string("a.m"), &KeyPath::from_string("a.c"),
+    ]);
+    assert!(index.keys_under(&KeyPath::from_string("b")).is_empty());
+}
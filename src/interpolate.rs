@@ -0,0 +1,217 @@
+//! Expand `${VAR}` placeholders in strings as they're read.
+//!
+//! `${VAR}` is replaced by the variable's value, and `${VAR:-default}` by
+//! the value or, if it's unset or empty, the default, which is used as it's
+//! written. `$${` gives a literal `${`. Anything else, like a `$` on its own
+//! or `${` without a valid name and `}`, is left alone.
+//!
+//! Expansion only happens in the `_resolved` getters; the document itself,
+//! and what it's written back as, keeps the placeholders.
+
+use std::collections::HashMap;
+use std::env;
+
+use super::keypath::KeyPath;
+use super::error::Error;
+use super::document::Document;
+
+/// Where placeholders get their values from.
+#[derive(Debug,Clone,Default)]
+pub struct Resolver {
+    /// None to use the process environment
+    vars: Option<HashMap<String, String>>,
+}
+
+/// One placeholder found in a string.
+struct Placeholder<'a> {
+    /// Byte range of the whole `${...}`
+    start: usize,
+    end: usize,
+    name: &'a str,
+    default: Option<&'a str>,
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some('A'..='Z' | 'a'..='z' | '_')) &&
+        chars.all(|c| matches!(c, 'A'..='Z' | 'a'..='z' | '0'..='9' | '_'))
+}
+
+/// The next placeholder at or after pos, or an escaped `$${` (with no name).
+fn next_placeholder(text: &str, mut pos: usize) -> Option<Placeholder<'_>> {
+    while let Some(found) = text[pos..].find("${") {
+        let start = pos + found;
+        if text[..start].ends_with('$') {
+            return Some(Placeholder{start: start - 1, end: start + 2, name: "", default: None});
+        }
+        if let Some(len) = text[start + 2..].find('}') {
+            let inner = &text[start + 2..start + 2 + len];
+            let (name, default) = match inner.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (inner, None),
+            };
+            if is_name(name) {
+                return Some(Placeholder{start, end: start + 3 + len, name, default});
+            }
+        }
+        pos = start + 2;
+    }
+    None
+}
+
+impl Resolver {
+    /// Take values from the process environment.
+    pub fn from_env() -> Resolver {
+        Resolver{vars: None}
+    }
+
+    /// Take values from a map, ignoring the environment.
+    pub fn from_map(vars: HashMap<String, String>) -> Resolver {
+        Resolver{vars: Some(vars)}
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        match self.vars {
+            Some(ref vars) => vars.get(name).cloned(),
+            None => env::var(name).ok(),
+        }
+    }
+
+    /// Expand the placeholders in some text, or give the name of the first
+    /// variable which isn't set.
+    pub fn expand(&self, text: &str) -> Result<String, String> {
+        let mut res = String::new();
+        let mut pos = 0;
+        while let Some(p) = next_placeholder(text, pos) {
+            res.push_str(&text[pos..p.start]);
+            if p.name.is_empty() {
+                res.push_str("${");
+            } else {
+                match (self.lookup(p.name), p.default) {
+                    (Some(value), Some(default)) if value.is_empty() => res.push_str(default),
+                    (Some(value), _) => res.push_str(&value),
+                    (None, Some(default)) => res.push_str(default),
+                    (None, None) => return Err(String::from(p.name)),
+                }
+            }
+            pos = p.end;
+        }
+        res.push_str(&text[pos..]);
+        Ok(res)
+    }
+
+    /// The variables named in some text which aren't set and have no
+    /// default.
+    pub fn unresolved(&self, text: &str) -> Vec<String> {
+        let mut res = Vec::new();
+        let mut pos = 0;
+        while let Some(p) = next_placeholder(text, pos) {
+            if !p.name.is_empty() && p.default.is_none() && self.lookup(p.name).is_none() {
+                res.push(String::from(p.name));
+            }
+            pos = p.end;
+        }
+        res
+    }
+}
+
+impl Document {
+    /// The string at a key path with its placeholders expanded. Gives
+    /// `Ok(None)` if there's no string there.
+    pub fn get_str_resolved(&self, key: &KeyPath, resolver: &Resolver) -> Result<Option<String>, Error> {
+        match self.get_str(key) {
+            Some(s) => resolver.expand(s).map(Some).map_err(|var| Error::Unresolved(key.clone(), var)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a value which may be written as a string with placeholders, like
+    /// `port = "${PORT:-8080}"`, parsing the expanded string as a `T`.
+    fn get_parsed_resolved<T>(&self, key: &KeyPath, resolver: &Resolver, direct: Option<T>,
+                              expected: &'static str) -> Result<Option<T>, Error>
+        where T: ::std::str::FromStr {
+        if direct.is_some() {
+            return Ok(direct);
+        }
+        match self.get_str_resolved(key, resolver)? {
+            Some(s) => s.trim().parse().map(Some).map_err(|_| Error::WrongType(key.clone(), expected)),
+            None => Ok(None),
+        }
+    }
+
+    /// The integer at a key path, or a string there which expands to one.
+    pub fn get_integer_resolved(&self, key: &KeyPath, resolver: &Resolver) -> Result<Option<i64>, Error> {
+        self.get_parsed_resolved(key, resolver, self.get_integer(key), "integer")
+    }
+
+    /// The float at a key path, or a string there which expands to one.
+    pub fn get_float_resolved(&self, key: &KeyPath, resolver: &Resolver) -> Result<Option<f64>, Error> {
+        self.get_parsed_resolved(key, resolver, self.get_float(key), "float")
+    }
+
+    /// The boolean at a key path, or a string there which expands to `true`
+    /// or `false`.
+    pub fn get_bool_resolved(&self, key: &KeyPath, resolver: &Resolver) -> Result<Option<bool>, Error> {
+        self.get_parsed_resolved(key, resolver, self.get_bool(key), "boolean")
+    }
+
+    /// Every string in the document with placeholders that can't be
+    /// expanded, and the variables they need, in document order.
+    pub fn unresolved_placeholders(&self, resolver: &Resolver) -> Vec<(KeyPath, Vec<String>)> {
+        self.entries().filter_map(|e| {
+            let missing = resolver.unresolved(e.value.as_str()?);
+            if missing.is_empty() { None } else { Some((e.key, missing)) }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+fn test_resolver() -> Resolver {
+    let mut vars = HashMap::new();
+    vars.insert(String::from("HOST"), String::from("db.local"));
+    vars.insert(String::from("PORT"), String::from("5432"));
+    vars.insert(String::from("EMPTY"), String::new());
+    Resolver::from_map(vars)
+}
+
+#[test]
+fn test_expand() {
+    let r = test_resolver();
+    assert_eq!(r.expand("${HOST}:${PORT}"), Ok(String::from("db.local:5432")));
+    assert_eq!(r.expand("${USER:-admin} ${EMPTY:-x} ${HOST:-x}"), Ok(String::from("admin x db.local")));
+    assert_eq!(r.expand("$${HOST} $HOST ${not a name} ${HOST"), Ok(String::from("${HOST} $HOST ${not a name} ${HOST")));
+    assert_eq!(r.expand("a ${MISSING} b"), Err(String::from("MISSING")));
+    assert_eq!(r.unresolved("${A} ${HOST} ${B:-} $${C}"), vec![String::from("A")]);
+}
+
+#[test]
+fn test_get_resolved() {
+    let text = "url = \"postgres://${HOST}:${PORT}\"\nport = \"${PORT}\"\nworkers = \"${WORKERS:-4}\"\n\
+                debug = \"${DEBUG:-false}\"\nratio = 0.5\nbad = \"${HOST}\"\n\
+                [[servers]]\nname = \"${NAME}\"\ntags = [\"${TAG}\", \"x\"]\n";
+    let doc = Document::parse(text).unwrap();
+    let r = test_resolver();
+    let key = KeyPath::from_string;
+    assert_eq!(doc.get_str_resolved(&key("url"), &r), Ok(Some(String::from("postgres://db.local:5432"))));
+    assert_eq!(doc.get_integer_resolved(&key("port"), &r), Ok(Some(5432)));
+    assert_eq!(doc.get_integer_resolved(&key("workers"), &r), Ok(Some(4)));
+    assert_eq!(doc.get_bool_resolved(&key("debug"), &r), Ok(Some(false)));
+    assert_eq!(doc.get_float_resolved(&key("ratio"), &r), Ok(Some(0.5)));
+    assert_eq!(doc.get_integer_resolved(&key("missing"), &r), Ok(None));
+    assert_eq!(doc.get_integer_resolved(&key("bad"), &r), Err(Error::WrongType(key("bad"), "integer")));
+    assert_eq!(doc.get_str_resolved(&key("servers[0].name"), &r),
+               Err(Error::Unresolved(key("servers[0].name"), String::from("NAME"))));
+    assert_eq!(doc.unresolved_placeholders(&r), vec![
+        (key("servers[0].name"), vec![String::from("NAME")]),
+        (key("servers[0].tags[0]"), vec![String::from("TAG")]),
+    ]);
+    // The document itself is unchanged
+    assert_eq!(doc.to_string(), text);
+    let doc = Document::parse("when = [1979-05-27 07:32:00Z]\nwho = \"${WHO}\"\n").unwrap();
+    assert_eq!(doc.unresolved_placeholders(&r), vec![(key("who"), vec![String::from("WHO")])]);
+
+    // Cargo sets this when it runs the tests
+    let doc = Document::parse("a = \"${CARGO_PKG_NAME}\"\n").unwrap();
+    assert_eq!(doc.get_str_resolved(&key("a"), &Resolver::from_env()),
+               Ok(Some(String::from(env!("CARGO_PKG_NAME")))));
+}
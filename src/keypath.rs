@@ -1,13 +1,19 @@
+extern crate toml;
+
 use super::tokenise;
 use super::tokenise::{Token, TokenType};
+use super::error::Error;
+use super::decode::decode_string;
 
-#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
 pub enum KeyPathComponent {
     Key(String),
     Ix(usize),
 }
 
-#[derive(Debug,PartialEq,Clone,Eq,Hash)]
+/// Key paths sort part by part, so every key inside a key sorts directly
+/// after it.
+#[derive(Debug,PartialEq,Clone,Eq,PartialOrd,Ord,Hash)]
 pub struct KeyPath {
     pub parts: Vec<KeyPathComponent>
 }
@@ -17,6 +23,12 @@ pub struct KeyPath {
 //     Ix(Box<KeyPath>, usize),
 // }
 
+impl Default for KeyPath {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl KeyPath {
     pub fn new() -> KeyPath {
         KeyPath{parts: Vec::new()}
@@ -37,10 +49,12 @@ impl KeyPath {
     }
 
     pub fn parent(&self) -> Option<KeyPath> {
-        match self.parts.split_last() {
-            None => None,
-            Some((_, head)) => Some(KeyPath{parts: Vec::from(head)})
-        }
+        self.parts.split_last().map(|(_, head)| KeyPath{parts: Vec::from(head)})
+    }
+
+    /// Is this path equal to prefix, or inside it?
+    pub fn starts_with(&self, prefix: &KeyPath) -> bool {
+        self.parts.starts_with(&prefix.parts)
     }
 
     pub fn stringify(&self) -> String {
@@ -54,50 +68,90 @@ impl KeyPath {
         //     }
         // }
         let mut res = String::new();
-        for ref p in &self.parts {
-            match *p {
-                &KeyPathComponent::Key(ref s) => res.push_str(&format!(".{}", s)),
-                &KeyPathComponent::Ix(ref i) => res.push_str(&format!("[{}]", i))
+        for p in &self.parts {
+            match p {
+                KeyPathComponent::Key(s) => res.push_str(&format!(".{}", s)),
+                KeyPathComponent::Ix(i) => res.push_str(&format!("[{}]", i))
             }
         }
         res
     }
 
+    /// The path for showing to people, like `a.b[1]`: `stringify` without the
+    /// leading dot.
+    pub fn display_string(&self) -> String {
+        self.stringify().trim_start_matches('.').to_string()
+    }
+
     pub fn from_string(s: &str) -> KeyPath {
+        match KeyPath::parse(s) {
+            Ok(path) => path,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// Parse a path like `foo."bar baz"[2]`, as used in table headers.
+    /// Whitespace is allowed around `.` and `[n]`, but can't separate keys.
+    pub fn parse(s: &str) -> Result<KeyPath, Error> {
+        let invalid = || Error::InvalidKeyPath(String::from(s));
         let mut path = KeyPath::new();
         let mut remainder = s;
+        // Whether the next key has to come after a dot
+        let mut need_dot = false;
         loop {
             let next_char = remainder.chars().next();
             let (next_token, rem) = match next_char {
                 None => break,
                 Some(c) => {match c {
-                    '.' => {
-                        tokenise::read_punctuation(remainder)
+                    '.'|' '|'\t' => {
+                        (Token::from(&remainder[..1]), &remainder[1..])
                     },
                     '[' => {
-                        let (t, r) = tokenise::read_number_or_datetime(&remainder[1..]);
-                        (t, &r[1..])
+                        let close = remainder.find(']').ok_or_else(invalid)?;
+                        let (t, r) = tokenise::read_number_or_datetime(remainder[1..close].trim());
+                        if t.kind != TokenType::Integer || !r.is_empty() {
+                            return Err(invalid());
+                        }
+                        (t, &remainder[close+1..])
                     },
-                    'A'...'Z'|'a'...'z'|'0'...'9'|'_'|'-' => {
+                    'A'..='Z'|'a'..='z'|'0'..='9'|'_'|'-' => {
                         tokenise::read_bare_key(remainder)
                     },
-                    _ => panic!("Unexpected char")
+                    '"' => tokenise::read_basic_string(remainder),
+                    '\'' => tokenise::read_literal_string(remainder),
+                    _ => return Err(invalid())
                 }}
             };
+            let is_key = matches!(next_token.kind, TokenType::BareKey | TokenType::BasicString
+                                                   | TokenType::LiteralString);
+            if is_key && need_dot {
+                return Err(invalid());
+            }
             match next_token {
                 Token{kind: TokenType::BareKey, text: s} => {
                     path.parts.push(KeyPathComponent::Key(s));
-                    // path = path.append_key(s);
+                }
+                ref tok @ Token{kind: TokenType::BasicString, ..} | ref tok @ Token{kind: TokenType::LiteralString, ..} => {
+                    match decode_string(tok) {
+                        Some(key) => path.parts.push(KeyPathComponent::Key(key)),
+                        None => return Err(invalid())
+                    }
                 }
                 Token{kind: TokenType::Integer, text: s} => {
-                    path.parts.push(KeyPathComponent::Ix(s.parse::<usize>().unwrap()));
-                    // path = path.append_index(s.parse::<usize>().unwrap());
+                    let ix = s.parse::<usize>().map_err(|_| invalid())?;
+                    path.parts.push(KeyPathComponent::Ix(ix));
+                    need_dot = true;
                 }
-                _ => (),
+                Token{kind: TokenType::Punctuation, ..} => need_dot = false,
+                Token{kind: TokenType::Whitespace, ..} => (),
+                _ => return Err(invalid()),
             };
+            if is_key {
+                need_dot = true;
+            }
             remainder = rem;
         }
-        path
+        Ok(path)
     }
 }
 
@@ -108,6 +162,15 @@ fn test_stringify_keypath() {
     assert_eq!(kp.stringify(), String::from(".foo[2]"))
 }
 
+#[test]
+fn test_keypath_starts_with() {
+    let kp = KeyPath::from_string("a.b[1]");
+    assert!(kp.starts_with(&KeyPath::from_string("a.b")));
+    assert!(kp.starts_with(&kp));
+    assert!(kp.starts_with(&KeyPath::new()));
+    assert!(!kp.starts_with(&KeyPath::from_string("a.bc")));
+}
+
 #[test]
 fn test_keypath_from_string() {
     let s = "1";
@@ -117,3 +180,20 @@ fn test_keypath_from_string() {
                                 .append_index(2);
     assert_eq!(KeyPath::from_string("foo.bar[2]"), expected);
 }
+
+#[test]
+fn test_keypath_parse() {
+    let expected = KeyPath::new().append_key(String::from("a b"))
+                                .append_key(String::from("c"))
+                                .append_index(0)
+                                .append_key(String::from("d.e"));
+    assert_eq!(KeyPath::parse("\"a b\". c[0].'d.e'"), Ok(expected));
+    assert!(KeyPath::parse("a[x]").is_err());
+    assert!(KeyPath::parse("a/b").is_err());
+    assert!(KeyPath::parse("\"unclosed").is_err());
+    // Whitespace can go around separators, but isn't one
+    assert_eq!(KeyPath::parse("a . b [1] .c"), Ok(KeyPath::from_string("a.b[1].c")));
+    assert_eq!(KeyPath::parse("a b"), Err(Error::InvalidKeyPath(String::from("a b"))));
+    assert!(KeyPath::parse("a.b c").is_err());
+    assert!(KeyPath::parse("a[0] b").is_err());
+}
@@ -1,137 +1,62 @@
 extern crate toml;
+extern crate rustc_serialize;
+extern crate yaml_rust;
 
 mod tokenise;
 mod keypath;
 mod tableix;
-
-use std::str::FromStr;
-pub use tokenise::{Token, TokenType, tokenise};
+mod style;
+mod error;
+mod reshape;
+mod locate;
+mod index;
+mod entries;
+mod convert;
+mod decode;
+mod document;
+mod batch;
+mod diff;
+mod highlight;
+mod interpolate;
+mod compare;
+mod merge;
+mod overlay;
+mod patch;
+mod schema;
+pub mod fmt;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+
+pub use tokenise::{Token, TokenType, Diagnostic, tokenise, try_tokenise, tokenise_recovering, detect_newline};
 pub use keypath::KeyPath;
-
-enum ValueFormat {
-    Table,
-    InlineTable,
-    Array,
-}
-
-fn select_significant(tokens: &Vec<Token>, start: usize, n: usize) -> Vec<(&Token, usize)> {
-    let mut res = Vec::new();
-    let mut pos = start;
-    while (pos < tokens.len()) && (res.len() < n) {
-        let ref token = tokens[pos];
-        match token.kind {
-            TokenType::Whitespace | TokenType::Newline | TokenType::Comment => (),
-            _ => {
-                res.push((token, pos))
-            }
-        }
-        pos += 1;
-    }
-    res
-}
+pub use tableix::{insert_kv, insert_kv_with, insert_table, sort_table, InsertOptions, InsertPolicy};
+pub use style::{Style, StyleOverride, infer_style, format_value};
+pub use error::Error;
+pub use document::{Document, parse_value};
+pub use index::{Index, Span};
+pub use entries::{Entry, Entries};
+pub use convert::{JsonFormat, to_json, from_value, from_json, from_yaml};
+pub use batch::{Edit, EditBatch};
+pub use diff::{Hunk, DiffLine, line_diff, unified_diff};
+pub use highlight::{Highlight, highlight, to_ansi, to_html, ansi_diff};
+pub use interpolate::Resolver;
+pub use compare::{Change, CompareOptions, compare, compare_values};
+pub use merge::{Conflict, Merge, merge};
+pub use overlay::{OverlayOptions, ExistingPolicy, ArrayPolicy};
+pub use patch::{JsonPatch, PatchOp, pointer_to_keypath, apply_merge_patch};
+pub use schema::{Schema, SchemaType, Additional, Violation, ViolationKind};
+pub use reshape::{table_to_inline, inline_to_table, tables_to_array, array_to_tables,
+                  table_to_dotted, dotted_to_table};
 
 fn key_token_to_string(tok: &Token) -> String {
     match tok.kind {
         TokenType::BareKey => tok.text.clone(),
         TokenType::BasicString | TokenType::LiteralString => {
-            let val = toml::Value::from_str(&tok.text).unwrap();
-            match val {
-                toml::Value::String(s) => s,
-                _ => panic!("Unexpected key value {:?}", val)
+            match decode::decode_string(tok) {
+                Some(s) => s,
+                None => panic!("Invalid key {:?}", tok)
             }
         },
         _ => panic!("Unexpected key token {:?}", tok)
     }
 }
-
-fn is_atomic_tok(tok: &Token) -> bool {
-    match tok.kind {
-        TokenType::Punctuation => false,
-        _ => true
-    }
-}
-
-struct KeyTokenIter<'a> {
-    tokens: &'a Vec<Token>,
-    stack: Vec<(KeyPath, ValueFormat, usize)>,
-    pos: usize,
-}
-
-impl<'a> KeyTokenIter<'a> {
-    fn new(tokens: &Vec<Token>) -> KeyTokenIter {
-        KeyTokenIter{tokens: tokens, stack: Vec::new(), pos:0}
-    }
-
-    fn current_keypath(&self) -> KeyPath {
-        match self.stack.last() {
-            Some(trip) => trip.0.clone(),
-            None => KeyPath::new(),
-        }
-    }
-}
-
-impl<'a> Iterator for KeyTokenIter<'a> {
-    type Item = (KeyPath, usize, usize);
-
-    fn next(&mut self) -> Option<(KeyPath, usize, usize)> {
-        let next_3 = select_significant(self.tokens, self.pos, 3);
-        if next_3.len() < 3 {
-            return None;
-        }
-        if next_3[1].0 == &Token::from("=") {
-            // Key value pair
-            let key = key_token_to_string(next_3[0].0);
-            if is_atomic_tok(next_3[2].0) {
-                let (_, pos) = next_3[2];
-                self.pos = pos + 1;
-                return Some((self.current_keypath().append_key(key), pos, pos+1))
-            } else if next_3[2].0 == &Token::from("[") {
-                // Start an array
-                let pos = next_3[2].1;
-                let keypath = self.current_keypath().append_key(key);
-                self.stack.push((keypath, ValueFormat::Array, pos));
-                self.pos = pos + 1;
-                return self.next();
-            } else if next_3[2].0.text == String::from("{") {
-                let pos = next_3[2].1;
-                let keypath = self.current_keypath().append_key(key);
-                self.stack.push((keypath, ValueFormat::InlineTable, pos));
-                self.pos = pos + 1;
-                return self.next();
-            } else {
-                panic!("Unexpected token after = : {:?}", next_3[2]);
-            }
-        } else if next_3[0].0 == &Token::from("[") {
-            // New table
-            let prev_table = self.stack.pop();
-            if next_3[1].0 == &Token::from("[") {
-                // [[foo]] : table in array
-                // TODO
-            }
-            // TODO: there may be >1 token - [a.b.c]
-            let key = key_token_to_string(next_3[1].0);
-            self.pos = next_3[2].1+1;
-            let new_keypath = self.current_keypath().append_key(key);
-            self.stack.push((new_keypath, ValueFormat::Table, self.pos));
-            return match prev_table {
-                Some((key, _, start)) => Some((key, start, next_3[0].1 - 1)),
-                None => self.next()
-            }
-        } else {
-            return None
-        }
-    }
-}
-
-#[test]
-fn test_keytokeniter() {
-    let inp = vec![
-        Token::from("a"), Token::from("="), Token::from("1"),
-        Token::from("\n"),
-        Token::from("b"), Token::from("="), Token::from("2"),
-    ];
-    let mut kti = KeyTokenIter::new(&inp);
-    assert_eq!(kti.next(), Some((KeyPath::from_string("a"), 2, 3)));
-    assert_eq!(kti.next(), Some((KeyPath::from_string("b"), 6, 7)));
-    assert_eq!(kti.next(), None);
-}
@@ -0,0 +1,153 @@
+//! Find where each value in a document is written.
+
+use super::tokenise::{Token, TokenType};
+use super::keypath::KeyPath;
+use super::tableix::{find_tables, find_entries, entry_key_parts, inline_members, skip_value};
+use super::key_token_to_string;
+
+/// The ways a value can be written.
+#[derive(Debug,PartialEq,Clone,Copy)]
+pub enum Place {
+    /// A `[table]` or `[[array]]` section
+    Section,
+    /// A `key = value` line in a section
+    Entry,
+    /// A `key = value` pair in an inline table
+    Member,
+    /// An element of an array written with `[...]`
+    Element,
+}
+
+/// Token positions of one value in a document.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Location {
+    pub key: KeyPath,
+    pub place: Place,
+    /// First token of the whole item: the header of a section, the key of an
+    /// entry or member, or the value itself for array elements
+    pub item_start: usize,
+    /// First token of the value; for sections, the token after the header
+    pub start: usize,
+    /// One past the last token of the value
+    pub end: usize,
+}
+
+fn is_insignificant(tok: &Token) -> bool {
+    matches!(tok.kind, TokenType::Whitespace | TokenType::Newline | TokenType::Comment)
+}
+
+/// Find every value written in the document, in document order. Sections,
+/// arrays and inline tables come before the values inside them.
+///
+/// Tables which are only implied, by dotted keys or by the headers of their
+/// subtables, have no location of their own.
+pub fn locate_all(tokens: &[Token]) -> Vec<Location> {
+    let mut res = Vec::new();
+    for table in find_tables(tokens) {
+        if !table.key.parts.is_empty() {
+            let header = header_start(tokens, table.start);
+            res.push(Location{key: table.key.clone(), place: Place::Section,
+                              item_start: header, start: table.start, end: table.end});
+        }
+        for entry in find_entries(tokens, &table) {
+            let mut key = table.key.clone();
+            for part in entry_key_parts(tokens, &entry) {
+                key = key.append_key(part);
+            }
+            res.push(Location{key: key.clone(), place: Place::Entry, item_start: entry.key_start,
+                              start: entry.value_start, end: entry.value_end});
+            locate_inside(tokens, &key, entry.value_start, &mut res);
+        }
+    }
+    res
+}
+
+/// Find where the value for a key is written.
+pub fn locate(tokens: &[Token], key: &KeyPath) -> Option<Location> {
+    locate_all(tokens).into_iter().find(|loc| loc.key == *key)
+}
+
+/// The `[` starting the header of a section whose body starts at pos.
+pub fn header_start(tokens: &[Token], pos: usize) -> usize {
+    let mut depth = 0;
+    let mut i = pos;
+    while i > 0 {
+        i -= 1;
+        if tokens[i] == Token::from("]") {
+            depth += 1;
+        } else if tokens[i] == Token::from("[") {
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+    0
+}
+
+/// Add the members of an inline table or elements of an array at pos.
+fn locate_inside(tokens: &[Token], key: &KeyPath, pos: usize, res: &mut Vec<Location>) {
+    if tokens[pos] == Token::from("{") {
+        for (key_start, key_end, value_start, value_end) in inline_members(tokens, pos) {
+            let mut member_key = key.clone();
+            for tok in &tokens[key_start..key_end] {
+                if !is_insignificant(tok) && tok.text != "." {
+                    member_key = member_key.append_key(key_token_to_string(tok));
+                }
+            }
+            res.push(Location{key: member_key.clone(), place: Place::Member, item_start: key_start,
+                              start: value_start, end: value_end});
+            locate_inside(tokens, &member_key, value_start, res);
+        }
+    } else if tokens[pos] == Token::from("[") {
+        let close = skip_value(tokens, pos) - 1;
+        let mut i = pos + 1;
+        let mut index = 0;
+        while i < close {
+            let tok = &tokens[i];
+            if is_insignificant(tok) || *tok == Token::from(",") {
+                i += 1;
+                continue;
+            }
+            let end = skip_value(tokens, i);
+            let element_key = key.clone().append_index(index);
+            res.push(Location{key: element_key.clone(), place: Place::Element, item_start: i,
+                              start: i, end});
+            locate_inside(tokens, &element_key, i, res);
+            index += 1;
+            i = end;
+        }
+    }
+}
+
+#[cfg(test)]
+use super::tokenise::tokenise;
+
+#[test]
+fn test_locate_all() {
+    let tokens = tokenise("a.b = [1, {c = 2}]\n[[t]]\nx = 1\n[[t]]\n");
+    let found: Vec<(String, Place)> = locate_all(&tokens).into_iter()
+                                        .map(|loc| (loc.key.stringify(), loc.place)).collect();
+    assert_eq!(found, vec![
+        (String::from(".a.b"), Place::Entry),
+        (String::from(".a.b[0]"), Place::Element),
+        (String::from(".a.b[1]"), Place::Element),
+        (String::from(".a.b[1].c"), Place::Member),
+        (String::from(".t[0]"), Place::Section),
+        (String::from(".t[0].x"), Place::Entry),
+        (String::from(".t[1]"), Place::Section),
+    ]);
+    let loc = locate(&tokens, &KeyPath::from_string("a.b[1].c")).unwrap();
+    assert_eq!(tokens[loc.item_start].text, "c");
+    assert_eq!(tokens[loc.start].text, "2");
+    let loc = locate(&tokens, &KeyPath::from_string("t[1]")).unwrap();
+    assert_eq!(tokens[loc.item_start].text, "[");
+    assert_eq!(tokens[loc.item_start + 1].text, "[");
+
+    let tokens = tokenise("a = [1979-05-27 07:32:00Z, 1979-05-27]\n");
+    let found: Vec<String> = locate_all(&tokens).into_iter().map(|loc| loc.key.stringify()).collect();
+    assert_eq!(found, vec![".a", ".a[0]", ".a[1]"]);
+    let loc = locate(&tokens, &KeyPath::from_string("a[0]")).unwrap();
+    assert_eq!(tokens[loc.start].text, "1979-05-27 07:32:00Z");
+    assert_eq!(loc.end, loc.start + 1);
+}
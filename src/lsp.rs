@@ -0,0 +1,509 @@
+//! A Language Server Protocol server for TOML files, talking JSON-RPC over
+//! a pair of streams.
+//!
+//! It gives diagnostics as documents change, hover with the key path of the
+//! key under the cursor, document symbols for tables and their keys,
+//! go-to-definition from a key to the header or line where it's defined (so
+//! `x` in `[x.y]` goes to the right `[[x]]`), and whole-document formatting.
+//! Documents are synced in full on every change.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+
+use rustc_serialize::json::Json;
+
+use super::tokenise::{Token, TokenType, tokenise_recovering};
+use super::keypath::{KeyPath, KeyPathComponent};
+use super::document::Document;
+use super::decode::decode;
+use super::error::Error;
+use super::locate::{Place, locate_all, header_start};
+use super::tableix::{find_tables, find_entries, entry_key_parts};
+use super::fmt::{FormatOptions, format};
+
+// LSP constants
+const SYNC_FULL: u64 = 1;
+const SEVERITY_ERROR: u64 = 1;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_REQUEST: i64 = -32600;
+const PARSE_ERROR: i64 = -32700;
+
+fn obj(pairs: Vec<(&str, Json)>) -> Json {
+    let mut res = BTreeMap::new();
+    for (k, v) in pairs {
+        res.insert(String::from(k), v);
+    }
+    Json::Object(res)
+}
+
+fn string(s: &str) -> Json {
+    Json::String(String::from(s))
+}
+
+fn response(id: Json, result: Json) -> Json {
+    obj(vec![("jsonrpc", string("2.0")), ("id", id), ("result", result)])
+}
+
+fn error_response(id: Json, code: i64, message: &str) -> Json {
+    obj(vec![("jsonrpc", string("2.0")), ("id", id),
+             ("error", obj(vec![("code", Json::I64(code)), ("message", string(message))]))])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    obj(vec![("jsonrpc", string("2.0")), ("method", string(method)), ("params", params)])
+}
+
+/// A line and UTF-16 column, counting from 0, as LSP positions are.
+type Position = (usize, usize);
+
+fn position_json(pos: Position) -> Json {
+    obj(vec![("line", Json::U64(pos.0 as u64)), ("character", Json::U64(pos.1 as u64))])
+}
+
+fn range_json(start: Position, end: Position) -> Json {
+    obj(vec![("start", position_json(start)), ("end", position_json(end))])
+}
+
+/// Where each token starts, plus where the last one ends. A byte order mark,
+/// which isn't in the tokens, takes up the first column.
+fn token_positions(tokens: &[Token], bom: bool) -> Vec<Position> {
+    let (mut line, mut col) = (0, if bom { 1 } else { 0 });
+    let mut res = vec![(line, col)];
+    for tok in tokens {
+        for c in tok.text.chars() {
+            if c == '\n' {
+                line += 1;
+                col = 0;
+            } else {
+                col += c.len_utf16();
+            }
+        }
+        res.push((line, col));
+    }
+    res
+}
+
+/// The token at a position. At the boundary between a word and the space
+/// after it, the word wins, since that's where the cursor sits after typing.
+fn token_at(positions: &[Position], pos: Position) -> Option<usize> {
+    (0..positions.len() - 1).find(|&i| positions[i] <= pos && pos < positions[i + 1])
+}
+
+fn prefer_word(tokens: &[Token], positions: &[Position], pos: Position) -> Option<usize> {
+    match token_at(positions, pos) {
+        Some(i) if i > 0 && positions[i] == pos && is_insignificant(&tokens[i]) => Some(i - 1),
+        None if positions.len() > 1 && positions[positions.len() - 1] == pos => Some(positions.len() - 2),
+        found => found,
+    }
+}
+
+fn is_insignificant(tok: &Token) -> bool {
+    matches!(tok.kind, TokenType::Whitespace | TokenType::Newline | TokenType::Comment)
+}
+
+fn is_key_token(tok: &Token) -> bool {
+    matches!(tok.kind, TokenType::BareKey | TokenType::BasicString | TokenType::LiteralString)
+}
+
+/// Step back over insignificant tokens before end.
+fn trim_end(tokens: &[Token], start: usize, mut end: usize) -> usize {
+    while end > start && is_insignificant(&tokens[end - 1]) {
+        end -= 1;
+    }
+    end
+}
+
+/// The key path named by the key token at pos, up to and including that key:
+/// `b` in `[a.b.c]` gives `a.b`. Arrays of tables get their index, so `x` in
+/// `[x.y]` gives the element of `x` the table belongs to.
+fn key_at(tokens: &[Token], pos: usize) -> Option<KeyPath> {
+    if !is_key_token(&tokens[pos]) {
+        return None;
+    }
+    let locations = locate_all(tokens);
+    let loc = locations.iter().rev().find(|loc| {
+        loc.place != Place::Element && loc.item_start <= pos && pos < loc.start
+    })?;
+    let key_tokens: Vec<usize> = (loc.item_start..loc.start).filter(|&p| is_key_token(&tokens[p])).collect();
+    let nth = key_tokens.iter().position(|&p| p == pos)?;
+    let key_parts: Vec<usize> = loc.key.parts.iter().enumerate()
+        .filter(|&(_, p)| matches!(p, KeyPathComponent::Key(_)))
+        .map(|(i, _)| i)
+        .collect();
+    let mut end = key_parts[key_parts.len() - key_tokens.len() + nth] + 1;
+    while let Some(KeyPathComponent::Ix(_)) = loc.key.parts.get(end) {
+        end += 1;
+    }
+    Some(KeyPath{parts: loc.key.parts[..end].to_vec()})
+}
+
+/// The key path of the key under pos, or of the innermost value around it.
+fn path_at(tokens: &[Token], pos: usize) -> Option<KeyPath> {
+    key_at(tokens, pos).or_else(|| {
+        locate_all(tokens).into_iter().rev()
+            .find(|loc| loc.start <= pos && pos < loc.end)
+            .map(|loc| loc.key)
+    })
+}
+
+/// A document which parses, with the positions of its tokens.
+struct Analysis {
+    doc: Document,
+    positions: Vec<Position>,
+}
+
+impl Analysis {
+    fn new(text: &str) -> Option<Analysis> {
+        let doc = Document::parse(text).ok()?;
+        let positions = token_positions(doc.tokens(), text.starts_with('\u{feff}'));
+        Some(Analysis{doc, positions})
+    }
+
+    fn range(&self, start: usize, end: usize) -> Json {
+        range_json(self.positions[start], self.positions[end])
+    }
+
+    fn token_at(&self, pos: Position) -> Option<usize> {
+        prefer_word(self.doc.tokens(), &self.positions, pos)
+    }
+
+    fn hover(&self, pos: Position) -> Json {
+        let found = self.token_at(pos).and_then(|i| Some((i, path_at(self.doc.tokens(), i)?)));
+        match found {
+            Some((i, key)) => obj(vec![
+                ("contents", obj(vec![("kind", string("plaintext")), ("value", Json::String(key.display_string()))])),
+                ("range", self.range(i, i + 1)),
+            ]),
+            None => Json::Null,
+        }
+    }
+
+    fn definition(&self, uri: &str, pos: Position) -> Json {
+        let tokens = self.doc.tokens();
+        let key = match self.token_at(pos).and_then(|i| key_at(tokens, i)) {
+            Some(key) => key,
+            None => return Json::Null,
+        };
+        match self.doc.index().span_of(&key) {
+            Some(span) => {
+                // Just the first line: the header of a section, or a key's line
+                let end = (span.start..span.end).find(|&p| tokens[p].kind == TokenType::Newline).unwrap_or(span.end);
+                obj(vec![("uri", string(uri)), ("range", self.range(span.start, trim_end(tokens, span.start, end)))])
+            },
+            None => Json::Null,
+        }
+    }
+
+    fn symbols(&self) -> Json {
+        let tokens = self.doc.tokens();
+        let mut res = Vec::new();
+        for table in find_tables(tokens) {
+            let mut children = Vec::new();
+            for entry in find_entries(tokens, &table) {
+                let name = entry_key_parts(tokens, &entry).join(".");
+                let kind = match tokens[entry.value_start].kind {
+                    TokenType::BasicString | TokenType::LiteralString
+                    | TokenType::MultilineBasicString | TokenType::MultilineLiteralString => 15,
+                    TokenType::Integer | TokenType::Float => 16,
+                    TokenType::Boolean => 17,
+                    TokenType::Datetime => 14,
+                    _ if tokens[entry.value_start] == Token::from("[") => 18,
+                    _ => 19,
+                };
+                children.push(obj(vec![
+                    ("name", Json::String(name)),
+                    ("kind", Json::U64(kind)),
+                    ("range", self.range(entry.key_start, entry.value_end)),
+                    ("selectionRange", self.range(entry.key_start, trim_end(tokens, entry.key_start, entry.eq))),
+                ]));
+            }
+            if table.key.parts.is_empty() {
+                res.extend(children);
+                continue;
+            }
+            let header = header_start(tokens, table.start);
+            let array = tokens[header + 1] == Token::from("[");
+            let header_end = trim_end(tokens, header, table.start);
+            res.push(obj(vec![
+                ("name", Json::String(table.key.display_string())),
+                ("detail", string(if array { "array of tables" } else { "table" })),
+                ("kind", Json::U64(if array { 18 } else { 19 })),
+                ("range", self.range(header, trim_end(tokens, header, table.end))),
+                ("selectionRange", self.range(header, header_end)),
+                ("children", Json::Array(children)),
+            ]));
+        }
+        Json::Array(res)
+    }
+
+    fn formatting(&self, options: &Json) -> Json {
+        let mut fmt_options = FormatOptions::default();
+        if let Some(size) = options.find("tabSize").and_then(|s| s.as_u64()) {
+            fmt_options.array_indent = match options.find("insertSpaces") {
+                Some(&Json::Boolean(false)) => String::from("\t"),
+                _ => " ".repeat(size as usize),
+            };
+        }
+        let tokens = self.doc.tokens();
+        let formatted = format(tokens, &fmt_options);
+        if formatted.as_slice() == tokens {
+            return Json::Array(Vec::new());
+        }
+        let text: String = formatted.iter().map(|t| t.text.as_str()).collect();
+        Json::Array(vec![obj(vec![
+            ("range", self.range(0, tokens.len())),
+            ("newText", Json::String(text)),
+        ])])
+    }
+}
+
+fn diagnostic_json(range: Json, message: &str) -> Json {
+    obj(vec![("range", range), ("severity", Json::U64(SEVERITY_ERROR)),
+             ("source", string("tomledit")), ("message", string(message))])
+}
+
+/// Problems in a document: everything the tokeniser couldn't read or, if it
+/// read everything, the first error decoding it.
+fn diagnostics(text: &str) -> Vec<Json> {
+    let bom = text.starts_with('\u{feff}');
+    let (tokens, problems) = tokenise_recovering(text);
+    let positions = token_positions(&tokens, bom);
+    if !problems.is_empty() {
+        return problems.iter().map(|d| {
+            diagnostic_json(range_json(positions[d.token], positions[d.token + 1]), &d.message)
+        }).collect();
+    }
+    match decode(&tokens) {
+        Err(Error::Parse(line, col, msg)) => {
+            let line_text = text.trim_start_matches('\u{feff}').lines().nth(line - 1).unwrap_or("");
+            let mut utf16_col: usize = line_text.chars().take(col - 1).map(char::len_utf16).sum();
+            if bom && line == 1 {
+                utf16_col += 1;
+            }
+            let start = (line - 1, utf16_col);
+            let range = match token_at(&positions, start) {
+                Some(i) => range_json(start, positions[i + 1]),
+                None => range_json(start, start),
+            };
+            vec![diagnostic_json(range, &msg)]
+        },
+        Err(e) => vec![diagnostic_json(range_json((0, 0), (0, 0)), &e.to_string())],
+        Ok(_) => Vec::new(),
+    }
+}
+
+fn capabilities() -> Json {
+    obj(vec![
+        ("capabilities", obj(vec![
+            ("textDocumentSync", Json::U64(SYNC_FULL)),
+            ("hoverProvider", Json::Boolean(true)),
+            ("documentSymbolProvider", Json::Boolean(true)),
+            ("definitionProvider", Json::Boolean(true)),
+            ("documentFormattingProvider", Json::Boolean(true)),
+        ])),
+        ("serverInfo", obj(vec![("name", string("tomledit-lsp")), ("version", string(env!("CARGO_PKG_VERSION")))])),
+    ])
+}
+
+fn find_str<'a>(json: &'a Json, path: &[&str]) -> Option<&'a str> {
+    json.find_path(path).and_then(|j| j.as_string())
+}
+
+fn find_position(params: &Json) -> Option<Position> {
+    let line = params.find_path(&["position", "line"])?.as_u64()?;
+    let col = params.find_path(&["position", "character"])?.as_u64()?;
+    Some((line as usize, col as usize))
+}
+
+/// The state of a language server: the documents the client has open.
+#[derive(Debug,Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    shut_down: bool,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Has the client asked the server to shut down?
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> Json {
+        let found = self.documents.get(uri).map(|text| diagnostics(text)).unwrap_or_default();
+        notification("textDocument/publishDiagnostics",
+                     obj(vec![("uri", string(uri)), ("diagnostics", Json::Array(found))]))
+    }
+
+    fn analyse<'a>(&self, params: &'a Json) -> Option<(&'a str, Analysis)> {
+        let uri = find_str(params, &["textDocument", "uri"])?;
+        Some((uri, Analysis::new(self.documents.get(uri)?)?))
+    }
+
+    /// Handle one message from the client, giving the messages to send back.
+    pub fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = msg.find("method").and_then(|m| m.as_string()).unwrap_or("");
+        let params = msg.find("params").cloned().unwrap_or(Json::Null);
+        let id = match msg.find("id") {
+            Some(id) => id.clone(),
+            None => return self.notify(method, &params),
+        };
+        if self.shut_down {
+            return vec![error_response(id, INVALID_REQUEST, "the server has shut down")];
+        }
+        let result = match method {
+            "initialize" => capabilities(),
+            "shutdown" => {
+                self.shut_down = true;
+                Json::Null
+            },
+            "textDocument/hover" => match (self.analyse(&params), find_position(&params)) {
+                (Some((_, analysis)), Some(pos)) => analysis.hover(pos),
+                _ => Json::Null,
+            },
+            "textDocument/definition" => match (self.analyse(&params), find_position(&params)) {
+                (Some((uri, analysis)), Some(pos)) => analysis.definition(uri, pos),
+                _ => Json::Null,
+            },
+            "textDocument/documentSymbol" => match self.analyse(&params) {
+                Some((_, analysis)) => analysis.symbols(),
+                None => Json::Null,
+            },
+            "textDocument/formatting" => match self.analyse(&params) {
+                Some((_, analysis)) => analysis.formatting(params.find("options").unwrap_or(&Json::Null)),
+                None => Json::Null,
+            },
+            _ => return vec![error_response(id, METHOD_NOT_FOUND, &format!("unknown method {}", method))],
+        };
+        vec![response(id, result)]
+    }
+
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = match find_str(params, &["textDocument", "uri"]) {
+            Some(uri) => String::from(uri),
+            None => return Vec::new(),
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = find_str(params, &["textDocument", "text"]).unwrap_or("");
+                self.documents.insert(uri.clone(), String::from(text));
+            },
+            "textDocument/didChange" => {
+                let changes = params.find("contentChanges").and_then(|c| c.as_array());
+                match changes.and_then(|c| c.last()).and_then(|c| find_str(c, &["text"])) {
+                    Some(text) => self.documents.insert(uri.clone(), String::from(text)),
+                    None => return Vec::new(),
+                };
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+            },
+            _ => return Vec::new(),
+        }
+        vec![self.publish_diagnostics(&uri)]
+    }
+}
+
+/// Read one message, or `None` at the end of the input.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message<W: Write>(output: &mut W, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serve one client until it sends `exit` or closes the input. Returns
+/// whether it asked the server to shut down first, as it should have.
+pub fn run<R: BufRead, W: Write>(input: &mut R, output: &mut W) -> io::Result<bool> {
+    let mut server = Server::new();
+    while let Some(text) = read_message(input)? {
+        let msg = match Json::from_str(&text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                write_message(output, &error_response(Json::Null, PARSE_ERROR, &e.to_string()))?;
+                continue;
+            },
+        };
+        if msg.find("method").and_then(|m| m.as_string()) == Some("exit") {
+            break;
+        }
+        for reply in server.handle(&msg) {
+            write_message(output, &reply)?;
+        }
+    }
+    Ok(server.is_shut_down())
+}
+
+#[cfg(test)]
+fn request(server: &mut Server, method: &str, params: Json) -> Json {
+    let msg = obj(vec![("jsonrpc", string("2.0")), ("id", Json::U64(1)), ("method", string(method)),
+                       ("params", params)]);
+    server.handle(&msg).remove(0).find("result").unwrap().clone()
+}
+
+#[cfg(test)]
+fn at(line: u64, character: u64) -> Json {
+    obj(vec![("textDocument", obj(vec![("uri", string("file:///a.toml"))])),
+             ("position", obj(vec![("line", Json::U64(line)), ("character", Json::U64(character))]))])
+}
+
+#[test]
+fn test_token_positions() {
+    let tokens = tokenise_recovering("a = \"\u{1f600}\"\nb = 1").0;
+    let positions = token_positions(&tokens, false);
+    assert_eq!(positions[4], (0, 4));
+    assert_eq!(positions[5], (0, 8));
+    assert_eq!(positions[6], (1, 0));
+    assert_eq!(token_at(&positions, (0, 5)), Some(4));
+    assert_eq!(prefer_word(&tokens, &positions, (0, 1)), Some(0));
+    assert_eq!(prefer_word(&tokens, &positions, (1, 5)), Some(10));
+    assert_eq!(token_positions(&tokens, true)[1], (0, 2));
+}
+
+#[test]
+fn test_lsp_hover_and_definition() {
+    let mut server = Server::new();
+    let text = "[[bin]]\nname = \"a\"\n[[bin]]\nname = \"b\"\n[bin.meta]\nx.y = [1, 2]\n";
+    let open = obj(vec![("textDocument", obj(vec![("uri", string("file:///a.toml")), ("text", string(text))]))]);
+    server.handle(&obj(vec![("method", string("textDocument/didOpen")), ("params", open)]));
+
+    let hover = |server: &mut Server, line, col| {
+        let res = request(server, "textDocument/hover", at(line, col));
+        res.find_path(&["contents", "value"]).map(|v| v.as_string().unwrap().to_string())
+    };
+    assert_eq!(hover(&mut server, 3, 1), Some(String::from("bin[1].name")));
+    assert_eq!(hover(&mut server, 4, 2), Some(String::from("bin[1]")));
+    assert_eq!(hover(&mut server, 4, 6), Some(String::from("bin[1].meta")));
+    assert_eq!(hover(&mut server, 5, 2), Some(String::from("bin[1].meta.x.y")));
+    assert_eq!(hover(&mut server, 5, 10), Some(String::from("bin[1].meta.x.y[1]")));
+
+    // From bin in [bin.meta] to the [[bin]] it belongs to
+    let def = request(&mut server, "textDocument/definition", at(4, 2));
+    assert_eq!(def.find_path(&["range", "start", "line"]), Some(&Json::U64(2)));
+    assert_eq!(def.find_path(&["range", "end", "character"]), Some(&Json::U64(7)));
+}
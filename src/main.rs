@@ -0,0 +1,241 @@
+extern crate toml;
+extern crate tomledit;
+
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::process;
+
+use tomledit::{Document, EditBatch, Error, JsonFormat, KeyPath, Style, format_value, parse_value,
+               unified_diff, to_json, from_json, from_yaml, tokenise_recovering, to_ansi, to_html,
+               ansi_diff};
+
+const USAGE: &str = "\
+Usage:
+    tomledit get FILE KEYPATH
+    tomledit set [--in-place | --diff] FILE KEYPATH VALUE
+    tomledit delete [--in-place | --diff] FILE KEYPATH
+    tomledit apply [--in-place | --diff] FILE SCRIPT
+    tomledit convert [--tagged] FILE
+    tomledit show [--html] FILE
+
+KEYPATH is a dotted path like package.name or bin[0].path. VALUE is written
+in TOML syntax, so strings need quotes: '\"1.0\"'. FILE may be - to read
+standard input. Edited documents are written to standard output, or back to
+FILE with --in-place (-i). With --diff, nothing is written and the changes
+are shown as a unified diff instead.
+
+SCRIPT is a list of edits in JSON (if it ends in .json) or TOML, applied
+all together or not at all.
+
+convert writes a TOML FILE as JSON, or a FILE ending in .json, .yaml or .yml
+as TOML. With --tagged, JSON is written in the toml-test format, with the
+type of each value.
+
+show prints FILE with syntax highlighting, or as HTML with --html.
+Highlighting in show and --diff output is used when writing to a terminal;
+--color=always or --color=never overrides that.";
+
+enum Failure {
+    /// The command line was wrong
+    Usage(String),
+    /// The command couldn't be carried out
+    Failed(String),
+}
+
+struct Args {
+    in_place: bool,
+    diff: bool,
+    tagged: bool,
+    html: bool,
+    color: bool,
+    positional: Vec<String>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => (),
+        Err(Failure::Usage(msg)) => {
+            eprintln!("tomledit: {}\n\n{}", msg, USAGE);
+            process::exit(2);
+        },
+        Err(Failure::Failed(msg)) => {
+            eprintln!("tomledit: {}", msg);
+            process::exit(1);
+        },
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, Failure> {
+    let mut res = Args{in_place: false, diff: false, tagged: false, html: false,
+                       color: io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+                       positional: Vec::new()};
+    for arg in args {
+        match arg.as_str() {
+            "--html" => res.html = true,
+            "--color=always" => res.color = true,
+            "--color=never" => res.color = false,
+            "--color=auto" => (),
+            "-i" | "--in-place" => res.in_place = true,
+            "--diff" => res.diff = true,
+            "--tagged" => res.tagged = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            a if a.starts_with('-') && a != "-" => {
+                return Err(Failure::Usage(format!("unknown option {}", a)));
+            },
+            _ => res.positional.push(arg.clone()),
+        }
+    }
+    if res.in_place && res.diff {
+        return Err(Failure::Usage(String::from("--in-place and --diff can't be used together")));
+    }
+    Ok(res)
+}
+
+/// Describe an error in a file, with the line and column if there is one.
+fn located(path: &str, e: Error) -> Failure {
+    match e {
+        Error::Parse(line, col, msg) => Failure::Failed(format!("{}:{}:{}: {}", path, line, col, msg)),
+        e => Failure::Failed(format!("{}: {}", path, e)),
+    }
+}
+
+fn read_text(path: &str) -> Result<String, Failure> {
+    let mut text = String::new();
+    let read = if path == "-" {
+        io::stdin().read_to_string(&mut text).map(|_| ())
+    } else {
+        fs::File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map(|_| ())
+    };
+    read.map_err(|e| Failure::Failed(format!("{}: {}", path, e)))?;
+    Ok(text)
+}
+
+fn read_document(path: &str) -> Result<Document, Failure> {
+    Document::parse(&read_text(path)?).map_err(|e| located(path, e))
+}
+
+fn read_script(path: &str) -> Result<EditBatch, Failure> {
+    let text = fs::read_to_string(path).map_err(|e| Failure::Failed(format!("{}: {}", path, e)))?;
+    let batch = if path.ends_with(".json") {
+        EditBatch::from_json(&text)
+    } else {
+        EditBatch::from_toml(&text)
+    };
+    batch.map_err(|e| located(path, e))
+}
+
+/// Output an edited document as the options ask.
+fn write_document(original: &Document, doc: &Document, path: &str, args: &Args) -> Result<(), Failure> {
+    if args.diff && args.color {
+        print!("{}", ansi_diff(original.tokens(), doc.tokens(), path));
+        return Ok(());
+    }
+    if args.diff {
+        print!("{}", unified_diff(original.tokens(), doc.tokens(), path));
+        return Ok(());
+    }
+    if !args.in_place {
+        print!("{}", doc);
+        return Ok(());
+    }
+    if path == "-" {
+        return Err(Failure::Usage(String::from("--in-place needs a file, not standard input")));
+    }
+    fs::write(path, doc.to_string()).map_err(|e| Failure::Failed(format!("{}: {}", path, e)))
+}
+
+fn key_path(s: &str) -> Result<KeyPath, Failure> {
+    KeyPath::parse(s).map_err(|e| Failure::Failed(e.to_string()))
+}
+
+/// Strings are printed as they are, so scripts can use them directly; other
+/// values are printed in TOML syntax.
+fn display_value(value: &toml::Value) -> String {
+    match *value {
+        toml::Value::String(ref s) => s.clone(),
+        _ => format_value(value, &Style::default(), "\n"),
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let args = parse_args(args)?;
+    let (command, rest) = match args.positional.split_first() {
+        Some((c, rest)) => (c.as_str(), rest),
+        None => return Err(Failure::Usage(String::from("no command given"))),
+    };
+    match (command, rest) {
+        ("get", [file, key]) => {
+            let doc = read_document(file)?;
+            let key = key_path(key)?;
+            match doc.get(&key) {
+                Some(value) => {
+                    println!("{}", display_value(value));
+                    Ok(())
+                },
+                None => Err(located(file, Error::KeyNotFound(key))),
+            }
+        },
+        ("set", [file, key, value]) => {
+            let original = read_document(file)?;
+            let key = key_path(key)?;
+            let value = parse_value(value)
+                            .map_err(|e| Failure::Failed(format!("invalid value: {}", e)))?;
+            let mut doc = original.clone();
+            doc.set(&key, value).map_err(|e| located(file, e))?;
+            write_document(&original, &doc, file, &args)
+        },
+        ("delete", [file, key]) => {
+            let original = read_document(file)?;
+            let key = key_path(key)?;
+            let mut doc = original.clone();
+            doc.remove(&key).map_err(|e| located(file, e))?;
+            write_document(&original, &doc, file, &args)
+        },
+        ("apply", [file, script]) => {
+            let original = read_document(file)?;
+            let batch = read_script(script)?;
+            let mut doc = original.clone();
+            batch.apply(&mut doc).map_err(|e| located(file, e))?;
+            write_document(&original, &doc, file, &args)
+        },
+        ("convert", [file]) => {
+            if args.in_place || args.diff {
+                return Err(Failure::Usage(String::from("convert writes to standard output")));
+            }
+            if file.ends_with(".json") || file.ends_with(".yaml") || file.ends_with(".yml") {
+                let text = read_text(file)?;
+                let doc = if file.ends_with(".json") { from_json(&text) } else { from_yaml(&text) };
+                print!("{}", doc.map_err(|e| located(file, e))?);
+            } else {
+                let format = if args.tagged { JsonFormat::Tagged } else { JsonFormat::Plain };
+                let json = to_json(&read_document(file)?, format).map_err(|e| located(file, e))?;
+                println!("{}", json.pretty());
+            }
+            Ok(())
+        },
+        ("show", [file]) => {
+            if args.in_place || args.diff {
+                return Err(Failure::Usage(String::from("show writes to standard output")));
+            }
+            // Files with errors are still shown, with the errors marked
+            let (tokens, _) = tokenise_recovering(&read_text(file)?);
+            if args.html {
+                println!("<pre class=\"toml\">{}</pre>", to_html(&tokens));
+            } else if args.color {
+                print!("{}", to_ansi(&tokens));
+            } else {
+                print!("{}", tokens.iter().map(|t| t.text.as_str()).collect::<String>());
+            }
+            Ok(())
+        },
+        ("get", _) | ("set", _) | ("delete", _) | ("apply", _) | ("convert", _) | ("show", _) => {
+            Err(Failure::Usage(format!("wrong number of arguments for {}", command)))
+        },
+        _ => Err(Failure::Usage(format!("unknown command {}", command))),
+    }
+}
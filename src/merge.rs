@@ -0,0 +1,161 @@
+//! Three-way merge: bring the changes between two versions of a document into
+//! a third copy which has been edited separately.
+
+extern crate toml;
+
+use super::keypath::{KeyPath, KeyPathComponent};
+use super::error::Error;
+use super::document::Document;
+use super::compare::{Change, CompareOptions, compare, compare_values};
+
+/// A key which both sides changed in different ways. Each value is `None`
+/// where that version doesn't have the key.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Conflict {
+    pub key: KeyPath,
+    pub base: Option<toml::Value>,
+    pub ours: Option<toml::Value>,
+    pub theirs: Option<toml::Value>,
+}
+
+/// The result of `merge`.
+#[derive(Debug,Clone)]
+pub struct Merge {
+    /// Our document with the changes that didn't conflict applied
+    pub document: Document,
+    /// Changes that were left out, in key order
+    pub conflicts: Vec<Conflict>,
+}
+
+fn same(a: Option<&toml::Value>, b: Option<&toml::Value>) -> bool {
+    match (a, b) {
+        (Some(x), Some(y)) => compare_values(x, y, &CompareOptions::default()).is_empty(),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Whether a change to a key can't be made in `document` because of what it
+/// has around the key: a table or array that was in the base and has been
+/// removed (adding to it would bring it back), something that is no longer a
+/// table or array, or an array too short to add the element to.
+fn blocked(base: &Document, document: &Document, key: &KeyPath) -> bool {
+    let mut ancestor = key.parent();
+    while let Some(p) = ancestor {
+        if p.parts.is_empty() {
+            break;
+        }
+        match document.get(&p) {
+            Some(&toml::Value::Table(_)) | Some(&toml::Value::Array(_)) => (),
+            Some(_) => return true,
+            None if base.get(&p).is_some() => return true,
+            None => (),
+        }
+        ancestor = p.parent();
+    }
+    match (key.parts.last(), key.parent()) {
+        (Some(&KeyPathComponent::Ix(i)), Some(p)) => match document.get(&p) {
+            Some(toml::Value::Array(items)) => i > items.len(),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Apply the changes from `base` to `theirs` to a copy of `ours`.
+///
+/// Changes are made as edits, so comments and layout in `ours` are kept. A
+/// change is applied where `ours` still has the base value, and skipped where
+/// `ours` already has the new value; otherwise, or if `ours` no longer has
+/// a table or array for it to go in, it's reported as a conflict and `ours`
+/// is left as it is. Array elements are matched by position.
+pub fn merge(base: &Document, ours: &Document, theirs: &Document) -> Result<Merge, Error> {
+    let mut document = ours.clone();
+    let mut conflicts = Vec::new();
+    let mut removals = Vec::new();
+    for change in compare(base, theirs, &CompareOptions::default()) {
+        let key = change.key().clone();
+        let new = match change {
+            Change::Added(_, ref v) | Change::Changed(_, _, ref v) => Some(v),
+            Change::Removed(..) => None,
+        };
+        let (in_base, in_ours) = (base.get(&key), ours.get(&key));
+        if same(in_ours, new) {
+            continue;
+        }
+        if !same(in_ours, in_base) || (new.is_some() && blocked(base, &document, &key)) {
+            conflicts.push(Conflict{key, base: in_base.cloned(), ours: in_ours.cloned(),
+                                    theirs: new.cloned()});
+            continue;
+        }
+        match new {
+            Some(v) => document.set(&key, v.clone())?,
+            None => removals.push(key),
+        }
+    }
+    // Removed array elements come after any kept ones, so taking them from
+    // the end keeps the other indices valid
+    for key in removals.iter().rev() {
+        document.remove(key)?;
+    }
+    Ok(Merge{document, conflicts})
+}
+
+#[cfg(test)]
+fn merged(base: &str, ours: &str, theirs: &str) -> Merge {
+    merge(&Document::parse(base).unwrap(), &Document::parse(ours).unwrap(),
+          &Document::parse(theirs).unwrap()).unwrap()
+}
+
+#[test]
+fn test_merge() {
+    let base = "[a]\nx = 1\ny = 2\nz = [1, 2, 3]\n";
+    let ours = "# My settings\n[a]\nx = 1 # keep\ny = 5\nz = [1, 2, 3]\n";
+    let theirs = "[a]\nx = 10\ny = 2\nz = [1]\nw = \"new\"\n";
+    let res = merged(base, ours, theirs);
+    assert!(res.conflicts.is_empty());
+    assert_eq!(res.document.to_string(),
+               "# My settings\n[a]\nx = 10 # keep\ny = 5\nz = [1]\nw = \"new\"\n");
+
+    // Both sides making the same change is fine
+    assert!(merged("a = 1\n", "a = 2\n", "a = 2\n").conflicts.is_empty());
+}
+
+#[test]
+fn test_merge_conflicts() {
+    let res = merged("a = 1\nb = 1\n", "a = 2\nb = 1\n", "a = 3\n");
+    assert_eq!(res.conflicts, vec![Conflict{
+        key: KeyPath::from_string("a"),
+        base: Some(toml::Value::Integer(1)),
+        ours: Some(toml::Value::Integer(2)),
+        theirs: Some(toml::Value::Integer(3)),
+    }]);
+    assert_eq!(res.document.to_string(), "a = 2\n");
+
+    // Theirs adds to a table we removed
+    let res = merged("[t]\nx = 1\n", "", "[t]\nx = 1\ny = 2\n");
+    assert_eq!(res.conflicts.len(), 1);
+    assert_eq!(res.conflicts[0].ours, None);
+    assert_eq!(res.document.to_string(), "");
+
+    // Theirs appends to an array we shortened
+    let res = merged("a = [1, 2, 3]\n", "a = [1, 2]\n", "a = [1, 2, 3, 4]\n");
+    assert_eq!(res.conflicts, vec![Conflict{
+        key: KeyPath::from_string("a[3]"),
+        base: None,
+        ours: None,
+        theirs: Some(toml::Value::Integer(4)),
+    }]);
+    assert_eq!(res.document.to_string(), "a = [1, 2]\n");
+
+    // Theirs adds to a table we replaced with a value
+    let res = merged("[x]\na = 1\n", "x = 5\n", "[x]\na = 1\nb = 2\n");
+    assert_eq!(res.conflicts.len(), 1);
+    assert_eq!(res.conflicts[0].key, KeyPath::from_string("x.b"));
+    assert_eq!(res.document.to_string(), "x = 5\n");
+
+    // Appending several elements works while the array keeps up
+    let res = merged("a = [1]\n", "a = [1] # c\n", "a = [1, 2, 3]\n");
+    assert!(res.conflicts.is_empty());
+    assert_eq!(res.document.to_string(), "a = [1, 2, 3] # c\n");
+}
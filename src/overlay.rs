@@ -0,0 +1,134 @@
+//! Merge a fragment of TOML into a document, key by key.
+
+extern crate toml;
+
+use super::keypath::KeyPath;
+use super::error::Error;
+use super::document::Document;
+use super::locate::locate_all;
+use super::compare::{CompareOptions, compare_values};
+
+/// What to do when the fragment has a value for a key the document already
+/// has, with a different value.
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub enum ExistingPolicy {
+    /// Use the value from the fragment
+    #[default]
+    Overwrite,
+    /// Leave the document's value alone
+    Keep,
+    /// Fail with `Error::KeyExists`
+    Error,
+}
+
+/// How to combine an array in the fragment with one in the document.
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub enum ArrayPolicy {
+    /// Treat the array like any other value, following the `ExistingPolicy`
+    #[default]
+    Replace,
+    /// Add the fragment's elements to the end
+    Append,
+    /// Add the fragment's elements which aren't already in the array
+    Union,
+}
+
+/// Options for `Document::merge_from_with`.
+#[derive(Debug,PartialEq,Clone,Copy,Default)]
+pub struct OverlayOptions {
+    pub existing: ExistingPolicy,
+    pub arrays: ArrayPolicy,
+}
+
+fn same(a: &toml::Value, b: &toml::Value) -> bool {
+    compare_values(a, b, &CompareOptions::default()).is_empty()
+}
+
+impl Document {
+    pub fn merge_from(&mut self, fragment: &str) -> Result<(), Error> {
+        self.merge_from_with(fragment, &OverlayOptions::default())
+    }
+
+    /// Set each value in a fragment of TOML, going into tables so that only
+    /// the leaves are replaced. Missing tables are created, and keys are
+    /// visited in the order the fragment has them.
+    ///
+    /// If this fails, the document is left unchanged.
+    pub fn merge_from_with(&mut self, fragment: &str, options: &OverlayOptions) -> Result<(), Error> {
+        let fragment = Document::parse(fragment)?;
+        let order: Vec<KeyPath> = locate_all(fragment.tokens()).into_iter().map(|loc| loc.key).collect();
+        let mut working = self.clone();
+        working.overlay(&KeyPath::new(), fragment.values(), &order, options)?;
+        *self = working;
+        Ok(())
+    }
+
+    fn overlay(&mut self, key: &KeyPath, value: &toml::Value, order: &[KeyPath],
+               options: &OverlayOptions) -> Result<(), Error> {
+        let existing = self.get(key).cloned();
+        match (value, existing) {
+            (toml::Value::Table(table), None) | (toml::Value::Table(table), Some(toml::Value::Table(_))) => {
+                let first_written = |k: &KeyPath| order.iter().position(|o| o.starts_with(k));
+                let mut children: Vec<(KeyPath, &toml::Value)> = table.iter()
+                    .map(|(k, v)| (key.clone().append_key(k.clone()), v))
+                    .collect();
+                children.sort_by_key(|(k, _)| first_written(k).unwrap_or(usize::MAX));
+                for (child, v) in children {
+                    self.overlay(&child, v, order, options)?;
+                }
+                Ok(())
+            },
+            (toml::Value::Array(items), Some(toml::Value::Array(ref current)))
+                    if options.arrays != ArrayPolicy::Replace => {
+                for item in items {
+                    if options.arrays == ArrayPolicy::Union && current.iter().any(|c| same(c, item)) {
+                        continue;
+                    }
+                    self.append(key, item.clone())?;
+                }
+                Ok(())
+            },
+            (_, None) => self.set(key, value.clone()),
+            (_, Some(ref current)) if same(current, value) => Ok(()),
+            (_, Some(_)) => match options.existing {
+                ExistingPolicy::Overwrite => self.set(key, value.clone()),
+                ExistingPolicy::Keep => Ok(()),
+                ExistingPolicy::Error => Err(Error::KeyExists(key.clone())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+fn overlaid(inp: &str, fragment: &str, options: &OverlayOptions) -> Result<String, Error> {
+    let mut doc = Document::parse(inp).unwrap();
+    doc.merge_from_with(fragment, options).map(|_| doc.to_string())
+}
+
+#[test]
+fn test_merge_from() {
+    let inp = "[package]\nname = \"x\" # ours\n\n[profile.release]\nopt-level = 3\n";
+    let mut doc = Document::parse(inp).unwrap();
+    doc.merge_from("[profile.release]\nlto = true\ndebug = false\n[package]\nname = \"y\"\n").unwrap();
+    assert_eq!(doc.to_string(),
+               "[package]\nname = \"y\" # ours\n\n[profile.release]\nopt-level = 3\nlto = true\ndebug = false\n");
+
+    let keep = OverlayOptions{existing: ExistingPolicy::Keep, ..Default::default()};
+    assert_eq!(overlaid("a = 1\n", "a = 2\nb = 3\n", &keep), Ok(String::from("a = 1\nb = 3\n")));
+    let error = OverlayOptions{existing: ExistingPolicy::Error, ..Default::default()};
+    assert_eq!(overlaid("a = 1\n", "b = 3\na = 2\n", &error), Err(Error::KeyExists(KeyPath::from_string("a"))));
+    assert_eq!(overlaid("a = 1\n", "a = 1\n", &error), Ok(String::from("a = 1\n")));
+}
+
+#[test]
+fn test_merge_from_arrays() {
+    let options = |arrays| OverlayOptions{arrays, ..Default::default()};
+    assert_eq!(overlaid("a = [1, 2]\n", "a = [2, 3]\n", &options(ArrayPolicy::Replace)),
+               Ok(String::from("a = [2, 3]\n")));
+    assert_eq!(overlaid("a = [1, 2]\n", "a = [2, 3]\n", &options(ArrayPolicy::Append)),
+               Ok(String::from("a = [1, 2, 2, 3]\n")));
+    assert_eq!(overlaid("a = [1, 2]\n", "a = [2, 3]\n", &options(ArrayPolicy::Union)),
+               Ok(String::from("a = [1, 2, 3]\n")));
+    assert_eq!(overlaid("[[bin]]\nname = \"a\"\n", "[[bin]]\nname = \"b\"\n", &options(ArrayPolicy::Append)),
+               Ok(String::from("[[bin]]\nname = \"a\"\n\n[[bin]]\nname = \"b\"\n")));
+}
@@ -0,0 +1,341 @@
+//! Apply JSON Patch (RFC 6902) and JSON Merge Patch (RFC 7386) documents.
+//!
+//! JSON Pointer paths are turned into key paths against the document as it
+//! is when each operation runs, so `/bin/0/name` means `bin[0].name` if `bin`
+//! is an array, and `bin."0".name` if it's a table.
+
+extern crate toml;
+
+use rustc_serialize::json::Json;
+
+use super::keypath::{KeyPath, KeyPathComponent};
+use super::error::Error;
+use super::document::Document;
+use super::batch::json_to_toml;
+use super::compare::{CompareOptions, compare_values};
+
+/// One JSON Patch operation. Paths are JSON Pointers, like `/a/b/0`.
+#[derive(Debug,PartialEq,Clone)]
+pub enum PatchOp {
+    Add(String, toml::Value),
+    Remove(String),
+    Replace(String, toml::Value),
+    /// From, then to
+    Move(String, String),
+    /// From, then to
+    Copy(String, String),
+    Test(String, toml::Value),
+}
+
+/// A list of JSON Patch operations, applied all together or not at all.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct JsonPatch {
+    pub ops: Vec<PatchOp>,
+}
+
+/// Split a JSON Pointer into its unescaped reference tokens.
+fn pointer_tokens(pointer: &str) -> Result<Vec<String>, Error> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !pointer.starts_with('/') {
+        return Err(Error::InvalidKeyPath(String::from(pointer)));
+    }
+    Ok(pointer[1..].split('/').map(|t| t.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+/// Turn a JSON Pointer into a key path for this document. Tokens are array
+/// indices where the value they're applied to is an array, and `-` means one
+/// past the end of an array.
+pub fn pointer_to_keypath(doc: &Document, pointer: &str) -> Result<KeyPath, Error> {
+    let invalid = || Error::InvalidKeyPath(String::from(pointer));
+    let mut key = KeyPath::new();
+    for token in pointer_tokens(pointer)? {
+        key = match doc.get(&key) {
+            Some(toml::Value::Array(items)) => {
+                if token == "-" {
+                    key.append_index(items.len())
+                } else if token == "0" || (!token.starts_with('0') && !token.starts_with('+')) {
+                    key.append_index(token.parse().map_err(|_| invalid())?)
+                } else {
+                    return Err(invalid());
+                }
+            },
+            _ => key.append_key(token),
+        };
+    }
+    Ok(key)
+}
+
+fn same(a: &toml::Value, b: &toml::Value) -> bool {
+    compare_values(a, b, &CompareOptions::default()).is_empty()
+}
+
+fn op_from_json(i: usize, json: &Json) -> Result<PatchOp, Error> {
+    let invalid = |why: &str| Error::InvalidScript(format!("operation {}: {}", i + 1, why));
+    let obj = match *json {
+        Json::Object(ref o) => o,
+        _ => return Err(invalid("expected an object")),
+    };
+    let string_field = |name: &str| match obj.get(name) {
+        Some(Json::String(s)) => Ok(s.clone()),
+        Some(_) => Err(invalid(&format!("\"{}\" should be a string", name))),
+        None => Err(invalid(&format!("missing \"{}\"", name))),
+    };
+    let value_field = || match obj.get("value") {
+        Some(v) => json_to_toml(v).map_err(|e| invalid(&e)),
+        None => Err(invalid("missing \"value\"")),
+    };
+    let path = string_field("path")?;
+    match string_field("op")?.as_str() {
+        "add" => Ok(PatchOp::Add(path, value_field()?)),
+        "remove" => Ok(PatchOp::Remove(path)),
+        "replace" => Ok(PatchOp::Replace(path, value_field()?)),
+        "move" => Ok(PatchOp::Move(string_field("from")?, path)),
+        "copy" => Ok(PatchOp::Copy(string_field("from")?, path)),
+        "test" => Ok(PatchOp::Test(path, value_field()?)),
+        op => Err(invalid(&format!("unknown op \"{}\"", op))),
+    }
+}
+
+/// Make the whole document hold this table, keeping what's already there
+/// where the values match.
+fn replace_root(doc: &mut Document, value: toml::Value) -> Result<(), Error> {
+    let table = match value {
+        toml::Value::Table(t) => t,
+        _ => return Err(Error::NotRepresentable(KeyPath::new(), String::from("a document must be a table"))),
+    };
+    let old_keys: Vec<String> = match doc.values() {
+        toml::Value::Table(t) => t.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+    for k in old_keys {
+        if !table.contains_key(&k) {
+            doc.remove(&KeyPath::new().append_key(k))?;
+        }
+    }
+    for (k, v) in table {
+        let key = KeyPath::new().append_key(k);
+        if !doc.get(&key).is_some_and(|current| same(current, &v)) {
+            doc.set(&key, v)?;
+        }
+    }
+    Ok(())
+}
+
+/// Add a value as JSON Patch does: replacing a member of a table, or
+/// inserting into an array before the element at the index. The table or
+/// array has to be there already.
+fn add(doc: &mut Document, pointer: &str, value: toml::Value) -> Result<(), Error> {
+    let key = pointer_to_keypath(doc, pointer)?;
+    let (parent, index) = match key.parts.split_last() {
+        None => return replace_root(doc, value),
+        Some((&KeyPathComponent::Ix(i), _)) => (key.parent().unwrap(), i),
+        Some(_) => {
+            let parent = key.parent().unwrap();
+            return match doc.get(&parent) {
+                Some(toml::Value::Table(_)) => doc.set(&key, value),
+                Some(_) => Err(Error::WrongType(parent, "table")),
+                None => Err(Error::KeyNotFound(parent)),
+            };
+        },
+    };
+    let mut items = match doc.get(&parent) {
+        Some(toml::Value::Array(items)) => items.clone(),
+        Some(_) => return Err(Error::WrongType(parent, "array")),
+        None => return Err(Error::KeyNotFound(parent)),
+    };
+    if index > items.len() {
+        return Err(Error::KeyNotFound(key));
+    }
+    // Shift the later elements along one at a time, so each is rewritten
+    // where it's already written
+    items.insert(index, value);
+    for i in (index..items.len()).rev() {
+        doc.set(&parent.clone().append_index(i), items[i].clone())?;
+    }
+    Ok(())
+}
+
+fn existing(doc: &Document, pointer: &str) -> Result<(KeyPath, toml::Value), Error> {
+    let key = pointer_to_keypath(doc, pointer)?;
+    match doc.get(&key) {
+        Some(v) => Ok((key, v.clone())),
+        None => Err(Error::KeyNotFound(key)),
+    }
+}
+
+impl JsonPatch {
+    /// Read a patch from a JSON list of operations.
+    pub fn from_json(text: &str) -> Result<JsonPatch, Error> {
+        match Json::from_str(text).map_err(|e| Error::InvalidScript(e.to_string()))? {
+            Json::Array(ref items) => {
+                let ops = items.iter().enumerate().map(|(i, j)| op_from_json(i, j)).collect::<Result<_, _>>()?;
+                Ok(JsonPatch{ops})
+            },
+            _ => Err(Error::InvalidScript(String::from("expected a list of operations"))),
+        }
+    }
+
+    /// Carry out each operation in turn. If one fails, including a `test`
+    /// that doesn't match, the document is left as it was.
+    pub fn apply(&self, doc: &mut Document) -> Result<(), Error> {
+        let mut working = doc.clone();
+        for (i, op) in self.ops.iter().enumerate() {
+            apply_op(&mut working, op).map_err(|e| Error::Operation(i, Box::new(e)))?;
+        }
+        *doc = working;
+        Ok(())
+    }
+}
+
+fn apply_op(doc: &mut Document, op: &PatchOp) -> Result<(), Error> {
+    match *op {
+        PatchOp::Add(ref path, ref value) => add(doc, path, value.clone()),
+        PatchOp::Remove(ref path) => {
+            let (key, _) = existing(doc, path)?;
+            doc.remove(&key).map(|_| ())
+        },
+        PatchOp::Replace(ref path, ref value) => {
+            let (key, _) = existing(doc, path)?;
+            if key.parts.is_empty() {
+                replace_root(doc, value.clone())
+            } else {
+                doc.set(&key, value.clone())
+            }
+        },
+        PatchOp::Move(ref from, ref path) => {
+            let (key, value) = existing(doc, from)?;
+            if from == path {
+                return Ok(());
+            }
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(Error::NotRepresentable(key, String::from("can't move a value inside itself")));
+            }
+            doc.remove(&key)?;
+            add(doc, path, value)
+        },
+        PatchOp::Copy(ref from, ref path) => {
+            let (_, value) = existing(doc, from)?;
+            add(doc, path, value)
+        },
+        PatchOp::Test(ref path, ref value) => {
+            let (key, current) = existing(doc, path)?;
+            if same(&current, value) {
+                Ok(())
+            } else {
+                Err(Error::TestFailed(key))
+            }
+        },
+    }
+}
+
+/// A merge patch value with any `null` members of objects dropped.
+fn without_nulls(json: &Json) -> Result<toml::Value, Error> {
+    match *json {
+        Json::Object(ref obj) => {
+            let mut table = toml::Table::new();
+            for (k, v) in obj {
+                if *v != Json::Null {
+                    table.insert(k.clone(), without_nulls(v)?);
+                }
+            }
+            Ok(toml::Value::Table(table))
+        },
+        _ => json_to_toml(json).map_err(Error::InvalidScript),
+    }
+}
+
+fn merge_patch_at(doc: &mut Document, key: &KeyPath, patch: &Json) -> Result<(), Error> {
+    let obj = match *patch {
+        Json::Object(ref o) => o,
+        _ => unreachable!(),
+    };
+    for (k, v) in obj {
+        let child = key.clone().append_key(k.clone());
+        let current = doc.get(&child);
+        match *v {
+            Json::Null => {
+                if current.is_some() {
+                    doc.remove(&child)?;
+                }
+            },
+            Json::Object(_) if matches!(current, Some(toml::Value::Table(_))) => {
+                merge_patch_at(doc, &child, v)?;
+            },
+            _ => {
+                let value = without_nulls(v)?;
+                if !current.is_some_and(|c| same(c, &value)) {
+                    doc.set(&child, value)?;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Apply a JSON Merge Patch: members set to `null` are removed, objects are
+/// merged into tables, and anything else replaces the value. If this fails,
+/// the document is left unchanged.
+pub fn apply_merge_patch(doc: &mut Document, text: &str) -> Result<(), Error> {
+    let patch = Json::from_str(text).map_err(|e| Error::InvalidScript(e.to_string()))?;
+    let mut working = doc.clone();
+    match patch {
+        Json::Object(_) => merge_patch_at(&mut working, &KeyPath::new(), &patch)?,
+        _ => return Err(Error::NotRepresentable(KeyPath::new(), String::from("a document must be a table"))),
+    }
+    *doc = working;
+    Ok(())
+}
+
+#[cfg(test)]
+fn patched(inp: &str, patch: &str) -> Result<String, Error> {
+    let mut doc = Document::parse(inp).unwrap();
+    JsonPatch::from_json(patch)?.apply(&mut doc).map(|_| doc.to_string())
+}
+
+#[test]
+fn test_pointer_to_keypath() {
+    let doc = Document::parse("a = [1, 2]\n[b]\n\"0\" = 1\n\"x/y\" = 2\n").unwrap();
+    let kp = |p| pointer_to_keypath(&doc, p);
+    assert_eq!(kp("/a/1"), Ok(KeyPath::from_string("a[1]")));
+    assert_eq!(kp("/a/-"), Ok(KeyPath::from_string("a[2]")));
+    assert_eq!(kp("/b/0"), Ok(KeyPath::from_string("b.\"0\"")));
+    assert_eq!(kp("/b/x~1y"), Ok(KeyPath::from_string("b.\"x/y\"")));
+    assert_eq!(kp(""), Ok(KeyPath::new()));
+    assert_eq!(kp("/a/01"), Err(Error::InvalidKeyPath(String::from("/a/01"))));
+}
+
+#[test]
+fn test_json_patch() {
+    let inp = "# Deploy\n[server]\nhost = \"a\" # main\nports = [80, 443]\n";
+    assert_eq!(patched(inp, r#"[
+        {"op": "replace", "path": "/server/host", "value": "b"},
+        {"op": "add", "path": "/server/ports/1", "value": 8080},
+        {"op": "add", "path": "/server/tls", "value": true},
+        {"op": "test", "path": "/server/ports/0", "value": 80}
+    ]"#), Ok(String::from("# Deploy\n[server]\nhost = \"b\" # main\nports = [80, 8080, 443]\ntls = true\n")));
+    assert_eq!(patched(inp, r#"[{"op": "move", "from": "/server/host", "path": "/host"},
+                                {"op": "remove", "path": "/server/ports/0"}]"#),
+               Ok(String::from("# Deploy\nhost = \"a\"\n[server]\nports = [443]\n")));
+
+    assert_eq!(patched(inp, r#"[{"op": "test", "path": "/server/host", "value": "b"}]"#),
+               Err(Error::Operation(0, Box::new(Error::TestFailed(KeyPath::from_string("server.host"))))));
+    assert_eq!(patched(inp, r#"[{"op": "replace", "path": "/server/x", "value": 1}]"#),
+               Err(Error::Operation(0, Box::new(Error::KeyNotFound(KeyPath::from_string("server.x"))))));
+    // Adding needs the parent to be there already
+    assert_eq!(patched(inp, r#"[{"op": "add", "path": "/missing/child", "value": 1}]"#),
+               Err(Error::Operation(0, Box::new(Error::KeyNotFound(KeyPath::from_string("missing"))))));
+    assert_eq!(patched(inp, r#"[{"op": "add", "path": "/server/host/x", "value": 1}]"#),
+               Err(Error::Operation(0, Box::new(Error::WrongType(KeyPath::from_string("server.host"), "table")))));
+    assert_eq!(patched(inp, r#"[{"op": "add", "path": "/missing/0", "value": 1}]"#),
+               Err(Error::Operation(0, Box::new(Error::KeyNotFound(KeyPath::from_string("missing"))))));
+}
+
+#[test]
+fn test_merge_patch() {
+    let mut doc = Document::parse("[a]\nx = 1 # keep\ny = 2\n\n[b]\nz = 3\n").unwrap();
+    apply_merge_patch(&mut doc, r#"{"a": {"y": null, "w": [1, 2]}, "b": 5}"#).unwrap();
+    assert_eq!(doc.to_string(), "b = 5\n[a]\nx = 1 # keep\nw = [1, 2]\n");
+    assert!(apply_merge_patch(&mut doc, "[1]").is_err());
+}
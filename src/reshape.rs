@@ -0,0 +1,545 @@
+//! Convert values between the different ways TOML can write them: `[table]`
+//! sections, inline tables, dotted keys, and `[[array]]` sections.
+//!
+//! Values are copied over token for token. Comments go wherever the new
+//! layout can hold them: above the new entry or header, or inside arrays.
+
+use super::tokenise::{Token, TokenType, detect_newline, tokenise_value, text_of};
+use super::keypath::{KeyPath, KeyPathComponent};
+use super::error::Error;
+use super::style::{Style, infer_style};
+use super::tableix::{TablePos, EntryPos, InsertOptions, InsertPolicy, find_tables, find_table,
+                     find_entries, entry_key_parts, entry_comments, entry_comment_positions,
+                     section_comments, section_comment_positions,
+                     remove_entry, remove_sections, insert_line, insert_section, insert_table,
+                     make_key_token, skip_value, inline_members};
+
+fn split_key(key: &KeyPath) -> Result<(KeyPath, String), Error> {
+    match (key.parent(), key.parts.last()) {
+        (Some(parent), Some(KeyPathComponent::Key(name))) => Ok((parent, name.clone())),
+        _ => Err(Error::WrongType(key.clone(), "key"))
+    }
+}
+
+/// Write a value on one line, for use inside an inline table.
+fn single_line_value(tokens: &[Token], key: &KeyPath) -> Result<String, Error> {
+    if tokens.iter().any(|t| t.kind == TokenType::Comment) {
+        return Err(Error::NotRepresentable(key.clone(), String::from("comment inside a value")));
+    }
+    if !tokens.iter().any(|t| t.kind == TokenType::Newline) {
+        return Ok(text_of(tokens));
+    }
+    let significant: Vec<&Token> = tokens.iter()
+        .filter(|t| t.kind != TokenType::Whitespace && t.kind != TokenType::Newline)
+        .collect();
+    let mut res = String::new();
+    for (i, tok) in significant.iter().enumerate() {
+        let next = significant.get(i + 1).map(|t| t.text.as_str());
+        if tok.text == "," && (next == Some("]") || next == Some("}")) {
+            // Trailing comma
+            continue;
+        }
+        res.push_str(&tok.text);
+        let space = match (tok.text.as_str(), next) {
+            (_, None) | ("[", _) | (_, Some("]")) | (_, Some(",")) | (".", _) | (_, Some(".")) => false,
+            (",", _) | ("=", _) | (_, Some("=")) | ("{", _) | (_, Some("}")) => true,
+            _ => false
+        };
+        if space {
+            res.push(' ');
+        }
+    }
+    Ok(res)
+}
+
+fn wrap_inline_table(members: Vec<String>, style: &Style) -> String {
+    if members.is_empty() {
+        String::from("{}")
+    } else if style.inline_table_padding {
+        format!("{{ {} }}", members.join(", "))
+    } else {
+        format!("{{{}}}", members.join(", "))
+    }
+}
+
+fn direct_children(tables: &[TablePos], key: &KeyPath) -> Vec<String> {
+    let mut names = Vec::new();
+    for t in tables {
+        if t.key.parts.len() > key.parts.len() && t.key.starts_with(key) {
+            if let KeyPathComponent::Key(ref name) = t.key.parts[key.parts.len()] {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+    }
+    names
+}
+
+fn is_table_array(tables: &[TablePos], key: &KeyPath) -> bool {
+    tables.iter().any(|t| {
+        t.key.parts.len() > key.parts.len() && t.key.starts_with(key) &&
+        matches!(t.key.parts[key.parts.len()], KeyPathComponent::Ix(_))
+    })
+}
+
+/// Build the inline table text for a table and everything nested under it,
+/// collecting the comments which can't go inside it.
+fn inline_table_text(tokens: &[Token], tables: &[TablePos], key: &KeyPath, style: &Style,
+                     comments: &mut Vec<String>) -> Result<String, Error> {
+    let mut members = Vec::new();
+    for (i, t) in tables.iter().enumerate() {
+        if t.key != *key {
+            continue;
+        }
+        if i > 0 {
+            comments.extend(section_comments(tokens, tables, i));
+        }
+        for entry in find_entries(tokens, t) {
+            let key_text = text_of(&tokens[entry.key_start..entry.eq]);
+            let value = single_line_value(&tokens[entry.value_start..entry.value_end], key)?;
+            members.push(format!("{}{}={}{}", key_text.trim_end(), style.before_eq,
+                                 style.after_eq, value));
+        }
+    }
+    for name in direct_children(tables, key) {
+        let child = key.clone().append_key(name.clone());
+        let value = if is_table_array(tables, &child) {
+            let mut elements = Vec::new();
+            let mut ix = 0;
+            while tables.iter().any(|t| t.key == child.clone().append_index(ix)) {
+                let element = child.clone().append_index(ix);
+                elements.push(inline_table_text(tokens, tables, &element, style, comments)?);
+                ix += 1;
+            }
+            format!("[{}]", elements.join(", "))
+        } else {
+            inline_table_text(tokens, tables, &child, style, comments)?
+        };
+        members.push(format!("{}{}={}{}", make_key_token(&name).text, style.before_eq,
+                             style.after_eq, value));
+    }
+    Ok(wrap_inline_table(members, style))
+}
+
+/// Make sure a table exists so entries can be put in it.
+fn ensure_table(tokens: Vec<Token>, key: &KeyPath) -> Result<Vec<Token>, Error> {
+    if find_table(&tokens, key).is_some() {
+        return Ok(tokens);
+    }
+    if key.parts.iter().any(|p| matches!(*p, KeyPathComponent::Ix(_))) {
+        return Err(Error::NotRepresentable(key.clone(), String::from("no table to put the value in")));
+    }
+    Ok(insert_table(&tokens, key, &InsertOptions::default()))
+}
+
+/// Indices of the sections for a table and all the tables under it.
+fn subtree_sections(tables: &[TablePos], key: &KeyPath) -> Vec<usize> {
+    (1..tables.len()).filter(|&i| tables[i].key.starts_with(key)).collect()
+}
+
+fn find_entry(tokens: &[Token], table_key: &KeyPath, name: &str) -> Option<(TablePos, EntryPos)> {
+    let table = find_table(tokens, table_key)?;
+    let entry = find_entries(tokens, &table).into_iter()
+                    .find(|e| entry_key_parts(tokens, e) == vec![String::from(name)])?;
+    Some((table, entry))
+}
+
+fn body_line(style: &Style, key: &[Token], value: &[Token]) -> Vec<Token> {
+    let mut line = Vec::new();
+    if !style.indent.is_empty() {
+        line.push(Token::from(&style.indent));
+    }
+    line.extend_from_slice(key);
+    if !style.before_eq.is_empty() {
+        line.push(Token::from(&style.before_eq));
+    }
+    line.push(Token::from("="));
+    if !style.after_eq.is_empty() {
+        line.push(Token::from(&style.after_eq));
+    }
+    line.extend_from_slice(value);
+    line
+}
+
+fn inline_table_body(tokens: &[Token], open: usize, style: &Style) -> Vec<Vec<Token>> {
+    inline_members(tokens, open).into_iter().map(|(ks, ke, vs, ve)| {
+        body_line(style, &tokens[ks..ke], &tokens[vs..ve])
+    }).collect()
+}
+
+/// Turn a `[table]` section, and any tables under it, into an inline table
+/// in its parent.
+pub fn table_to_inline(tokens: &[Token], key: &KeyPath) -> Result<Vec<Token>, Error> {
+    let (parent, name) = split_key(key)?;
+    let tables = find_tables(tokens);
+    let sections = subtree_sections(&tables, key);
+    if sections.is_empty() {
+        return Err(Error::KeyNotFound(key.clone()));
+    }
+    if is_table_array(&tables, key) {
+        return Err(Error::WrongType(key.clone(), "table"));
+    }
+    let style = infer_style(tokens, &parent);
+    let mut comments = Vec::new();
+    let text = inline_table_text(tokens, &tables, key, &style, &mut comments)?;
+
+    let res = remove_sections(tokens, &tables, &sections);
+    let res = ensure_table(res, &parent)?;
+    let style = infer_style(&res, &parent);
+    Ok(insert_line(&res, &parent, &style, vec![make_key_token(&name)], tokenise_value(&text),
+                   &comments, &InsertPolicy::Append))
+}
+
+/// Turn an inline table value into a `[table]` section.
+pub fn inline_to_table(tokens: &[Token], key: &KeyPath) -> Result<Vec<Token>, Error> {
+    let (parent, name) = split_key(key)?;
+    let (table, entry) = match find_entry(tokens, &parent, &name) {
+        Some(found) => found,
+        None => return Err(Error::KeyNotFound(key.clone())),
+    };
+    if tokens[entry.value_start] != Token::from("{") {
+        return Err(Error::WrongType(key.clone(), "inline table"));
+    }
+    let style = infer_style(tokens, key);
+    let body = inline_table_body(tokens, entry.value_start, &style);
+    let comments = entry_comments(tokens, &table, &entry);
+    let res = remove_entry(tokens, &table, &entry);
+    Ok(insert_section(&res, key, false, &comments, body, &InsertPolicy::Append))
+}
+
+/// Turn `[[array]]` sections into an array of inline tables in their parent.
+pub fn tables_to_array(tokens: &[Token], key: &KeyPath) -> Result<Vec<Token>, Error> {
+    let (parent, name) = split_key(key)?;
+    let tables = find_tables(tokens);
+    if !is_table_array(&tables, key) {
+        return Err(Error::KeyNotFound(key.clone()));
+    }
+    let newline = detect_newline(tokens);
+    let style = infer_style(tokens, &parent);
+    let indent = format!("{}{}", style.indent, style.array_indent);
+    let mut text = String::from("[");
+    let mut ix = 0;
+    while tables.iter().any(|t| t.key == key.clone().append_index(ix)) {
+        let element = key.clone().append_index(ix);
+        let mut comments = Vec::new();
+        let element_text = inline_table_text(tokens, &tables, &element, &style, &mut comments)?;
+        for comment in comments {
+            text.push_str(&format!("{}{}{}", newline, indent, comment));
+        }
+        text.push_str(&format!("{}{}{},", newline, indent, element_text));
+        ix += 1;
+    }
+    text.push_str(&format!("{}{}]", newline, style.indent));
+
+    let res = remove_sections(tokens, &tables, &subtree_sections(&tables, key));
+    let res = ensure_table(res, &parent)?;
+    let style = infer_style(&res, &parent);
+    Ok(insert_line(&res, &parent, &style, vec![make_key_token(&name)], tokenise_value(&text),
+                   &[], &InsertPolicy::Append))
+}
+
+/// Turn an array of inline tables into `[[array]]` sections.
+pub fn array_to_tables(tokens: &[Token], key: &KeyPath) -> Result<Vec<Token>, Error> {
+    let (parent, name) = split_key(key)?;
+    let (table, entry) = match find_entry(tokens, &parent, &name) {
+        Some(found) => found,
+        None => return Err(Error::KeyNotFound(key.clone())),
+    };
+    if tokens[entry.value_start] != Token::from("[") {
+        return Err(Error::WrongType(key.clone(), "array"));
+    }
+    let style = infer_style(tokens, key);
+    // Gather the elements with the comments before each one
+    let mut elements = Vec::new();
+    let mut comments = entry_comments(tokens, &table, &entry);
+    let mut pos = entry.value_start + 1;
+    while pos < entry.value_end - 1 {
+        let tok = &tokens[pos];
+        match tok.kind {
+            TokenType::Comment => {
+                comments.push(tok.text.clone());
+                pos += 1;
+            },
+            TokenType::Whitespace | TokenType::Newline => pos += 1,
+            _ if *tok == Token::from(",") => pos += 1,
+            _ if *tok == Token::from("{") => {
+                elements.push((comments, inline_table_body(tokens, pos, &style)));
+                comments = Vec::new();
+                pos = skip_value(tokens, pos);
+            },
+            _ => return Err(Error::WrongType(key.clone(), "array of inline tables"))
+        }
+    }
+    let mut res = remove_entry(tokens, &table, &entry);
+    for (comments, body) in elements {
+        res = insert_section(&res, key, true, &comments, body, &InsertPolicy::Append);
+    }
+    Ok(res)
+}
+
+/// Turn a `[table]` section, and any tables under it, into dotted keys in its
+/// parent table.
+pub fn table_to_dotted(tokens: &[Token], key: &KeyPath) -> Result<Vec<Token>, Error> {
+    let (parent, _) = split_key(key)?;
+    let tables = find_tables(tokens);
+    let sections = subtree_sections(&tables, key);
+    if sections.is_empty() {
+        return Err(Error::KeyNotFound(key.clone()));
+    }
+    if sections.iter().any(|&i| tables[i].key.parts.iter().any(|p| matches!(*p, KeyPathComponent::Ix(_)))) {
+        return Err(Error::NotRepresentable(key.clone(), String::from("array of tables in dotted keys")));
+    }
+    // (comments, key tokens, value tokens) for each new line
+    let mut lines = Vec::new();
+    let mut pending_comments = Vec::new();
+    for &i in &sections {
+        let table = &tables[i];
+        let entries = find_entries(tokens, table);
+        let mut in_entries = Vec::new();
+        for entry in &entries {
+            in_entries.extend(entry_comment_positions(tokens, table, entry));
+        }
+        // Comments which go with an entry are moved with it
+        pending_comments.extend(section_comment_positions(tokens, &tables, i).into_iter()
+                                    .filter(|pos| !in_entries.contains(pos))
+                                    .map(|pos| tokens[pos].text.clone()));
+        let mut prefix = Vec::new();
+        for part in &table.key.parts[parent.parts.len()..] {
+            if let KeyPathComponent::Key(ref s) = *part {
+                prefix.push(make_key_token(s));
+                prefix.push(Token::from("."));
+            }
+        }
+        for entry in &entries {
+            let mut comments = pending_comments.clone();
+            pending_comments.clear();
+            comments.extend(entry_comments(tokens, table, entry));
+            let mut key_tokens = prefix.clone();
+            key_tokens.extend(trim_tokens(&tokens[entry.key_start..entry.eq]));
+            let value = tokens[entry.value_start..entry.value_end].to_vec();
+            lines.push((comments, key_tokens, value));
+        }
+    }
+    if lines.is_empty() {
+        return Err(Error::NotRepresentable(key.clone(), String::from("empty table")));
+    }
+
+    let mut res = remove_sections(tokens, &tables, &sections);
+    res = ensure_table(res, &parent)?;
+    let style = infer_style(&res, &parent);
+    for (comments, key_tokens, value) in lines {
+        res = insert_line(&res, &parent, &style, key_tokens, value, &comments, &InsertPolicy::Append);
+    }
+    Ok(res)
+}
+
+fn trim_tokens(tokens: &[Token]) -> Vec<Token> {
+    let mut res = tokens.to_vec();
+    while res.last().map(|t| t.kind == TokenType::Whitespace).unwrap_or(false) {
+        res.pop();
+    }
+    res
+}
+
+/// Gather the dotted keys starting with a name in a table into a `[table]`
+/// section of that name. If the section is already there, they're added to
+/// the end of it.
+pub fn dotted_to_table(tokens: &[Token], key: &KeyPath) -> Result<Vec<Token>, Error> {
+    let (parent, name) = split_key(key)?;
+    let table = match find_table(tokens, &parent) {
+        Some(t) => t,
+        None => return Err(Error::KeyNotFound(key.clone())),
+    };
+    let entries: Vec<EntryPos> = find_entries(tokens, &table).into_iter().filter(|e| {
+        let parts = entry_key_parts(tokens, e);
+        parts.len() > 1 && parts[0] == name
+    }).collect();
+    if entries.is_empty() {
+        return Err(Error::KeyNotFound(key.clone()));
+    }
+    let style = infer_style(tokens, key);
+    let mut lines = Vec::new();
+    for entry in &entries {
+        // Drop the first part of the key and the dot after it
+        let dot = (entry.key_start..entry.eq).find(|&p| tokens[p] == Token::from(".")).unwrap();
+        let mut key_start = dot + 1;
+        while tokens[key_start].kind == TokenType::Whitespace {
+            key_start += 1;
+        }
+        let key_tokens = trim_tokens(&tokens[key_start..entry.eq]);
+        let value = tokens[entry.value_start..entry.value_end].to_vec();
+        lines.push((entry_comments(tokens, &table, entry), key_tokens, value));
+    }
+    let mut res = tokens.to_vec();
+    for entry in entries.iter().rev() {
+        res = remove_entry(&res, &table, entry);
+    }
+    if find_table(&res, key).is_some() {
+        for (comments, key_tokens, value) in lines {
+            res = insert_line(&res, key, &style, key_tokens, value, &comments, &InsertPolicy::Append);
+        }
+        return Ok(res);
+    }
+    let mut body = Vec::new();
+    for (comments, key_tokens, value) in lines {
+        for comment in comments {
+            let mut line = Vec::new();
+            if !style.indent.is_empty() {
+                line.push(Token::from(&style.indent));
+            }
+            line.push(Token::from(&comment));
+            body.push(line);
+        }
+        body.push(body_line(&style, &key_tokens, &value));
+    }
+    Ok(insert_section(&res, key, false, &[], body, &InsertPolicy::Append))
+}
+
+#[cfg(test)]
+use super::tokenise::tokenise;
+
+#[cfg(test)]
+fn reshaped(f: fn(&[Token], &KeyPath) -> Result<Vec<Token>, Error>, inp: &str, key: &str) -> String {
+    text_of(&f(&tokenise(inp), &KeyPath::from_string(key)).unwrap())
+}
+
+#[test]
+fn test_table_inline_round_trip() {
+    let table = "\
+[package]
+name = \"foo\"
+
+# About metadata
+[package.metadata]
+a = 1
+b = [
+    \"x\",
+    \"y\",
+]
+
+[package.metadata.sub]
+c = true
+";
+    let inline = "\
+[package]
+name = \"foo\"
+# About metadata
+metadata = { a = 1, b = [\"x\", \"y\"], sub = { c = true } }
+";
+    assert_eq!(reshaped(table_to_inline, table, "package.metadata"), inline);
+    assert_eq!(reshaped(inline_to_table, inline, "package.metadata"), "\
+[package]
+name = \"foo\"
+
+# About metadata
+[package.metadata]
+a = 1
+b = [\"x\", \"y\"]
+sub = { c = true }
+");
+    let res = table_to_inline(&tokenise(inline), &KeyPath::from_string("package.metadata"));
+    assert_eq!(res, Err(Error::KeyNotFound(KeyPath::from_string("package.metadata"))));
+    let res = inline_to_table(&tokenise(inline), &KeyPath::from_string("package.name"));
+    assert_eq!(res, Err(Error::WrongType(KeyPath::from_string("package.name"), "inline table")));
+}
+
+#[test]
+fn test_array_of_tables_round_trip() {
+    let tables = "\
+name = \"foo\"
+
+# First
+[[bin]]
+name = \"a\"
+
+[[bin]]
+name = \"b\"
+";
+    let array = "\
+name = \"foo\"
+bin = [
+    # First
+    { name = \"a\" },
+    { name = \"b\" },
+]
+";
+    assert_eq!(reshaped(tables_to_array, tables, "bin"), array);
+    assert_eq!(reshaped(array_to_tables, array, "bin"), tables);
+}
+
+#[test]
+fn test_dotted_round_trip() {
+    let table = "\
+[a]
+x = 1
+
+[a.b]
+# Comment on y
+y = 2
+z = 3
+";
+    let dotted = "\
+[a]
+x = 1
+# Comment on y
+b.y = 2
+b.z = 3
+";
+    assert_eq!(reshaped(table_to_dotted, table, "a.b"), dotted);
+    assert_eq!(reshaped(dotted_to_table, dotted, "a.b"), "\
+[a]
+x = 1
+
+[a.b]
+# Comment on y
+y = 2
+z = 3
+");
+}
+
+#[test]
+fn test_dotted_to_existing_table() {
+    let inp = "\
+[a]
+x = 1
+# Comment on z
+b.z = 3
+
+[a.b]
+y = 2
+
+[c]
+";
+    assert_eq!(reshaped(dotted_to_table, inp, "a.b"), "\
+[a]
+x = 1
+
+[a.b]
+y = 2
+# Comment on z
+z = 3
+
+[c]
+");
+}
+
+#[test]
+fn test_table_to_dotted_repeated_comments() {
+    let inp = "\
+[a]
+x = 1
+
+# TODO
+[a.b]
+# TODO
+y = 2
+";
+    assert_eq!(reshaped(table_to_dotted, inp, "a.b"), "\
+[a]
+x = 1
+# TODO
+# TODO
+b.y = 2
+");
+}
@@ -0,0 +1,594 @@
+//! Check documents against a schema.
+//!
+//! A schema can be built in Rust, or read from JSON Schema. Only part of
+//! JSON Schema (draft 2020-12) is understood: `type`, `properties`,
+//! `required`, `additionalProperties`, `items`, `enum`, `const`, date and
+//! time `format`s, `default`, `description`, and `$ref` to `#/$defs/...` or
+//! `#/definitions/...`, as long as they aren't recursive. Other keywords are
+//! ignored.
+//!
+//! `Document::fill_defaults` uses a schema to add the keys it has defaults
+//! for, in the order the schema lists them.
+
+extern crate toml;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use rustc_serialize::json::{Json, JsonEvent, Parser, StackElement};
+
+use super::keypath::KeyPath;
+use super::error::Error;
+use super::document::Document;
+use super::tableix::{InsertOptions, InsertPolicy};
+use super::index::Span;
+use super::batch::json_to_toml;
+
+/// Deeper than this, `$ref`s are assumed to be recursive.
+const MAX_REF_DEPTH: usize = 32;
+
+/// The kinds of TOML value.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum SchemaType {
+    String,
+    Integer,
+    Float,
+    Boolean,
+    Datetime,
+    Array,
+    Table,
+}
+
+impl SchemaType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SchemaType::String => "string",
+            SchemaType::Integer => "integer",
+            SchemaType::Float => "float",
+            SchemaType::Boolean => "boolean",
+            SchemaType::Datetime => "datetime",
+            SchemaType::Array => "array",
+            SchemaType::Table => "table",
+        }
+    }
+
+    pub fn of(value: &toml::Value) -> SchemaType {
+        match *value {
+            toml::Value::String(_) => SchemaType::String,
+            toml::Value::Integer(_) => SchemaType::Integer,
+            toml::Value::Float(_) => SchemaType::Float,
+            toml::Value::Boolean(_) => SchemaType::Boolean,
+            toml::Value::Datetime(_) => SchemaType::Datetime,
+            toml::Value::Array(_) => SchemaType::Array,
+            toml::Value::Table(_) => SchemaType::Table,
+        }
+    }
+}
+
+/// What a table allows besides its listed properties.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub enum Additional {
+    /// Any other keys, with any values
+    #[default]
+    Any,
+    /// No other keys
+    Deny,
+    /// Other keys, with values matching this schema
+    Schema(Box<Schema>),
+}
+
+/// The values allowed at some point in a document. The default allows
+/// anything.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct Schema {
+    /// The kinds of value allowed, or `None` for any
+    pub types: Option<Vec<SchemaType>>,
+    /// For tables, the schemas of known keys, in the order they should be
+    /// written
+    pub properties: Vec<(String, Schema)>,
+    pub required: Vec<String>,
+    pub additional: Additional,
+    /// For arrays, the schema every item must match
+    pub items: Option<Box<Schema>>,
+    /// The only values allowed, if not empty
+    pub allowed: Vec<toml::Value>,
+    /// The value to fill in when the key is missing
+    pub default: Option<toml::Value>,
+    /// Written as comments above keys added by `fill_defaults`
+    pub description: Option<String>,
+}
+
+impl Schema {
+    /// A schema allowing one kind of value.
+    pub fn of_type(t: SchemaType) -> Schema {
+        Schema{types: Some(vec![t]), ..Schema::default()}
+    }
+
+    /// An array whose items all match a schema.
+    pub fn array_of(items: Schema) -> Schema {
+        Schema{items: Some(Box::new(items)), ..Schema::of_type(SchemaType::Array)}
+    }
+
+    /// Add a known key to a table schema, after those already added.
+    pub fn property(mut self, name: &str, schema: Schema) -> Schema {
+        self.properties.retain(|(k, _)| k != name);
+        self.properties.push((String::from(name), schema));
+        self
+    }
+
+    pub fn with_default(mut self, value: toml::Value) -> Schema {
+        self.default = Some(value);
+        self
+    }
+
+    pub fn with_description(mut self, text: &str) -> Schema {
+        self.description = Some(String::from(text));
+        self
+    }
+
+    /// The schema for a known key.
+    pub fn get_property(&self, name: &str) -> Option<&Schema> {
+        self.properties.iter().find(|(k, _)| k == name).map(|(_, s)| s)
+    }
+
+    /// Does this schema, or any below it, have a default?
+    fn has_defaults(&self) -> bool {
+        self.default.is_some() || self.properties.iter().any(|(_, s)| s.has_defaults())
+    }
+
+    /// Add a known key which must be present.
+    pub fn required_property(mut self, name: &str, schema: Schema) -> Schema {
+        self.required.push(String::from(name));
+        self.property(name, schema)
+    }
+
+    /// Report keys which aren't listed as properties.
+    pub fn deny_unknown(mut self) -> Schema {
+        self.additional = Additional::Deny;
+        self
+    }
+
+    /// Read a schema from JSON Schema text.
+    pub fn from_json(text: &str) -> Result<Schema, Error> {
+        let json = Json::from_str(text).map_err(|e| Error::InvalidInput(e.to_string()))?;
+        let reader = SchemaReader{root: &json, key_order: key_order(text)};
+        reader.read(&json, &[], 0)
+    }
+
+    /// Check a document against this schema, giving every problem found,
+    /// in document order.
+    pub fn validate(&self, doc: &Document) -> Vec<Violation> {
+        let mut res = Vec::new();
+        check(self, doc.values(), &KeyPath::new(), &mut res);
+        for v in &mut res {
+            v.span = doc.index().span_of(&v.key);
+        }
+        res.sort_by_key(|v| v.span.map(|s| s.start));
+        res
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::InvalidInput(format!("invalid schema: {}", msg))
+}
+
+/// The keys of every object in JSON text, in the order they're written,
+/// by the path to the object. `Json` keeps objects sorted, which would lose
+/// the order properties are listed in.
+fn key_order(text: &str) -> HashMap<Vec<String>, Vec<String>> {
+    let mut res: HashMap<Vec<String>, Vec<String>> = HashMap::new();
+    let mut parser = Parser::new(text.chars());
+    while let Some(event) = parser.next() {
+        if matches!(event, JsonEvent::ObjectEnd | JsonEvent::ArrayEnd | JsonEvent::Error(_)) {
+            continue;
+        }
+        let stack = parser.stack();
+        let path: Vec<String> = (0..stack.len()).map(|i| match stack.get(i) {
+            StackElement::Key(k) => String::from(k),
+            StackElement::Index(i) => i.to_string(),
+        }).collect();
+        if let Some(StackElement::Key(_)) = stack.top() {
+            let (key, parent) = path.split_last().unwrap();
+            res.entry(parent.to_vec()).or_default().push(key.clone());
+        }
+    }
+    res
+}
+
+fn path_to(parent: &[String], key: &str) -> Vec<String> {
+    let mut res = parent.to_vec();
+    res.push(String::from(key));
+    res
+}
+
+fn types_from_json(json: &Json, format: Option<&str>) -> Result<Vec<SchemaType>, Error> {
+    let names = match *json {
+        Json::String(ref s) => vec![s.as_str()],
+        Json::Array(ref items) => items.iter().map(|t| t.as_string().ok_or_else(|| invalid("type must be a string")))
+                                       .collect::<Result<_, _>>()?,
+        _ => return Err(invalid("type must be a string or a list")),
+    };
+    let date_format = matches!(format, Some("date-time") | Some("date") | Some("time") | Some("partial-time"));
+    let mut res = Vec::new();
+    for name in names {
+        match name {
+            "string" if date_format => res.extend([SchemaType::Datetime, SchemaType::String]),
+            "string" => res.push(SchemaType::String),
+            "integer" => res.push(SchemaType::Integer),
+            "number" => res.extend([SchemaType::Integer, SchemaType::Float]),
+            "boolean" => res.push(SchemaType::Boolean),
+            "array" => res.push(SchemaType::Array),
+            "object" => res.push(SchemaType::Table),
+            // TOML has no null
+            "null" => (),
+            other => return Err(invalid(&format!("unknown type {}", other))),
+        }
+    }
+    Ok(res)
+}
+
+struct SchemaReader<'a> {
+    root: &'a Json,
+    key_order: HashMap<Vec<String>, Vec<String>>,
+}
+
+impl<'a> SchemaReader<'a> {
+    /// Find what a `$ref` points to, and its path.
+    fn resolve(&self, reference: &str) -> Result<(&'a Json, Vec<String>), Error> {
+        let path = match reference.strip_prefix("#/") {
+            Some(path) => path,
+            None if reference == "#" => return Ok((self.root, Vec::new())),
+            None => return Err(invalid(&format!("can't follow $ref {}", reference))),
+        };
+        let parts: Vec<String> = path.split('/').map(|t| t.replace("~1", "/").replace("~0", "~")).collect();
+        let refs: Vec<&str> = parts.iter().map(|p| p.as_str()).collect();
+        match self.root.find_path(&refs) {
+            Some(json) => Ok((json, parts)),
+            None => Err(invalid(&format!("$ref {} not found", reference))),
+        }
+    }
+
+    /// Read the schema at a path in the JSON.
+    fn read(&self, json: &Json, path: &[String], depth: usize) -> Result<Schema, Error> {
+        if depth > MAX_REF_DEPTH {
+            return Err(invalid("$ref nested too deeply"));
+        }
+        let obj = match *json {
+            Json::Object(ref obj) => obj,
+            Json::Boolean(true) => return Ok(Schema::default()),
+            Json::Boolean(false) => return Ok(Schema{types: Some(Vec::new()), ..Schema::default()}),
+            _ => return Err(invalid("a schema must be an object or a boolean")),
+        };
+        if let Some(reference) = obj.get("$ref") {
+            let reference = reference.as_string().ok_or_else(|| invalid("$ref must be a string"))?;
+            let (target, target_path) = self.resolve(reference)?;
+            return self.read(target, &target_path, depth + 1);
+        }
+        let mut schema = Schema::default();
+        let format = obj.get("format").and_then(|f| f.as_string());
+        if let Some(types) = obj.get("type") {
+            schema.types = Some(types_from_json(types, format)?);
+        }
+        if let Some(props) = obj.get("properties") {
+            let props = props.as_object().ok_or_else(|| invalid("properties must be an object"))?;
+            let props_path = path_to(path, "properties");
+            let order = self.key_order.get(&props_path).cloned().unwrap_or_else(|| props.keys().cloned().collect());
+            for k in order {
+                if let Some(v) = props.get(&k) {
+                    schema = schema.property(&k, self.read(v, &path_to(&props_path, &k), depth)?);
+                }
+            }
+        }
+        if let Some(required) = obj.get("required") {
+            let required = required.as_array().ok_or_else(|| invalid("required must be a list"))?;
+            for name in required {
+                schema.required.push(String::from(name.as_string().ok_or_else(|| invalid("required must list strings"))?));
+            }
+        }
+        schema.additional = match obj.get("additionalProperties") {
+            None | Some(&Json::Boolean(true)) => Additional::Any,
+            Some(&Json::Boolean(false)) => Additional::Deny,
+            Some(other) => Additional::Schema(Box::new(self.read(other, &path_to(path, "additionalProperties"), depth)?)),
+        };
+        if let Some(items) = obj.get("items") {
+            schema.items = Some(Box::new(self.read(items, &path_to(path, "items"), depth)?));
+        }
+        let allowed = match (obj.get("enum"), obj.get("const")) {
+            (Some(values), _) => values.as_array().ok_or_else(|| invalid("enum must be a list"))?.clone(),
+            (None, Some(value)) => vec![value.clone()],
+            (None, None) => Vec::new(),
+        };
+        for value in allowed {
+            // A null can't match anything in TOML, so leave it out
+            if value.is_null() {
+                continue;
+            }
+            schema.allowed.push(json_to_toml(&value).map_err(|e| invalid(&e))?);
+        }
+        match obj.get("default") {
+            None | Some(&Json::Null) => (),
+            Some(value) => schema.default = Some(json_to_toml(value).map_err(|e| invalid(&e))?),
+        }
+        if let Some(description) = obj.get("description") {
+            let description = description.as_string().ok_or_else(|| invalid("description must be a string"))?;
+            schema.description = Some(String::from(description));
+        }
+        Ok(schema)
+    }
+}
+
+/// A description as comment lines.
+fn comment_lines(description: &Option<String>) -> Vec<String> {
+    match *description {
+        Some(ref text) => text.lines().map(|l| format!("# {}", l).trim_end().to_string()).collect(),
+        None => Vec::new(),
+    }
+}
+
+impl Document {
+    /// Add the keys a schema has defaults for which aren't in the document,
+    /// with their descriptions from the schema as comments above them.
+    /// Missing tables with defaults inside are added too. New keys go among
+    /// the keys already there in the order the schema lists them.
+    ///
+    /// If this fails, the document is left unchanged.
+    pub fn fill_defaults(&mut self, schema: &Schema) -> Result<(), Error> {
+        let mut working = self.clone();
+        working.fill_table(&KeyPath::new(), schema)?;
+        *self = working;
+        Ok(())
+    }
+
+    fn fill_table(&mut self, key: &KeyPath, schema: &Schema) -> Result<(), Error> {
+        for (i, (name, sub)) in schema.properties.iter().enumerate() {
+            let sub_key = key.clone().append_key(name.clone());
+            let items = match self.get(&sub_key) {
+                Some(toml::Value::Table(_)) => {
+                    self.fill_table(&sub_key, sub)?;
+                    continue;
+                },
+                Some(toml::Value::Array(items)) => items.len(),
+                Some(_) => continue,
+                None => {
+                    let allows_table = sub.types.as_ref().is_none_or(|t| t.contains(&SchemaType::Table));
+                    let value = match sub.default {
+                        Some(ref v) => v.clone(),
+                        None if allows_table && sub.has_defaults() => toml::Value::Table(toml::Table::new()),
+                        None => continue,
+                    };
+                    let options = InsertOptions{policy: self.schema_policy(key, schema, i), ..InsertOptions::default()};
+                    self.insert_commented(&sub_key, value, &comment_lines(&sub.description), &options)?;
+                    if let Some(toml::Value::Table(_)) = self.get(&sub_key) {
+                        self.fill_table(&sub_key, sub)?;
+                    }
+                    continue;
+                },
+            };
+            // Arrays of tables
+            if let Some(ref item_schema) = sub.items {
+                for j in 0..items {
+                    let item_key = sub_key.clone().append_index(j);
+                    if let Some(toml::Value::Table(_)) = self.get(&item_key) {
+                        self.fill_table(&item_key, item_schema)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Where to put the ith property of a table schema: after the nearest
+    /// property before it which is in the document, or else before the
+    /// nearest one after it.
+    fn schema_policy(&self, key: &KeyPath, schema: &Schema, i: usize) -> InsertPolicy {
+        let present = |&(name, _): &&(String, Schema)| self.get(&key.clone().append_key(name.clone())).is_some();
+        if let Some((name, _)) = schema.properties[..i].iter().rev().find(present) {
+            InsertPolicy::After(name.clone())
+        } else if let Some((name, _)) = schema.properties[i + 1..].iter().find(present) {
+            InsertPolicy::Before(name.clone())
+        } else {
+            InsertPolicy::Append
+        }
+    }
+}
+
+/// What's wrong with a value.
+#[derive(Debug,PartialEq,Clone)]
+pub enum ViolationKind {
+    /// The value isn't any of these kinds; if there are none, no value is
+    /// allowed there
+    WrongType(Vec<SchemaType>),
+    /// A table is missing a required key
+    MissingKey(String),
+    /// The key isn't one the schema knows
+    UnknownKey,
+    /// The value isn't one of the allowed values
+    NotAllowed,
+}
+
+/// A problem found by `Schema::validate`.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Violation {
+    /// The value with the problem; for missing keys, the table they're
+    /// missing from
+    pub key: KeyPath,
+    pub kind: ViolationKind,
+    /// Where the value is written, if it is
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = self.key.display_string();
+        match self.kind {
+            ViolationKind::WrongType(ref types) if types.is_empty() => write!(f, "no value allowed at {}", key),
+            ViolationKind::WrongType(ref types) => {
+                let names: Vec<&str> = types.iter().map(|t| t.name()).collect();
+                write!(f, "expected {} at {}", names.join(" or "), key)
+            },
+            ViolationKind::MissingKey(ref name) if key.is_empty() => write!(f, "missing key {}", name),
+            ViolationKind::MissingKey(ref name) => write!(f, "missing key {} in {}", name, key),
+            ViolationKind::UnknownKey => write!(f, "unknown key {}", key),
+            ViolationKind::NotAllowed => write!(f, "value not allowed at {}", key),
+        }
+    }
+}
+
+fn check(schema: &Schema, value: &toml::Value, key: &KeyPath, res: &mut Vec<Violation>) {
+    let violation = |kind| Violation{key: key.clone(), kind, span: None};
+    if let Some(ref types) = schema.types {
+        if !types.contains(&SchemaType::of(value)) {
+            res.push(violation(ViolationKind::WrongType(types.clone())));
+            return;
+        }
+    }
+    if !schema.allowed.is_empty() && !schema.allowed.contains(value) {
+        res.push(violation(ViolationKind::NotAllowed));
+    }
+    match *value {
+        toml::Value::Table(ref table) => {
+            for name in &schema.required {
+                if !table.contains_key(name) {
+                    res.push(violation(ViolationKind::MissingKey(name.clone())));
+                }
+            }
+            for (k, v) in table {
+                let sub_key = key.clone().append_key(k.clone());
+                match (schema.get_property(k), &schema.additional) {
+                    (Some(sub), _) => check(sub, v, &sub_key, res),
+                    (None, Additional::Schema(sub)) => check(sub, v, &sub_key, res),
+                    (None, Additional::Deny) => {
+                        res.push(Violation{key: sub_key, kind: ViolationKind::UnknownKey, span: None});
+                    },
+                    (None, Additional::Any) => (),
+                }
+            }
+        },
+        toml::Value::Array(ref items) => {
+            if let Some(ref sub) = schema.items {
+                for (i, item) in items.iter().enumerate() {
+                    check(sub, item, &key.clone().append_index(i), res);
+                }
+            }
+        },
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+fn messages(schema: &Schema, text: &str) -> Vec<String> {
+    let doc = Document::parse(text).unwrap();
+    schema.validate(&doc).iter().map(|v| v.to_string()).collect()
+}
+
+#[test]
+fn test_validate_rust_schema() {
+    let schema = Schema::of_type(SchemaType::Table)
+        .required_property("package", Schema::of_type(SchemaType::Table)
+            .required_property("name", Schema::of_type(SchemaType::String))
+            .property("edition", Schema{allowed: vec![toml::Value::String(String::from("2018")),
+                                                       toml::Value::String(String::from("2021"))],
+                                        ..Schema::of_type(SchemaType::String)})
+            .property("authors", Schema::array_of(Schema::of_type(SchemaType::String)))
+            .deny_unknown());
+    assert!(messages(&schema, "[package]\nname = \"x\"\nedition = \"2021\"\n").is_empty());
+    assert_eq!(messages(&schema, "[package]\nedition = \"2015\"\nauthors = [\"a\", 1]\nlicence = \"MIT\"\n"), vec![
+        "missing key name in package",
+        "value not allowed at package.edition",
+        "expected string at package.authors[1]",
+        "unknown key package.licence",
+    ]);
+    assert_eq!(messages(&schema, "package = 1\n"), vec!["expected table at package"]);
+    assert_eq!(messages(&schema, ""), vec!["missing key package"]);
+
+    // Spans point at where the value is written
+    let doc = Document::parse("[package]\nname = 1\n").unwrap();
+    let found = schema.validate(&doc);
+    assert_eq!(found[0].key, KeyPath::from_string("package.name"));
+    assert_eq!(found[0].span, Some(Span{start: 4, end: 9}));
+}
+
+#[test]
+fn test_validate_json_schema() {
+    let schema = Schema::from_json(r##"{
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "type": "object",
+        "properties": {
+            "port": {"type": "integer"},
+            "ratio": {"type": "number"},
+            "started": {"type": "string", "format": "date-time"},
+            "mode": {"enum": ["fast", "slow"]},
+            "servers": {"type": "array", "items": {"$ref": "#/$defs/server"}},
+            "env": {"type": "object", "additionalProperties": {"type": "string"}}
+        },
+        "required": ["port"],
+        "additionalProperties": false,
+        "$defs": {
+            "server": {"type": "object", "required": ["host"], "properties": {"host": {"type": "string"}}}
+        }
+    }"##).unwrap();
+    assert!(messages(&schema, "port = 80\nratio = 1\nstarted = 1979-05-27T07:32:00Z\nmode = \"fast\"\n").is_empty());
+    assert_eq!(messages(&schema, "port = \"80\"\nmode = \"medium\"\nextra = true\n[[servers]]\n[env]\nA = 1\n"), vec![
+        "expected integer at port",
+        "value not allowed at mode",
+        "unknown key extra",
+        "missing key host in servers[0]",
+        "expected string at env.A",
+    ]);
+    assert!(Schema::from_json(r#"{"type": "thing"}"#).is_err());
+    assert!(Schema::from_json(r##"{"$ref": "#/$defs/missing"}"##).is_err());
+    assert!(Schema::from_json(r##"{"$ref": "#"}"##).is_err());
+    let schema = Schema::from_json(r#"{"properties": {"old": false, "n": {"type": ["integer", "null"]}}}"#).unwrap();
+    assert_eq!(messages(&schema, "old = 1\nn = 1.5\n"), vec!["no value allowed at old", "expected integer at n"]);
+}
+
+#[test]
+fn test_fill_defaults() {
+    let schema = Schema::from_json(r#"{
+        "type": "object",
+        "properties": {
+            "name": {"type": "string", "description": "What to call it"},
+            "workers": {"type": "integer", "default": 4,
+                        "description": "How many threads to run.\nMore isn't always faster."},
+            "log": {"type": "string", "default": "info"},
+            "server": {
+                "type": "object",
+                "description": "Where to listen",
+                "properties": {"port": {"type": "integer", "default": 8080}, "host": {"default": "localhost"}}
+            },
+            "cache": {"type": "object", "properties": {"size": {"type": "integer"}}}
+        }
+    }"#).unwrap();
+    let names: Vec<&str> = schema.properties.iter().map(|(k, _)| k.as_str()).collect();
+    assert_eq!(names, vec!["name", "workers", "log", "server", "cache"]);
+
+    let mut doc = Document::parse("\n").unwrap();
+    doc.fill_defaults(&schema).unwrap();
+    assert_eq!(doc.to_string(), "\
+# How many threads to run.
+# More isn't always faster.
+workers = 4
+log = \"info\"
+
+# Where to listen
+[server]
+port = 8080
+host = \"localhost\"
+");
+    assert!(schema.validate(&doc).is_empty());
+
+    // Missing keys go among those already there, in the schema's order
+    let mut doc = Document::parse("name = \"x\"\nlog = \"debug\"\n\n[server]\nhost = \"::\"\n").unwrap();
+    doc.fill_defaults(&schema).unwrap();
+    assert_eq!(doc.to_string(), "\
+name = \"x\"
+# How many threads to run.
+# More isn't always faster.
+workers = 4
+log = \"debug\"
+
+[server]
+port = 8080
+host = \"::\"
+");
+}
@@ -0,0 +1,369 @@
+extern crate toml;
+
+use std::collections::HashMap;
+
+use super::tokenise::{Token, TokenType, text_of};
+use super::keypath::KeyPath;
+use super::tableix::{find_tables, find_entries, make_key_token, TablePos, EntryPos};
+
+/// Formatting conventions used when writing new entries into a document.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Style {
+    /// Whitespace before keys in the table
+    pub indent: String,
+    /// Whitespace between a key and `=`
+    pub before_eq: String,
+    /// Whitespace between `=` and a value
+    pub after_eq: String,
+    /// Column (counted in characters from the start of the line) at which
+    /// the `=` signs of the table line up, if they do
+    pub align_eq: Option<usize>,
+    /// Write inline tables as `{ a = 1 }` rather than `{a = 1}`
+    pub inline_table_padding: bool,
+    /// Put each array element on its own line
+    pub array_multiline: bool,
+    /// Extra indentation for elements of multiline arrays
+    pub array_indent: String,
+    /// Put a comma after the last element of multiline arrays
+    pub array_trailing_comma: bool,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            indent: String::new(),
+            before_eq: String::from(" "),
+            after_eq: String::from(" "),
+            align_eq: None,
+            inline_table_padding: true,
+            array_multiline: false,
+            array_indent: String::from("    "),
+            array_trailing_comma: true,
+        }
+    }
+}
+
+/// Explicit choices which take precedence over the inferred `Style`.
+///
+/// Fields left as `None` are inferred from the document.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct StyleOverride {
+    pub indent: Option<String>,
+    pub before_eq: Option<String>,
+    pub after_eq: Option<String>,
+    pub align_eq: Option<Option<usize>>,
+    pub inline_table_padding: Option<bool>,
+    pub array_multiline: Option<bool>,
+    pub array_indent: Option<String>,
+    pub array_trailing_comma: Option<bool>,
+}
+
+impl Style {
+    pub fn with_overrides(mut self, overrides: &StyleOverride) -> Style {
+        if let Some(ref v) = overrides.indent { self.indent = v.clone(); }
+        if let Some(ref v) = overrides.before_eq { self.before_eq = v.clone(); }
+        if let Some(ref v) = overrides.after_eq { self.after_eq = v.clone(); }
+        if let Some(v) = overrides.align_eq { self.align_eq = v; }
+        if let Some(v) = overrides.inline_table_padding { self.inline_table_padding = v; }
+        if let Some(v) = overrides.array_multiline { self.array_multiline = v; }
+        if let Some(ref v) = overrides.array_indent { self.array_indent = v.clone(); }
+        if let Some(v) = overrides.array_trailing_comma { self.array_trailing_comma = v; }
+        self
+    }
+}
+
+fn most_common(values: Vec<String>) -> Option<String> {
+    let mut counts = HashMap::new();
+    let mut order = Vec::new();
+    for v in values {
+        let count = counts.entry(v.clone()).or_insert(0);
+        if *count == 0 {
+            order.push(v);
+        }
+        *count += 1;
+    }
+    // Ties go to whichever value appeared first
+    let mut best: Option<String> = None;
+    for v in order {
+        best = match best {
+            Some(b) if counts[&b] >= counts[&v] => Some(b),
+            _ => Some(v),
+        };
+    }
+    best
+}
+
+fn entry_indent(tokens: &[Token], entry: &EntryPos) -> String {
+    text_of(&tokens[entry.start..entry.key_start])
+}
+
+fn eq_column(tokens: &[Token], entry: &EntryPos) -> usize {
+    text_of(&tokens[entry.start..entry.eq]).chars().count()
+}
+
+fn key_width(tokens: &[Token], entry: &EntryPos) -> usize {
+    let key = text_of(&tokens[entry.key_start..entry.eq]);
+    key.trim_end().chars().count()
+}
+
+fn align_column(tokens: &[Token], entries: &[EntryPos]) -> Option<usize> {
+    if entries.len() < 2 {
+        return None;
+    }
+    let col = eq_column(tokens, &entries[0]);
+    let first_width = key_width(tokens, &entries[0]);
+    if entries.iter().any(|e| eq_column(tokens, e) != col) {
+        return None;
+    }
+    // If every key is the same width, equal columns say nothing about alignment
+    if entries.iter().all(|e| key_width(tokens, e) == first_width) {
+        return None;
+    }
+    Some(col)
+}
+
+fn infer_array_style(tokens: &[Token], entries: &[EntryPos], style: &mut Style) {
+    for entry in entries {
+        if tokens[entry.value_start] != Token::from("[") {
+            continue;
+        }
+        let value = &tokens[entry.value_start..entry.value_end];
+        let newline_pos = match value.iter().position(|t| t.kind == TokenType::Newline) {
+            Some(p) => p,
+            None => {
+                style.array_multiline = false;
+                return;
+            }
+        };
+        style.array_multiline = true;
+        if let Some(ws) = value.get(newline_pos + 1) {
+            if ws.kind == TokenType::Whitespace {
+                let base = entry_indent(tokens, entry);
+                style.array_indent = if ws.text.starts_with(&base) {
+                    String::from(&ws.text[base.len()..])
+                } else {
+                    ws.text.clone()
+                };
+            }
+        }
+        let last = value[..value.len() - 1].iter().rev().find(|t| {
+            !matches!(t.kind, TokenType::Whitespace | TokenType::Newline | TokenType::Comment)
+        });
+        style.array_trailing_comma = last == Some(&Token::from(","));
+        return;
+    }
+}
+
+fn infer_inline_table_style(tokens: &[Token], style: &mut Style) {
+    for (i, tok) in tokens.iter().enumerate() {
+        if *tok == Token::from("{") {
+            if let Some(next) = tokens.get(i + 1) {
+                if *next == Token::from("}") {
+                    continue;
+                }
+                style.inline_table_padding = next.kind == TokenType::Whitespace;
+                return;
+            }
+        }
+    }
+}
+
+/// Work out how new entries in the given table should be formatted.
+///
+/// Indentation and alignment come from the table itself, falling back to
+/// other tables at the same depth. Spacing around `=`, inline table padding
+/// and array layout are taken from the whole document.
+pub fn infer_style(tokens: &[Token], table: &KeyPath) -> Style {
+    let mut style = Style::default();
+    let tables = find_tables(tokens);
+    let mut all_entries = Vec::new();
+    let mut own_entries = Vec::new();
+    let mut sibling_indents = Vec::new();
+    for t in &tables {
+        let entries = find_entries(tokens, t);
+        if t.key == *table {
+            own_entries = entries.clone();
+        } else if same_depth(t, table) {
+            for e in &entries {
+                sibling_indents.push(entry_indent(tokens, e));
+            }
+        }
+        all_entries.extend(entries);
+    }
+
+    let own_indents = own_entries.iter().map(|e| entry_indent(tokens, e)).collect();
+    if let Some(indent) = most_common(own_indents).or_else(|| most_common(sibling_indents)) {
+        style.indent = indent;
+    }
+
+    // Aligned entries don't tell us the preferred spacing before '='
+    let spacing_entries: Vec<&EntryPos> = if align_column(tokens, &own_entries).is_some() {
+        all_entries.iter().filter(|e| !own_entries.contains(e)).collect()
+    } else {
+        all_entries.iter().collect()
+    };
+    let before = spacing_entries.iter().map(|e| {
+        let key = text_of(&tokens[e.key_start..e.eq]);
+        String::from(&key[key.trim_end().len()..])
+    }).collect();
+    if let Some(before) = most_common(before) {
+        style.before_eq = before;
+    }
+    let after = all_entries.iter().map(|e| text_of(&tokens[e.eq + 1..e.value_start])).collect();
+    if let Some(after) = most_common(after) {
+        style.after_eq = after;
+    }
+
+    style.align_eq = align_column(tokens, &own_entries);
+    infer_inline_table_style(tokens, &mut style);
+    infer_array_style(tokens, &all_entries, &mut style);
+    style
+}
+
+fn same_depth(t: &TablePos, table: &KeyPath) -> bool {
+    !t.key.parts.is_empty() && t.key.parts.len() == table.parts.len()
+}
+
+/// The whitespace to put between a key and `=`, taking alignment into account.
+pub fn padding_before_eq(style: &Style, key: &str) -> String {
+    match style.align_eq {
+        Some(col) => {
+            let used = style.indent.chars().count() + key.chars().count();
+            let pad = if col > used { col - used } else { 1 };
+            " ".repeat(pad)
+        },
+        None => style.before_eq.clone()
+    }
+}
+
+fn format_inline_value(value: &toml::Value, style: &Style) -> String {
+    match *value {
+        toml::Value::Array(ref items) => {
+            let parts: Vec<String> = items.iter().map(|v| format_inline_value(v, style)).collect();
+            format!("[{}]", parts.join(", "))
+        },
+        toml::Value::Table(ref table) => {
+            if table.is_empty() {
+                return String::from("{}");
+            }
+            let parts: Vec<String> = table.iter().map(|(k, v)| {
+                format!("{}{}={}{}", make_key_token(k).text, style.before_eq,
+                        style.after_eq, format_inline_value(v, style))
+            }).collect();
+            if style.inline_table_padding {
+                format!("{{ {} }}", parts.join(", "))
+            } else {
+                format!("{{{}}}", parts.join(", "))
+            }
+        },
+        _ => value.to_string()
+    }
+}
+
+/// A string with line breaks in it as a multiline basic string, with its
+/// lines broken as they are in the string.
+fn format_multiline_string(s: &str, newline: &str) -> String {
+    let mut res = format!("\"\"\"{}", newline);
+    let mut quotes = 0;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        quotes = if c == '"' { quotes + 1 } else { 0 };
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str(newline),
+            // Line endings in the text read back as LF, so a CR has to be
+            // escaped to keep it
+            '\r' => res.push_str("\\r"),
+            // Three quotes in a row would end the string
+            '"' if quotes == 3 || (chars.peek().is_none() && quotes > 0) => {
+                res.push_str("\\\"");
+                quotes = 0;
+            },
+            '\t' => res.push('\t'),
+            c if c.is_control() => res.push_str(&format!("\\u{:04X}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push_str("\"\"\"");
+    res
+}
+
+/// Write a value as it should appear on the right of `=`. Strings with line
+/// breaks are written as multiline strings, unless `newline` is empty.
+pub fn format_value(value: &toml::Value, style: &Style, newline: &str) -> String {
+    match *value {
+        toml::Value::String(ref s) if s.contains('\n') && !newline.is_empty() => {
+            format_multiline_string(s, newline)
+        },
+        toml::Value::Array(ref items) if style.array_multiline && !items.is_empty() => {
+            let mut res = String::from("[");
+            for (i, item) in items.iter().enumerate() {
+                res.push_str(newline);
+                res.push_str(&style.indent);
+                res.push_str(&style.array_indent);
+                res.push_str(&format_inline_value(item, style));
+                if i + 1 < items.len() || style.array_trailing_comma {
+                    res.push(',');
+                }
+            }
+            res.push_str(newline);
+            res.push_str(&style.indent);
+            res.push(']');
+            res
+        },
+        _ => format_inline_value(value, style)
+    }
+}
+
+#[cfg(test)]
+use super::tokenise::tokenise;
+#[cfg(test)]
+use super::decode::decode_string;
+
+#[test]
+fn test_infer_style_indent_and_alignment() {
+    let tokens = tokenise("[a]\n    x     = 1\n    long  = 2\n[b]\n    y=3\n");
+    let style = infer_style(&tokens, &KeyPath::from_string("a"));
+    assert_eq!(style.indent, "    ");
+    assert_eq!(style.align_eq, Some(10));
+    assert_eq!(style.before_eq, "");
+    assert_eq!(padding_before_eq(&style, "new"), "   ");
+
+    // An empty table takes its indentation from its siblings
+    let tokens = tokenise("[a]\n  x = 1\n[b]\n");
+    let style = infer_style(&tokens, &KeyPath::from_string("b"));
+    assert_eq!(style.indent, "  ");
+    assert_eq!(style.align_eq, None);
+}
+
+#[test]
+fn test_infer_style_values() {
+    let tokens = tokenise("a = {b=1}\nc = [\n  1,\n  2\n]\n");
+    let style = infer_style(&tokens, &KeyPath::new());
+    assert!(!style.inline_table_padding);
+    assert!(style.array_multiline);
+    assert_eq!(style.array_indent, "  ");
+    assert!(!style.array_trailing_comma);
+    let value = toml::Value::Array(vec![toml::Value::Integer(1), toml::Value::Integer(2)]);
+    assert_eq!(format_value(&value, &style, "\n"), "[\n  1,\n  2\n]");
+
+    let style = style.with_overrides(&StyleOverride{array_multiline: Some(false), ..Default::default()});
+    assert_eq!(format_value(&value, &style, "\n"), "[1, 2]");
+}
+
+#[test]
+fn test_format_multiline_string() {
+    let style = Style::default();
+    let value = toml::Value::String(String::from("a\n\\b \"\"\"\n\u{7}\""));
+    let text = format_value(&value, &style, "\n");
+    assert_eq!(text, "\"\"\"\na\n\\\\b \"\"\\\"\n\\u0007\\\"\"\"\"");
+    assert_eq!(decode_string(&tokenise(&text)[0]), Some(String::from("a\n\\b \"\"\"\n\u{7}\"")));
+
+    // CRLF files get CRLF line breaks in the string, but the value is the same
+    let text = format_value(&value, &style, "\r\n");
+    assert!(text.starts_with("\"\"\"\r\na\r\n"));
+    assert_eq!(decode_string(&tokenise(&text)[0]), Some(String::from("a\n\\b \"\"\"\n\u{7}\"")));
+
+    // Inside inline tables, where there's no newline to use, they stay on one line
+    assert_eq!(format_value(&value, &style, ""), value.to_string());
+}
@@ -2,19 +2,143 @@ extern crate toml;
 
 use std::collections::HashMap;
 
-use super::tokenise::{Token, TokenType, tokenise};
+use super::tokenise::{Token, TokenType, detect_newline, tokenise_value, text_of};
+#[cfg(test)]
+use super::tokenise::tokenise;
 use super::keypath::{KeyPath, KeyPathComponent};
+use super::key_token_to_string;
+use super::style::{Style, StyleOverride, infer_style, format_value, padding_before_eq};
 
 // use std::boxed::Box;
 
 #[derive(Debug,PartialEq)]
 pub struct TablePos {
-    key: KeyPath,
-    start: usize,
-    end: usize,
+    pub key: KeyPath,
+    pub start: usize,
+    pub end: usize,
 }
 
-fn read_table_name(tokens: &Vec<Token>, pos: usize, table_arrays: &mut HashMap<KeyPath, usize>) -> (KeyPath, usize) {
+/// Token positions of a `key = value` line inside a table section.
+#[derive(Debug,PartialEq,Clone)]
+pub struct EntryPos {
+    /// First token of the line, including any indentation
+    pub start: usize,
+    pub key_start: usize,
+    pub eq: usize,
+    pub value_start: usize,
+    /// One past the last token of the value
+    pub value_end: usize,
+    /// One past the newline ending the entry, or the end of the table
+    pub end: usize,
+}
+
+fn is_insignificant(tok: &Token) -> bool {
+    matches!(tok.kind, TokenType::Whitespace | TokenType::Newline | TokenType::Comment)
+}
+
+/// Find the end of the value starting at pos, skipping over nested arrays and
+/// inline tables.
+pub fn skip_value(tokens: &[Token], pos: usize) -> usize {
+    if tokens[pos].kind != TokenType::Punctuation {
+        return pos + 1;
+    }
+    let mut depth = 0;
+    for (i, tok) in tokens.iter().enumerate().skip(pos) {
+        if tok.kind != TokenType::Punctuation {
+            continue;
+        }
+        match tok.text.as_str() {
+            "[" | "{" => depth += 1,
+            "]" | "}" => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            },
+            _ => ()
+        }
+    }
+    tokens.len()
+}
+
+/// The `key = value` members of an inline table starting at `open`, as ranges
+/// of (key start, key end, value start, value end).
+pub fn inline_members(tokens: &[Token], open: usize) -> Vec<(usize, usize, usize, usize)> {
+    let close = skip_value(tokens, open) - 1;
+    let mut res = Vec::new();
+    let mut pos = open + 1;
+    while pos < close {
+        let tok = &tokens[pos];
+        if tok.kind == TokenType::Whitespace || *tok == Token::from(",") {
+            pos += 1;
+            continue;
+        }
+        let key_start = pos;
+        while tokens[pos] != Token::from("=") {
+            pos += 1;
+        }
+        let mut key_end = pos;
+        while tokens[key_end-1].kind == TokenType::Whitespace {
+            key_end -= 1;
+        }
+        pos += 1;
+        while tokens[pos].kind == TokenType::Whitespace {
+            pos += 1;
+        }
+        let value_end = skip_value(tokens, pos);
+        res.push((key_start, key_end, pos, value_end));
+        pos = value_end;
+    }
+    res
+}
+
+/// Find the key/value lines directly inside a table section.
+pub fn find_entries(tokens: &[Token], table: &TablePos) -> Vec<EntryPos> {
+    let mut res = Vec::new();
+    let mut line_start = table.start;
+    let mut pos = table.start;
+    while pos < table.end {
+        let tok = &tokens[pos];
+        if tok.kind == TokenType::Newline {
+            line_start = pos + 1;
+        }
+        if is_insignificant(tok) {
+            pos += 1;
+            continue;
+        }
+        // Read a (possibly dotted) key up to the '='
+        let key_start = pos;
+        while pos < table.end && tokens[pos] != Token::from("=") {
+            pos += 1;
+        }
+        if pos >= table.end {
+            break;
+        }
+        let eq = pos;
+        pos += 1;
+        while pos < table.end && tokens[pos].kind == TokenType::Whitespace {
+            pos += 1;
+        }
+        if pos >= table.end {
+            break;
+        }
+        let value_start = pos;
+        let value_end = skip_value(tokens, value_start);
+        // The entry runs on to the end of the line, including any comment
+        pos = value_end;
+        while pos < table.end && tokens[pos].kind != TokenType::Newline {
+            pos += 1;
+        }
+        if pos < table.end {
+            pos += 1;
+        }
+        res.push(EntryPos{start: line_start, key_start, eq, value_start, value_end, end: pos});
+        line_start = pos;
+    }
+    res
+}
+
+fn read_table_name(tokens: &[Token], pos: usize, table_arrays: &mut HashMap<KeyPath, usize>) -> (KeyPath, usize) {
     let mut my_pos = pos+1;
     let table_in_array = tokens[my_pos] == Token::from("[");
     if table_in_array {
@@ -48,7 +172,7 @@ fn read_table_name(tokens: &Vec<Token>, pos: usize, table_arrays: &mut HashMap<K
     }
 }
 
-pub fn find_tables(tokens: &Vec<Token>) -> Vec<TablePos> {
+pub fn find_tables(tokens: &[Token]) -> Vec<TablePos> {
     let mut res = Vec::new();
     let mut table_arrays = HashMap::new();
     let mut prev_table = (KeyPath::new(), 0);
@@ -56,7 +180,7 @@ pub fn find_tables(tokens: &Vec<Token>) -> Vec<TablePos> {
     let mut array_depth = 0;
     let mut pos = 0;
     while pos < tokens.len()  {
-        let ref tok = tokens[pos];
+        let tok = &tokens[pos];
         match tok.kind {
             TokenType::Whitespace | TokenType::Newline | TokenType::Comment => {
                 pos += 1;
@@ -67,9 +191,11 @@ pub fn find_tables(tokens: &Vec<Token>) -> Vec<TablePos> {
         if *tok == Token::from("[") {
             if *prev_token == Token::from("=") || array_depth > 0 {
                 array_depth += 1;
+                prev_token = tok;
+                pos += 1;
             } else {
                 // New table
-                let (new_key, new_start) = read_table_name(&tokens, pos, &mut table_arrays);
+                let (new_key, new_start) = read_table_name(tokens, pos, &mut table_arrays);
                 let (prev_key, prev_start) = prev_table;
                 res.push(TablePos{key: prev_key, start: prev_start, end: pos});
                 prev_table = (new_key, new_start);
@@ -91,7 +217,7 @@ pub fn find_tables(tokens: &Vec<Token>) -> Vec<TablePos> {
     res
 }
 
-pub fn find_table(tokens: &Vec<Token>, key: &KeyPath) -> Option<TablePos> {
+pub fn find_table(tokens: &[Token], key: &KeyPath) -> Option<TablePos> {
     for candidate in find_tables(tokens) {
         if candidate.key == *key {
             return Some(candidate)
@@ -100,11 +226,8 @@ pub fn find_table(tokens: &Vec<Token>, key: &KeyPath) -> Option<TablePos> {
     None
 }
 
-fn make_key_token(key: &str) -> Token {
-    if key.chars().any(|c| { match c {
-        'a'...'z' | 'A'...'Z' | '-' | '_' => false,
-        _ => true
-    }}) {
+pub fn make_key_token(key: &str) -> Token {
+    if key.is_empty() || key.chars().any(|c| !matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_')) {
         // Key needs quoting
         let val = toml::Value::String(String::from(key));
         Token{kind: TokenType::BasicString, text: val.to_string()}
@@ -114,39 +237,494 @@ fn make_key_token(key: &str) -> Token {
     }
 }
 
-pub fn insert_kv(tokens: &Vec<Token>, key: &KeyPath, value: toml::Value) -> Vec<Token> {
-    let table_pos = find_table(tokens, &key.parent().unwrap()).unwrap();
-    // Find insertion point
-    let mut pos = table_pos.end - 1;
-    while pos >= table_pos.start && (tokens[pos].kind == TokenType::Whitespace ||
-                                     tokens[pos].kind == TokenType::Newline) {
-        pos -= 1;
+/// Where new keys and tables go among their siblings.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub enum InsertPolicy {
+    /// After everything else in the table
+    #[default]
+    Append,
+    /// In alphabetical order, if the existing siblings are already sorted;
+    /// otherwise append.
+    Alphabetical,
+    /// Directly after the named sibling, or append if it's not there
+    After(String),
+    /// Directly before the named sibling (and its comments), or append if
+    /// it's not there
+    Before(String),
+}
+
+/// For each token, whether it's part of a key: in a table header, or on the
+/// left of `=`.
+pub fn key_positions(tokens: &[Token]) -> Vec<bool> {
+    let mut res = vec![false; tokens.len()];
+    let mut in_header = false;
+    let mut at_line_start = true;
+    let mut depth = 0;
+    for (i, tok) in tokens.iter().enumerate() {
+        match tok.kind {
+            TokenType::Newline => {
+                in_header = false;
+                at_line_start = depth == 0;
+                continue;
+            },
+            TokenType::Whitespace | TokenType::Comment => continue,
+            _ => ()
+        }
+        let punctuation = tok.kind == TokenType::Punctuation;
+        if at_line_start && *tok == Token::from("[") {
+            in_header = true;
+        } else if in_header {
+            res[i] = !punctuation;
+        } else if punctuation {
+            match tok.text.as_str() {
+                "[" | "{" => depth += 1,
+                "]" | "}" => depth -= 1,
+                _ => ()
+            }
+        } else {
+            let next = tokens[i+1..].iter().find(|t| t.kind != TokenType::Whitespace);
+            res[i] = next == Some(&Token::from("=")) || next == Some(&Token::from("."));
+        }
+        at_line_start = false;
     }
-    pos += 1; // Cut just after the non-whitespace token we found
+    res
+}
 
+/// Options controlling how new entries are written.
+#[derive(Debug,PartialEq,Clone,Default)]
+pub struct InsertOptions {
+    /// Formatting choices to use instead of those inferred from the document
+    pub style: StyleOverride,
+    pub policy: InsertPolicy,
+}
+
+/// Where to put a new line of tokens.
+enum Slot {
+    /// On a new line after the token before this position
+    After(usize),
+    /// At the start of the line starting at this position
+    LineStart(usize),
+}
+
+fn splice_line(tokens: &[Token], slot: Slot, line: Vec<Token>, newline: &str) -> Vec<Token> {
     let mut res = Vec::new();
-    // Copy tokens before insertion point
-    for tok in &tokens[..pos] {
-        res.push((*tok).clone());
+    match slot {
+        Slot::After(pos) => {
+            res.extend_from_slice(&tokens[..pos]);
+            if pos > 0 {
+                res.push(Token::from(newline));
+            }
+            res.extend(line);
+            if pos < tokens.len() && tokens[pos].kind != TokenType::Newline {
+                res.push(Token::from(newline));
+            }
+            res.extend_from_slice(&tokens[pos..]);
+        },
+        Slot::LineStart(pos) => {
+            res.extend_from_slice(&tokens[..pos]);
+            res.extend(line);
+            res.push(Token::from(newline));
+            res.extend_from_slice(&tokens[pos..]);
+        }
+    }
+    res
+}
+
+/// Position just after the last thing in a table other than blank space.
+fn content_end(tokens: &[Token], table: &TablePos) -> usize {
+    let mut pos = table.end;
+    while pos > table.start && (tokens[pos-1].kind == TokenType::Whitespace ||
+                                tokens[pos-1].kind == TokenType::Newline) {
+        pos -= 1;
     }
-    // Insert new key-value pair
-    res.push(Token::from("\n"));
+    pos
+}
+
+/// Walk back from the start of a line to include comment lines directly above
+/// it, which are taken to describe it.
+fn attached_start(tokens: &[Token], line_start: usize, floor: usize) -> usize {
+    let mut start = line_start;
+    while start > floor && tokens[start-1].kind == TokenType::Newline {
+        let mut prev_start = start - 1;
+        while prev_start > floor && tokens[prev_start-1].kind != TokenType::Newline {
+            prev_start -= 1;
+        }
+        let mut significant = tokens[prev_start..start-1].iter()
+                                .filter(|t| t.kind != TokenType::Whitespace);
+        match (significant.next(), significant.next()) {
+            (Some(tok), None) if tok.kind == TokenType::Comment => start = prev_start,
+            _ => break
+        }
+    }
+    start
+}
+
+/// The parts of an entry's key; there's more than one for dotted keys.
+pub fn entry_key_parts(tokens: &[Token], entry: &EntryPos) -> Vec<String> {
+    tokens[entry.key_start..entry.eq].iter()
+        .filter(|t| !is_insignificant(t) && t.text != ".")
+        .map(key_token_to_string)
+        .collect()
+}
+
+/// The (possibly dotted) key of an entry, with dots joining the parts.
+pub fn entry_key(tokens: &[Token], entry: &EntryPos) -> String {
+    entry_key_parts(tokens, entry).join(".")
+}
+
+fn is_sorted(names: &[String]) -> bool {
+    names.windows(2).all(|w| w[0] <= w[1])
+}
+
+fn kv_slot(tokens: &[Token], table: &TablePos, name: &str, policy: &InsertPolicy) -> Slot {
+    let entries = find_entries(tokens, table);
+    let names: Vec<String> = entries.iter().map(|e| entry_key(tokens, e)).collect();
+    let append = Slot::After(content_end(tokens, table));
+    match *policy {
+        InsertPolicy::Append => append,
+        InsertPolicy::Alphabetical => {
+            if !is_sorted(&names) {
+                return append;
+            }
+            match names.iter().position(|n| n.as_str() > name) {
+                Some(i) => Slot::LineStart(attached_start(tokens, entries[i].start, table.start)),
+                None => append,
+            }
+        },
+        InsertPolicy::After(ref sibling) => {
+            match names.iter().position(|n| n == sibling) {
+                Some(i) => {
+                    let entry = &entries[i];
+                    if tokens[entry.end-1].kind == TokenType::Newline {
+                        Slot::LineStart(entry.end)
+                    } else {
+                        Slot::After(entry.end)
+                    }
+                },
+                None => append,
+            }
+        },
+        InsertPolicy::Before(ref sibling) => {
+            match names.iter().position(|n| n == sibling) {
+                Some(i) => Slot::LineStart(attached_start(tokens, entries[i].start, table.start)),
+                None => append,
+            }
+        },
+    }
+}
+
+pub fn insert_kv(tokens: &[Token], key: &KeyPath, value: toml::Value) -> Vec<Token> {
+    insert_kv_with(tokens, key, value, &InsertOptions::default())
+}
+
+pub fn insert_kv_with(tokens: &[Token], key: &KeyPath, value: toml::Value,
+                      options: &InsertOptions) -> Vec<Token> {
+    let table_key = key.parent().unwrap();
     let key_tail = match key.parts.last().unwrap() {
-        &KeyPathComponent::Key(ref s) => s,
+        KeyPathComponent::Key(s) => s,
         _ => panic!("Key must end with a string part")
     };
-    res.push(make_key_token(&key_tail));
-    for tok in [" ", "=", " "].iter() {
-        res.push(Token::from(tok));
+    let newline = detect_newline(tokens);
+    let style = infer_style(tokens, &table_key).with_overrides(&options.style);
+    let value_tokens = tokenise_value(&format_value(&value, &style, newline));
+    insert_line(tokens, &table_key, &style, vec![make_key_token(key_tail)], value_tokens,
+                &[], &options.policy)
+}
+
+/// Insert a `key = value` line made from ready tokenised pieces into a table,
+/// with comment lines above it.
+pub fn insert_line(tokens: &[Token], table_key: &KeyPath, style: &Style, key: Vec<Token>,
+                   value: Vec<Token>, comments: &[String], policy: &InsertPolicy) -> Vec<Token> {
+    let table_pos = find_table(tokens, table_key).unwrap();
+    let newline = detect_newline(tokens);
+    let key_text = text_of(&key);
+    let name: Vec<String> = key.iter().filter(|t| !is_insignificant(t) && t.text != ".")
+                               .map(key_token_to_string).collect();
+    let name = name.join(".");
+
+    let mut line = Vec::new();
+    for comment in comments {
+        if !style.indent.is_empty() {
+            line.push(Token::from(&style.indent));
+        }
+        line.push(Token::from(comment));
+        line.push(Token::from(newline));
+    }
+    if !style.indent.is_empty() {
+        line.push(Token::from(&style.indent));
+    }
+    let before_eq = padding_before_eq(style, &key_text);
+    line.extend(key);
+    if !before_eq.is_empty() {
+        line.push(Token::from(&before_eq));
+    }
+    line.push(Token::from("="));
+    if !style.after_eq.is_empty() {
+        line.push(Token::from(&style.after_eq));
+    }
+    line.extend(value);
+
+    let slot = kv_slot(tokens, &table_pos, &name, policy);
+    splice_line(tokens, slot, line, newline)
+}
+
+pub fn header_tokens(key: &KeyPath, array: bool) -> Vec<Token> {
+    let mut res = vec![Token::from("[")];
+    if array {
+        res.push(Token::from("["));
+    }
+    for (i, part) in key.parts.iter().enumerate() {
+        if i > 0 {
+            res.push(Token::from("."));
+        }
+        match *part {
+            KeyPathComponent::Key(ref s) => res.push(make_key_token(s)),
+            KeyPathComponent::Ix(_) => panic!("Can't write a table header for {:?}", key)
+        }
+    }
+    res.push(Token::from("]"));
+    if array {
+        res.push(Token::from("]"));
+    }
+    res
+}
+
+fn last_part_name(key: &KeyPath) -> String {
+    match key.parts.last() {
+        Some(KeyPathComponent::Key(s)) => s.clone(),
+        Some(KeyPathComponent::Ix(i)) => i.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Index of the last table in the run of tables nested under tables[i].
+fn subtree_last(tables: &[TablePos], i: usize) -> usize {
+    let mut j = i;
+    while j + 1 < tables.len() && tables[j+1].key.starts_with(&tables[i].key) {
+        j += 1;
+    }
+    j
+}
+
+fn table_slot(tokens: &[Token], tables: &[TablePos], key: &KeyPath, policy: &InsertPolicy) -> (Slot, bool) {
+    let parent = key.parent().unwrap();
+    // Sibling tables, leaving out the root table and [[array]] entries
+    let siblings: Vec<usize> = (1..tables.len()).filter(|&i| {
+        tables[i].key.parent().as_ref() == Some(&parent) &&
+        matches!(tables[i].key.parts.last(), Some(KeyPathComponent::Key(_)))
+    }).collect();
+    let names: Vec<String> = siblings.iter().map(|&i| last_part_name(&tables[i].key)).collect();
+    let name = last_part_name(key);
+
+    let append = {
+        let last = if parent.parts.is_empty() {
+            tables.len() - 1
+        } else {
+            match (1..tables.len()).rev().find(|&i| tables[i].key.starts_with(&parent)) {
+                Some(i) => i,
+                None => tables.len() - 1,
+            }
+        };
+        (Slot::After(content_end(tokens, &tables[last])), false)
+    };
+    let before = |i: usize| {
+        let header_start = tables[i-1].end;
+        (Slot::LineStart(attached_start(tokens, header_start, tables[i-1].start)), true)
+    };
+    match *policy {
+        InsertPolicy::Append => append,
+        InsertPolicy::Alphabetical => {
+            if !is_sorted(&names) {
+                return append;
+            }
+            match names.iter().position(|n| *n > name) {
+                Some(ix) => before(siblings[ix]),
+                None => append,
+            }
+        },
+        InsertPolicy::After(ref sibling) => {
+            match names.iter().position(|n| n == sibling) {
+                Some(ix) => {
+                    let last = subtree_last(tables, siblings[ix]);
+                    (Slot::After(content_end(tokens, &tables[last])), false)
+                },
+                None => append,
+            }
+        },
+        InsertPolicy::Before(ref sibling) => {
+            match names.iter().position(|n| n == sibling) {
+                Some(ix) => before(siblings[ix]),
+                None => append,
+            }
+        },
+    }
+}
+
+/// Add an empty `[table]` section, placed according to the insertion policy.
+///
+/// If the table already exists, the tokens are returned unchanged.
+pub fn insert_table(tokens: &[Token], key: &KeyPath, options: &InsertOptions) -> Vec<Token> {
+    if find_table(tokens, key).is_some() {
+        return tokens.to_vec();
+    }
+    insert_section(tokens, key, false, &[], Vec::new(), &options.policy)
+}
+
+/// Add a `[table]` or `[[array]]` section with the given comment lines above
+/// the header and lines of tokens in the body.
+pub fn insert_section(tokens: &[Token], key: &KeyPath, array: bool, comments: &[String],
+                      body: Vec<Vec<Token>>, policy: &InsertPolicy) -> Vec<Token> {
+    let tables = find_tables(tokens);
+    let newline = detect_newline(tokens);
+    let (slot, at_line_start) = table_slot(tokens, &tables, key, policy);
+    let mut line = Vec::new();
+    match slot {
+        // Leave a blank line between tables
+        Slot::After(pos) if pos > 0 => line.push(Token::from(newline)),
+        _ => ()
+    }
+    for comment in comments {
+        line.push(Token::from(comment));
+        line.push(Token::from(newline));
+    }
+    line.extend(header_tokens(key, array));
+    for body_line in body {
+        line.push(Token::from(newline));
+        line.extend(body_line);
+    }
+    if at_line_start {
+        line.push(Token::from(newline));
+    }
+    splice_line(tokens, slot, line, newline)
+}
+
+fn remove_range(tokens: &[Token], mut start: usize, end: usize) -> Vec<Token> {
+    // Don't leave a dangling newline if we remove the last line
+    let ends_line = end > start && tokens[end-1].kind == TokenType::Newline;
+    if !ends_line && start > 0 && tokens[start-1].kind == TokenType::Newline {
+        start -= 1;
+    }
+    let mut res = tokens[..start].to_vec();
+    res.extend_from_slice(&tokens[end..]);
+    res
+}
+
+/// The comments describing an entry: lines directly above it, and any comment
+/// at the end of its line.
+pub fn entry_comments(tokens: &[Token], table: &TablePos, entry: &EntryPos) -> Vec<String> {
+    entry_comment_positions(tokens, table, entry).into_iter().map(|pos| tokens[pos].text.clone()).collect()
+}
+
+/// Like `entry_comments`, but the positions of the comment tokens.
+pub fn entry_comment_positions(tokens: &[Token], table: &TablePos, entry: &EntryPos) -> Vec<usize> {
+    let start = attached_start(tokens, entry.start, table.start);
+    (start..entry.value_start).chain(entry.value_end..entry.end)
+        .filter(|&pos| tokens[pos].kind == TokenType::Comment)
+        .collect()
+}
+
+/// Remove an entry line along with the comment lines directly above it.
+pub fn remove_entry(tokens: &[Token], table: &TablePos, entry: &EntryPos) -> Vec<Token> {
+    let start = attached_start(tokens, entry.start, table.start);
+    remove_range(tokens, start, entry.end)
+}
+
+/// Where the header of tables[i] starts, including comment lines above it.
+fn section_start(tokens: &[Token], tables: &[TablePos], i: usize) -> usize {
+    attached_start(tokens, tables[i-1].end, tables[i-1].start)
+}
+
+/// The comments above a table header, on its line, and between its entries.
+pub fn section_comments(tokens: &[Token], tables: &[TablePos], i: usize) -> Vec<String> {
+    section_comment_positions(tokens, tables, i).into_iter().map(|pos| tokens[pos].text.clone()).collect()
+}
+
+/// Like `section_comments`, but the positions of the comment tokens.
+pub fn section_comment_positions(tokens: &[Token], tables: &[TablePos], i: usize) -> Vec<usize> {
+    let entries = find_entries(tokens, &tables[i]);
+    let in_value = |pos: usize| entries.iter().any(|e| pos >= e.value_start && pos < e.value_end);
+    (section_start(tokens, tables, i)..tables[i].end)
+        .filter(|&pos| tokens[pos].kind == TokenType::Comment && !in_value(pos))
+        .collect()
+}
+
+/// Remove the sections for the given indices into `tables`, including their
+/// headers and the comment lines above the headers.
+pub fn remove_sections(tokens: &[Token], tables: &[TablePos], indices: &[usize]) -> Vec<Token> {
+    let mut indices = indices.to_vec();
+    indices.sort();
+    let mut res = tokens.to_vec();
+    for &i in indices.iter().rev() {
+        let start = section_start(tokens, tables, i);
+        res = remove_range(&res, start, tables[i].end);
     }
-    res.push(Token::from(&value.to_string()));
-    // Copy tokens after insertion point
-    for tok in &tokens[pos..] {
-        res.push((*tok).clone());
+    // Removing the last section can leave blank lines at the end
+    let blank_count = res.iter().rev().take_while(|t| is_blank_token(t)).count();
+    if blank_count > 1 && indices.last() == Some(&(tables.len() - 1)) {
+        let keep = res.len() - blank_count;
+        let ended_with_newline = tokens.last().map(|t| t.kind == TokenType::Newline).unwrap_or(false);
+        res.truncate(keep);
+        if ended_with_newline {
+            res.push(Token::from(detect_newline(tokens)));
+        }
     }
     res
 }
 
+fn is_blank_token(tok: &Token) -> bool {
+    tok.kind == TokenType::Whitespace || tok.kind == TokenType::Newline
+}
+
+/// Reorder the entries of a table by key, keeping the comment lines directly
+/// above each entry with it.
+///
+/// Blank lines and other layout between entries stay where they are.
+pub fn sort_table(tokens: &[Token], key: &KeyPath) -> Vec<Token> {
+    let table = match find_table(tokens, key) {
+        Some(t) => t,
+        None => return tokens.to_vec(),
+    };
+    let entries = find_entries(tokens, &table);
+    if entries.is_empty() {
+        return tokens.to_vec();
+    }
+    let newline = detect_newline(tokens);
+    // Each chunk is an entry with its comments; gaps are what lies between them
+    let mut chunks = Vec::new();
+    let mut gaps = Vec::new();
+    let mut prev_end = entries[0].start;
+    let first_start = attached_start(tokens, entries[0].start, table.start);
+    for (i, entry) in entries.iter().enumerate() {
+        let start = if i == 0 { first_start } else { attached_start(tokens, entry.start, prev_end) };
+        if i > 0 {
+            gaps.push(&tokens[prev_end..start]);
+        }
+        let mut chunk = tokens[start..entry.end].to_vec();
+        if chunk.last().map(|t| t.kind != TokenType::Newline).unwrap_or(false) {
+            chunk.push(Token::from(newline));
+        }
+        chunks.push((entry_key(tokens, entry), chunk));
+        prev_end = entry.end;
+    }
+    let last_end = prev_end;
+    let ends_with_newline = tokens[last_end-1].kind == TokenType::Newline;
+    chunks.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut res = tokens[..first_start].to_vec();
+    let n_chunks = chunks.len();
+    for (i, (_, chunk)) in chunks.into_iter().enumerate() {
+        res.extend(chunk);
+        if i < gaps.len() {
+            res.extend_from_slice(gaps[i]);
+        }
+        if i + 1 == n_chunks && !ends_with_newline {
+            res.pop();
+        }
+    }
+    res.extend_from_slice(&tokens[last_end..]);
+    res
+}
+
 #[test]
 fn test_find_tables() {
     let inp = vec![
@@ -177,9 +755,34 @@ fn test_find_tables() {
     ])
 }
 
+#[test]
+fn test_find_entries() {
+    let tokens = tokenise("[t]\n  a = 1 # c\n\n  b = [1,\n    2]\n[u]\n");
+    let table = find_table(&tokens, &KeyPath::from_string("t")).unwrap();
+    let entries = find_entries(&tokens, &table);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(tokens[entries[0].start].text, "  ");
+    assert_eq!(tokens[entries[0].key_start].text, "a");
+    assert_eq!(tokens[entries[0].value_start].text, "1");
+    assert_eq!(tokens[entries[0].end - 1].kind, TokenType::Newline);
+    assert_eq!(tokens[entries[1].key_start].text, "b");
+    assert_eq!(tokens[entries[1].value_end - 1].text, "]");
+    assert_eq!(tokens[entries[1].end].text, "[");
+}
+
+#[test]
+fn test_key_positions() {
+    let tokens = tokenise("[[a.\"b\"]]\nc = [\n[\"d\"]]\ne = {f = \"g\"}\n");
+    let keys: Vec<&str> = tokens.iter().zip(key_positions(&tokens))
+                            .filter(|&(_, k)| k).map(|(t, _)| t.text.as_str()).collect();
+    assert_eq!(keys, vec!["a", "\"b\"", "c", "e", "f"]);
+}
+
 #[test]
 fn test_make_key_token() {
     assert_eq!(make_key_token("a_b"), Token{kind: TokenType::BareKey, text: String::from("a_b")});
+    assert_eq!(make_key_token("v2"), Token{kind: TokenType::BareKey, text: String::from("v2")});
+    assert_eq!(make_key_token(""), Token{kind: TokenType::BasicString, text: String::from("\"\"")});
     assert_eq!(make_key_token("a b"), Token{kind: TokenType::BasicString, text: String::from(r#""a b""#)});
 }
 
@@ -209,3 +812,109 @@ b = 2
     }
     assert_eq!(res, exp);
 }
+
+#[test]
+fn test_insert_kv_crlf() {
+    let inp = "[foo]\r\na = 1\r\n\r\n[bar]\r\nb = 2\r\n";
+    let exp = "[foo]\r\na = 1\r\nc = 3\r\n\r\n[bar]\r\nb = 2\r\n";
+    let tokens = tokenise(inp);
+    let res = insert_kv(&tokens, &KeyPath::from_string("foo.c"), toml::Value::Integer(3));
+    assert_eq!(text_of(&res), exp);
+}
+
+#[test]
+fn test_insert_kv_follows_style() {
+    let inp = "\
+[deps]
+    foo      = \"1\"
+    longname = \"2\"
+
+[other]
+";
+    let exp = "\
+[deps]
+    foo      = \"1\"
+    longname = \"2\"
+    bar      = [1, 2]
+
+[other]
+";
+    let tokens = tokenise(inp);
+    let value = toml::Value::Array(vec![toml::Value::Integer(1), toml::Value::Integer(2)]);
+    let res = insert_kv(&tokens, &KeyPath::from_string("deps.bar"), value);
+    assert_eq!(res, tokenise(exp));
+
+    let options = InsertOptions{style: StyleOverride{indent: Some(String::new()),
+                                                     align_eq: Some(None),
+                                                     ..Default::default()},
+                                ..Default::default()};
+    let res = insert_kv_with(&tokens, &KeyPath::from_string("deps.x"), toml::Value::Boolean(true), &options);
+    assert!(text_of(&res).contains("longname = \"2\"\nx = true\n"));
+}
+
+#[test]
+fn test_insert_kv_policies() {
+    let inp = "[deps]\nalpha = 1\n# about gamma\ngamma = 3\n";
+    let tokens = tokenise(inp);
+    let key = KeyPath::from_string("deps.beta");
+    let with_policy = |policy| InsertOptions{policy, ..Default::default()};
+
+    let res = insert_kv_with(&tokens, &key, toml::Value::Integer(2), &with_policy(InsertPolicy::Alphabetical));
+    assert_eq!(text_of(&res), "[deps]\nalpha = 1\nbeta = 2\n# about gamma\ngamma = 3\n");
+    let res = insert_kv_with(&tokens, &key, toml::Value::Integer(2), &with_policy(InsertPolicy::After(String::from("gamma"))));
+    assert_eq!(text_of(&res), "[deps]\nalpha = 1\n# about gamma\ngamma = 3\nbeta = 2\n");
+    let res = insert_kv_with(&tokens, &key, toml::Value::Integer(2), &with_policy(InsertPolicy::Before(String::from("alpha"))));
+    assert_eq!(text_of(&res), "[deps]\nbeta = 2\nalpha = 1\n# about gamma\ngamma = 3\n");
+
+    // Unsorted tables fall back to appending
+    let tokens = tokenise("[deps]\nz = 1\na = 2");
+    let res = insert_kv_with(&tokens, &key, toml::Value::Integer(2), &with_policy(InsertPolicy::Alphabetical));
+    assert_eq!(text_of(&res), "[deps]\nz = 1\na = 2\nbeta = 2");
+}
+
+#[test]
+fn test_insert_table() {
+    let tokens = tokenise("a = 1\n\n[b]\nx = 1\n\n[b.sub]\ny = 2\n\n[d]\nz = 3\n");
+    let with_policy = |policy| InsertOptions{policy, ..Default::default()};
+    let res = insert_table(&tokens, &KeyPath::from_string("c"), &with_policy(InsertPolicy::Alphabetical));
+    assert_eq!(text_of(&res), "a = 1\n\n[b]\nx = 1\n\n[b.sub]\ny = 2\n\n[c]\n\n[d]\nz = 3\n");
+    let res = insert_table(&tokens, &KeyPath::from_string("c"), &with_policy(InsertPolicy::After(String::from("b"))));
+    assert_eq!(text_of(&res), "a = 1\n\n[b]\nx = 1\n\n[b.sub]\ny = 2\n\n[c]\n\n[d]\nz = 3\n");
+    let res = insert_table(&tokens, &KeyPath::from_string("b.other"), &InsertOptions::default());
+    assert_eq!(text_of(&res), "a = 1\n\n[b]\nx = 1\n\n[b.sub]\ny = 2\n\n[b.other]\n\n[d]\nz = 3\n");
+    let res = insert_table(&tokens, &KeyPath::from_string("e"), &InsertOptions::default());
+    assert_eq!(text_of(&res), "a = 1\n\n[b]\nx = 1\n\n[b.sub]\ny = 2\n\n[d]\nz = 3\n\n[e]\n");
+
+    let res = insert_table(&tokenise(""), &KeyPath::from_string("e"), &InsertOptions::default());
+    let res = insert_kv(&res, &KeyPath::from_string("e.k"), toml::Value::Boolean(true));
+    assert_eq!(text_of(&res), "[e]\nk = true");
+}
+
+#[test]
+fn test_sort_table() {
+    let inp = "\
+[deps]
+# Comment about c
+c = 3
+a = 1
+
+# Comment about b
+b = 2 # trailing
+[other]
+";
+    let exp = "\
+[deps]
+a = 1
+# Comment about b
+b = 2 # trailing
+
+# Comment about c
+c = 3
+[other]
+";
+    let res = sort_table(&tokenise(inp), &KeyPath::from_string("deps"));
+    assert_eq!(text_of(&res), exp);
+
+    let res = sort_table(&tokenise("b = 2\na = 1"), &KeyPath::new());
+    assert_eq!(text_of(&res), "a = 1\nb = 2");
+}
@@ -1,3 +1,5 @@
+use super::error::Error;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum TokenType {
     Punctuation,
@@ -13,6 +15,8 @@ pub enum TokenType {
     Float,
     Boolean,
     Datetime,
+    /// Text that couldn't be tokenised, from `tokenise_recovering`
+    Error,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -24,7 +28,7 @@ pub struct Token {
 impl Token {
     pub fn from(s: &str) -> Token {
         use TokenType::*;
-        if s == "" {
+        if s.is_empty() {
             panic!("Token::from got empty string")
         }
         let kind = if s.starts_with(r#"""""#) {
@@ -36,16 +40,19 @@ impl Token {
         } else {
             match s.chars().next().unwrap() {
                 ' ' | '\t' => Whitespace,
-                '\n' | '\r' => Newline,
+                '\n' => Newline,
+                '\r' if s == "\r\n" => Newline,
+                // A CR on its own isn't a line ending, or anything else
+                '\r' => TokenType::Error,
                 '#' => Comment,
                 '"' => BasicString,
                 '\'' => LiteralString,
-                '-' | '+' | '0'...'9' => get_number_or_datetime_kind(s),
+                '-' | '+' | '0'..='9' => get_number_or_datetime_kind(s),
                 '[' | ']' | '{' | '}' | '=' | '.' | ',' => Punctuation,
                 _ => BareKey,
             }
         };
-        Token{kind: kind, text: String::from(s)}
+        Token{kind, text: String::from(s)}
     }
 }
 
@@ -86,9 +93,40 @@ fn read_whitespace(s: &str) -> (Token, &str) {
     (Token{kind: TokenType::Whitespace, text:String::from(tok)}, remainder)
 }
 
-fn read_newline(s: &str) -> (Token, &str) {
-    let (tok, remainder) = chars_while!(s, '\n', '\r');
-    (Token{kind: TokenType::Newline, text:String::from(tok)}, remainder)
+fn read_newline(s: &str) -> Result<(Token, &str), String> {
+    // One token per line ending: either LF or CRLF. A CR on its own is not
+    // a valid line ending in TOML.
+    let len = if s.starts_with("\r\n") {
+        2
+    } else if s.starts_with('\n') {
+        1
+    } else {
+        return Err(String::from("bare carriage return"))
+    };
+    let (tok, remainder) = s.split_at(len);
+    Ok((Token{kind: TokenType::Newline, text:String::from(tok)}, remainder))
+}
+
+/// Join tokens back into text.
+pub fn text_of(tokens: &[Token]) -> String {
+    let mut res = String::new();
+    for tok in tokens {
+        res.push_str(&tok.text);
+    }
+    res
+}
+
+/// Find the line ending used in a token stream, so that edits can match it.
+///
+/// The first newline in the document decides; documents without any newlines
+/// get LF.
+pub fn detect_newline(tokens: &[Token]) -> &'static str {
+    for tok in tokens {
+        if tok.kind == TokenType::Newline {
+            return if tok.text == "\r\n" { "\r\n" } else { "\n" }
+        }
+    }
+    "\n"
 }
 
 fn read_comment(s: &str) -> (Token, &str) {
@@ -97,14 +135,19 @@ fn read_comment(s: &str) -> (Token, &str) {
 }
 
 pub fn read_punctuation(s: &str) -> (Token, &str) {
-    // Punctuation is always 1 character (and 1 byte in UTF-8)
-    (Token{kind: TokenType::Punctuation, text:String::from(&s[..1])}, &s[1..])
+    // Punctuation is always 1 character
+    let len = s.chars().next().map(char::len_utf8).unwrap_or(0);
+    let (tok, remainder) = s.split_at(len);
+    (Token{kind: TokenType::Punctuation, text:String::from(tok)}, remainder)
 }
 
 fn get_number_or_datetime_kind(tok: &str) -> TokenType {
-    if tok.contains('e') || tok.contains('E') {
+    let unsigned = tok.trim_start_matches(['+', '-']);
+    if unsigned.starts_with("0x") || unsigned.starts_with("0o") || unsigned.starts_with("0b") {
+        TokenType::Integer
+    } else if unsigned == "inf" || unsigned == "nan" || tok.contains('e') || tok.contains('E') {
         TokenType::Float
-    } else if tok.contains('-') && !tok.starts_with('-') {
+    } else if tok.contains(':') || (tok.contains('-') && !tok.starts_with('-')) {
         TokenType::Datetime
     } else if tok.contains('.') {
         TokenType::Float
@@ -113,99 +156,162 @@ fn get_number_or_datetime_kind(tok: &str) -> TokenType {
     }
 }
 
+/// Is this a full date, like `1979-05-27`?
+fn is_full_date(tok: &str) -> bool {
+    let b = tok.as_bytes();
+    b.len() == 10 && b[4] == b'-' && b[7] == b'-' &&
+        b.iter().enumerate().all(|(i, c)| i == 4 || i == 7 || c.is_ascii_digit())
+}
+
 pub fn read_number_or_datetime(s: &str) -> (Token, &str) {
-    let (tok,  remainder) = chars_until!(s, ' ', '\t', '\n', '\r', '#', ']', '}');
+    let (mut tok, mut remainder) = chars_until!(s, ' ', '\t', '\n', '\r', '#', ',', ']', '}');
+    // A space can separate the date and time, which are still one value
+    let b = remainder.as_bytes();
+    if is_full_date(tok) && b.len() > 3 && b[0] == b' ' && b[1].is_ascii_digit() &&
+            b[2].is_ascii_digit() && b[3] == b':' {
+        let (time, rest) = chars_until!(remainder[1..], ' ', '\t', '\n', '\r', '#', ',', ']', '}');
+        tok = &s[..tok.len() + 1 + time.len()];
+        remainder = rest;
+    }
     let kind  = get_number_or_datetime_kind(tok);
-    (Token{kind: kind, text:String::from(tok)}, remainder)
+    (Token{kind, text:String::from(tok)}, remainder)
 }
 
 fn read_boolean(s: &str) -> (Token, &str) {
-    let (tok,  remainder) = chars_until!(s, ' ', '\t', '\n', '\r', '#');
+    let (tok,  remainder) = chars_until!(s, ' ', '\t', '\n', '\r', '#', ',', ']', '}');
     (Token{kind: TokenType::Boolean, text:String::from(tok)}, remainder)
 }
 
 pub fn read_bare_key(s: &str) -> (Token, &str) {
-    let (tok, remainder) = chars_while!(s, 'A'...'Z', 'a'...'z', '0'...'9', '_', '-');
+    let (tok, remainder) = chars_while!(s, 'A'..='Z', 'a'..='z', '0'..='9', '_', '-');
     (Token{kind: TokenType::BareKey, text:String::from(tok)}, remainder)
 }
 
-pub fn read_literal_string(s: &str) -> (Token, &str) {
-    let mut ends_at = s.len();
+/// Length of the run of `quote` characters at the start of s.
+fn quote_run(s: &str, quote: char) -> usize {
+    s.chars().take_while(|&c| c == quote).count()
+}
+
+/// Where a multiline string closes, given the position of a run of quotes in
+/// it. The last three quotes of a run close the string, so up to two quotes
+/// can come directly before them.
+fn multiline_close(s: &str, pos: usize, quote: char) -> Option<usize> {
+    let run = quote_run(&s[pos..], quote);
+    if run >= 3 {
+        Some(pos + run.min(5))
+    } else {
+        None
+    }
+}
+
+/// Find the end of a literal string at the start of s: its kind, where the
+/// token ends, and whether it was closed. Strings which aren't multiline
+/// end at a line ending even if they aren't closed.
+fn scan_literal_string(s: &str) -> (TokenType, usize, bool) {
     let (offset, kind) = if s.starts_with("'''") {
         (3, TokenType::MultilineLiteralString)
     } else {
         (1, TokenType::LiteralString)
     };
-    for (i, c) in s[offset..].char_indices() {
-        match c {
-            '\'' => {
-                if kind == TokenType::MultilineLiteralString {
-                    if s[i+offset..].starts_with("'''") {
-                        ends_at = i+6; // +6 for 2x triple quotes
-                        break;
-                    }
-                } else {
-                    ends_at = i+2; // +2 for 2x single quotes
-                    break;
-                }
-            },
-            _ => ()
+    let mut skip_to = offset;
+    for (i, c) in s.char_indices().skip(offset) {
+        if kind == TokenType::LiteralString && (c == '\n' || c == '\r') {
+            return (kind, i, false);
+        }
+        if i < skip_to || c != '\'' {
+            continue;
+        }
+        if kind == TokenType::MultilineLiteralString {
+            match multiline_close(s, i, '\'') {
+                Some(end) => return (kind, end, true),
+                None => skip_to = i + quote_run(&s[i..], '\''),
+            }
+        } else {
+            return (kind, i+1, true);
         }
     }
+    (kind, s.len(), false)
+}
+
+pub fn read_literal_string(s: &str) -> (Token, &str) {
+    let (kind, ends_at, _) = scan_literal_string(s);
     let (tok,  remainder) = s.split_at(ends_at);
-    (Token{kind: kind, text: String::from(tok)}, remainder)
+    (Token{kind, text: String::from(tok)}, remainder)
 }
 
-pub fn read_basic_string(s: &str) -> (Token, &str) {
-    let mut ends_at = s.len();
+/// Like `scan_literal_string`, for basic strings.
+fn scan_basic_string(s: &str) -> (TokenType, usize, bool) {
     let (offset, kind) = if s.starts_with("\"\"\"") {
         (3, TokenType::MultilineBasicString)
     } else {
         (1, TokenType::BasicString)
     };
     let mut escape = false;
-    for (i, c) in s[offset..].char_indices() {
+    let mut skip_to = offset;
+    for (i, c) in s.char_indices().skip(offset) {
+        if i < skip_to {
+            continue;
+        }
         match c {
             '\\' => escape = !escape,
+            '\n' | '\r' if kind == TokenType::BasicString => return (kind, i, false),
             '"' => {
                 if escape {
                     escape = false;
                     continue;
                 }
                 if kind == TokenType::MultilineBasicString {
-                    if s[i+offset..].starts_with("\"\"\"") {
-                        ends_at = i+6; // +6 for 2x triple quotes
-                        break;
+                    match multiline_close(s, i, '"') {
+                        Some(end) => return (kind, end, true),
+                        None => skip_to = i + quote_run(&s[i..], '"'),
                     }
                 } else {
-                    ends_at = i+2; // +2 for 2x single quotes
-                    break;
+                    return (kind, i+1, true);
                 }
             },
             _ => escape = false
         }
     }
+    (kind, s.len(), false)
+}
+
+pub fn read_basic_string(s: &str) -> (Token, &str) {
+    let (kind, ends_at, _) = scan_basic_string(s);
     let (tok,  remainder) = s.split_at(ends_at);
-    (Token{kind: kind, text: String::from(tok)}, remainder)
+    (Token{kind, text: String::from(tok)}, remainder)
+}
+
+/// Read a string of either kind. A multiline string which isn't closed runs
+/// to the end of the text, and comes with a problem to report; other strings
+/// which aren't closed are an error.
+fn read_string(s: &str) -> Result<(Token, &str, Option<&'static str>), String> {
+    let (kind, ends_at, closed) = if s.starts_with('"') {
+        scan_basic_string(s)
+    } else {
+        scan_literal_string(s)
+    };
+    let (tok, remainder) = s.split_at(ends_at);
+    let problem = match kind {
+        _ if closed => None,
+        TokenType::BasicString | TokenType::LiteralString => return Err(String::from("unterminated string")),
+        _ => Some("unterminated multiline string"),
+    };
+    Ok((Token{kind, text: String::from(tok)}, remainder, problem))
 }
 
-fn key_context(in_rhs: bool, bracket_stack: &Vec<char>, tokens: &Vec<Token>) -> bool {
+fn key_context(in_rhs: bool, bracket_stack: &[char], tokens: &[Token]) -> bool {
     if in_rhs {
         if bracket_stack.last() == Some(&'{') {
             for token in tokens.iter().rev() {
                 match token.kind {
                     TokenType::Whitespace => continue,
                     TokenType::Punctuation => {
-                        if token.text == "," || token.text == "{" {
-                            return true
-                        } else {
-                            return false
-                        }
+                        return token.text == "," || token.text == "{" || token.text == "."
                     },
                     _ => {return false}
                 }
             }
-            panic!()
+            false
         } else {
             false
         }
@@ -214,67 +320,221 @@ fn key_context(in_rhs: bool, bracket_stack: &Vec<char>, tokens: &Vec<Token>) ->
     }
 }
 
+/// Where a comment or string has a control character, which TOML doesn't
+/// allow there, as a byte offset into the token. Tabs are fine, and so are
+/// line endings in multiline strings.
+fn control_char_at(tok: &Token) -> Option<usize> {
+    let multiline = match tok.kind {
+        TokenType::Comment | TokenType::BasicString | TokenType::LiteralString => false,
+        TokenType::MultilineBasicString | TokenType::MultilineLiteralString => true,
+        _ => return None,
+    };
+    tok.text.char_indices().find(|&(i, c)| {
+        let line_ending = c == '\n' || (c == '\r' && tok.text[i..].starts_with("\r\n"));
+        (c < ' ' || c == '\x7f') && c != '\t' && !(multiline && line_ending)
+    }).map(|(i, _)| i)
+}
+
+/// The 1-based line and column of a byte offset into some text.
+pub fn line_col(s: &str, offset: usize) -> (usize, usize) {
+    let before = &s[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Split TOML text into tokens.
+///
+/// Panics if the text can't be tokenised; use `try_tokenise` for text that
+/// might not be valid, or `tokenise_recovering` to carry on past problems.
 pub fn tokenise(s: &str) -> Vec<Token> {
+    match try_tokenise(s) {
+        Ok(tokens) => tokens,
+        Err(e) => panic!("{}", e)
+    }
+}
+
+/// A problem found by `tokenise_recovering`.
+#[derive(Debug,PartialEq,Clone)]
+pub struct Diagnostic {
+    /// The index of the `Error` token covering the bad text
+    pub token: usize,
+    /// 1-based line and column where the problem is
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn to_error(&self) -> Error {
+        Error::Parse(self.line, self.col, self.message.clone())
+    }
+}
+
+/// Pick up again after bad text, at the start of the next line. Brackets
+/// opened and closed in the bad text, outside strings and comments, are
+/// followed, and inline tables, which can't span lines, are abandoned; only
+/// arrays opened after `=` are still open.
+fn resync(bad: &str, in_rhs: &mut bool, bracket_stack: &mut Vec<char>) {
+    let mut quote = None;
+    let mut escape = false;
+    for c in bad.chars() {
+        match quote {
+            Some(q) => {
+                if escape {
+                    escape = false;
+                } else if c == '\\' && q == '"' {
+                    escape = true;
+                } else if c == q {
+                    quote = None;
+                }
+            },
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '#' => break,
+                '[' | '{' => bracket_stack.push(c),
+                ']' | '}' => {
+                    bracket_stack.pop();
+                },
+                _ => (),
+            },
+        }
+    }
+    if !*in_rhs {
+        bracket_stack.clear();
+    }
+    let inline_table = bracket_stack.iter().position(|&c| c == '{');
+    if let Some(i) = inline_table {
+        bracket_stack.truncate(i);
+    }
+    *in_rhs = !bracket_stack.is_empty();
+}
+
+/// Split TOML text into tokens, carrying on past text which can't be
+/// tokenised. Each bad region becomes an `Error` token, running to the end of
+/// its line, with a diagnostic saying what was wrong, so joining the tokens
+/// still gives back the text, apart from a byte order mark at the start:
+/// that's skipped, so it isn't in the tokens.
+pub fn tokenise_recovering(s: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let s = s.strip_prefix('\u{feff}').unwrap_or(s);
     let mut tokens = Vec::new();
+    let mut diagnostics = Vec::new();
     let mut remainder = s;
     let mut in_rhs = false;
     let mut bracket_stack = Vec::new();
+    let mut report = |tokens: &Vec<Token>, offset: usize, msg: &str| {
+        let (line, col) = line_col(s, offset);
+        diagnostics.push(Diagnostic{token: tokens.len(), line, col, message: String::from(msg)});
+    };
     loop {
+        let offset = s.len() - remainder.len();
         let next_char = remainder.chars().next();
-        let (next_token, rem) = match next_char {
+        let mut unclosed = None;
+        let res = match next_char {
             None => {break;},
             Some(c) => {match c {
-                ' '|'\t' => read_whitespace(remainder),
+                ' '|'\t' => Ok(read_whitespace(remainder)),
                 '\n'|'\r' => {
-                    if bracket_stack.is_empty() {
+                    let res = read_newline(remainder);
+                    if res.is_ok() && bracket_stack.is_empty() {
                         in_rhs = false;
                     }
-                    read_newline(remainder)
+                    res
                 },
-                '#' => read_comment(remainder),
+                '#' => Ok(read_comment(remainder)),
                 '['|'{' => {
                     bracket_stack.push(c);
-                    read_punctuation(remainder)
+                    Ok(read_punctuation(remainder))
                 },
                 ']'|'}' => {
-                    bracket_stack.pop().unwrap();
-                    read_punctuation(remainder)
+                    if bracket_stack.pop().is_none() {
+                        Err(String::from("unmatched closing bracket"))
+                    } else {
+                        Ok(read_punctuation(remainder))
+                    }
                 },
                 '=' => {
                     in_rhs = true;
-                    read_punctuation(remainder)
+                    Ok(read_punctuation(remainder))
                 }
                 '.'|',' => {
-                    read_punctuation(remainder)
+                    Ok(read_punctuation(remainder))
                 },
-                '+' => read_number_or_datetime(remainder),
-                '0'...'9'|'-' => {
+                '+' => Ok(read_number_or_datetime(remainder)),
+                '0'..='9'|'-' => {
                     if key_context(in_rhs, &bracket_stack, &tokens) {
-                        read_bare_key(remainder)
+                        Ok(read_bare_key(remainder))
                     } else {
-                        read_number_or_datetime(remainder)
+                        Ok(read_number_or_datetime(remainder))
                     }
                 },
                 't'|'f' => {
                     if key_context(in_rhs, &bracket_stack, &tokens) {
-                        read_bare_key(remainder)
+                        Ok(read_bare_key(remainder))
                     } else {
-                        read_boolean(remainder)
+                        Ok(read_boolean(remainder))
                     }
                 },
-                'A'...'Z'|'a'...'z'|'_' => {
-                    assert!(key_context(in_rhs, &bracket_stack, &tokens));
-                    read_bare_key(remainder)
+                'A'..='Z'|'a'..='z'|'_' => {
+                    if key_context(in_rhs, &bracket_stack, &tokens) {
+                        Ok(read_bare_key(remainder))
+                    } else if remainder.starts_with("inf") || remainder.starts_with("nan") {
+                        Ok(read_number_or_datetime(remainder))
+                    } else {
+                        Err(String::from("expected a value"))
+                    }
                 },
-                '\'' => read_literal_string(remainder),
-                '"' => read_basic_string(remainder),
-                _ => panic!("Unexpected char")
+                '\'' | '"' => read_string(remainder).map(|(tok, rem, problem)| {
+                    unclosed = problem;
+                    (tok, rem)
+                }),
+                _ => Err(format!("unexpected character {:?}", c))
 
             }}
         };
-        tokens.push(next_token);
-        remainder = rem;
+        match res {
+            Ok((mut next_token, rem)) => {
+                if let Some(msg) = unclosed {
+                    // Nothing closes it, so the rest of the text is bad
+                    report(&tokens, offset, msg);
+                    next_token.kind = TokenType::Error;
+                } else if let Some(i) = control_char_at(&next_token) {
+                    report(&tokens, offset + i, "control character not allowed here");
+                    next_token.kind = TokenType::Error;
+                }
+                tokens.push(next_token);
+                remainder = rem;
+            },
+            Err(msg) => {
+                report(&tokens, offset, &msg);
+                // Always take the first character, so a bare CR can't stop us
+                let first = next_char.unwrap().len_utf8();
+                let (_, rest) = chars_until!(remainder[first..], '\n', '\r');
+                let (bad, rem) = remainder.split_at(remainder.len() - rest.len());
+                resync(bad, &mut in_rhs, &mut bracket_stack);
+                tokens.push(Token{kind: TokenType::Error, text: String::from(bad)});
+                remainder = rem;
+            },
+        }
+    }
+    (tokens, diagnostics)
+}
+
+/// Split TOML text into tokens, or report where it can't be tokenised.
+///
+/// A byte order mark at the start is skipped, so it isn't in the tokens.
+pub fn try_tokenise(s: &str) -> Result<Vec<Token>, Error> {
+    let (tokens, diagnostics) = tokenise_recovering(s);
+    match diagnostics.first() {
+        Some(d) => Err(d.to_error()),
+        None => Ok(tokens),
     }
+}
+
+/// Tokenise a value on its own, as it would appear on the right of `=`.
+pub fn tokenise_value(s: &str) -> Vec<Token> {
+    let mut tokens = tokenise(&format!("x={}", s));
+    tokens.drain(..2);
     tokens
 }
 
@@ -287,7 +547,26 @@ fn test_read_whitespace() {
 #[test]
 fn test_read_newline() {
     let res = read_newline("\n\r\na");
-    assert_eq!(res, (Token{kind: TokenType::Newline, text: String::from("\n\r\n")}, "a"))
+    assert_eq!(res, Ok((Token{kind: TokenType::Newline, text: String::from("\n")}, "\r\na")));
+    let res = read_newline("\r\n\na");
+    assert_eq!(res, Ok((Token{kind: TokenType::Newline, text: String::from("\r\n")}, "\na")));
+}
+
+#[test]
+fn test_read_bare_cr() {
+    assert_eq!(read_newline("\ra"), Err(String::from("bare carriage return")));
+    assert_eq!(Token::from("\r").kind, TokenType::Error);
+    assert_eq!(Token::from("\r\n").kind, TokenType::Newline);
+}
+
+#[test]
+fn test_tokenise_blank_lines() {
+    let res = tokenise("a = 1\r\n\r\nb = 2\r\n");
+    let newlines: Vec<&Token> = res.iter().filter(|t| t.kind == TokenType::Newline).collect();
+    assert_eq!(newlines.len(), 3);
+    assert!(newlines.iter().all(|t| t.text == "\r\n"));
+    assert_eq!(detect_newline(&res), "\r\n");
+    assert_eq!(detect_newline(&tokenise("a = 1\n")), "\n");
 }
 
 #[test]
@@ -308,8 +587,13 @@ fn test_read_number_or_datetime() {
             (Token{kind: TokenType::Float, text: String::from("6.626e-34")}, " "));
     assert_eq!(read_number_or_datetime("-12\n"),
             (Token{kind: TokenType::Integer, text: String::from("-12")}, "\n"));
+    assert_eq!(read_number_or_datetime("3,4]"),
+            (Token{kind: TokenType::Integer, text: String::from("3")}, ",4]"));
     assert_eq!(read_number_or_datetime("1979-05-27 "),
             (Token{kind: TokenType::Datetime, text: String::from("1979-05-27")}, " "));
+    assert_eq!(read_number_or_datetime("1979-05-27 07:32:00Z]"),
+            (Token{kind: TokenType::Datetime, text: String::from("1979-05-27 07:32:00Z")}, "]"));
+    assert_eq!(read_number_or_datetime("1979-05-27 # c").0.text, "1979-05-27");
 }
 
 #[test]
@@ -334,6 +618,105 @@ fn test_read_basic_string() {
             (Token{kind: TokenType::BasicString, text: String::from(r#""foo\"\nbar""#)}, " "));
     assert_eq!(read_basic_string(r#""""foo"\nbar\"""" "#),
             (Token{kind: TokenType::MultilineBasicString, text: String::from(r#""""foo"\nbar\"""""#)}, " "));
+    // Up to two quotes can go next to the closing quotes
+    assert_eq!(read_basic_string(r#"""""foo"""" "#).0.text, r#"""""foo"""""#);
+    assert_eq!(read_basic_string(r#""""a\\""" "#).0.text, r#""""a\\""""#);
+    assert_eq!(read_basic_string(r#""""a\\\"""" "#).0.text, r#""""a\\\"""""#);
+    assert_eq!(read_literal_string("'''''x''''' ").0.text, "'''''x'''''");
+}
+
+#[test]
+fn test_try_tokenise_errors() {
+    assert_eq!(try_tokenise("a = 1\nb = [1]]\n"),
+               Err(Error::Parse(2, 8, String::from("unmatched closing bracket"))));
+    assert_eq!(try_tokenise("a = x"), Err(Error::Parse(1, 5, String::from("expected a value"))));
+    assert_eq!(try_tokenise("a = 1\rb = 2"), Err(Error::Parse(1, 6, String::from("bare carriage return"))));
+    assert_eq!(try_tokenise("x = inf").unwrap()[4].kind, TokenType::Float);
+    assert_eq!(try_tokenise("a = \"x\u{1}\""),
+               Err(Error::Parse(1, 7, String::from("control character not allowed here"))));
+    assert_eq!(try_tokenise("# \u{7f}\n"),
+               Err(Error::Parse(1, 3, String::from("control character not allowed here"))));
+    assert_eq!(try_tokenise("a = [1, \u{2014}]"),
+               Err(Error::Parse(1, 9, String::from("unexpected character '\u{2014}'"))));
+    assert_eq!(try_tokenise("\u{feff}a = 1").unwrap()[0], Token::from("a"));
+}
+
+#[test]
+fn test_multiline_no_close() {
+    let unterminated = |line, col| Err(Error::Parse(line, col, String::from("unterminated multiline string")));
+    assert_eq!(try_tokenise("a = \"\"\"abc\n"), unterminated(1, 5));
+    assert_eq!(try_tokenise("a = '''abc\nb = 1\n"), unterminated(1, 5));
+    // Two quotes don't close it
+    assert_eq!(try_tokenise("x = 1\na = \"\"\"abc\"\""), unterminated(2, 5));
+    assert_eq!(try_tokenise("a = '''abc''"), unterminated(1, 5));
+    // An escaped quote doesn't either
+    assert_eq!(try_tokenise("a = \"\"\"abc\\\"\"\""), unterminated(1, 5));
+
+    let (tokens, diagnostics) = tokenise_recovering("a = \"\"\"abc\nb = 1\n");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(tokens[diagnostics[0].token], Token{kind: TokenType::Error, text: String::from("\"\"\"abc\nb = 1\n")});
+}
+
+#[test]
+fn test_string_no_close() {
+    assert_eq!(try_tokenise("a = \"abc\nb = 1\n"), Err(Error::Parse(1, 5, String::from("unterminated string"))));
+    assert_eq!(try_tokenise("a = 'abc\r\n"), Err(Error::Parse(1, 5, String::from("unterminated string"))));
+    assert_eq!(read_basic_string("\"abc\nx").0.text, "\"abc");
+
+    // Carry on at the next line
+    let text = "a = \"abc\nb = 1\nc = 'x\nd = [1,\n  2]\n";
+    let (tokens, diagnostics) = tokenise_recovering(text);
+    assert_eq!(tokens.iter().map(|t| t.text.as_str()).collect::<String>(), text);
+    let errors: Vec<(&str, usize, &str)> = diagnostics.iter().map(|d| {
+        (tokens[d.token].text.as_str(), d.line, d.message.as_str())
+    }).collect();
+    assert_eq!(errors, vec![("\"abc", 1, "unterminated string"), ("'x", 3, "unterminated string")]);
+    assert!(tokens.iter().any(|t| *t == Token::from("b")));
+    assert!(tokens.iter().any(|t| *t == Token::from("d")));
+}
+
+#[test]
+fn test_tokenise_value() {
+    let res = tokenise_value("[true, 1]");
+    assert_eq!(res, vec![Token::from("["), Token::from("true"), Token::from(","),
+                         Token::from(" "), Token::from("1"), Token::from("]")]);
+    assert_eq!(res[1].kind, TokenType::Boolean);
+}
+
+#[test]
+fn test_tokenise_recovering() {
+    let text = "a = [1, x]\nb = 2\nc = {d = \u{2014}, e = 1}\nf = [\n  1, y,\n  2,\n]\ng = \"\u{1}\"\n";
+    let (tokens, diagnostics) = tokenise_recovering(text);
+    assert_eq!(tokens.iter().map(|t| t.text.as_str()).collect::<String>(), text);
+    let errors: Vec<(&str, usize, usize, &str)> = diagnostics.iter().map(|d| {
+        (tokens[d.token].text.as_str(), d.line, d.col, d.message.as_str())
+    }).collect();
+    assert_eq!(errors, vec![
+        ("x]", 1, 9, "expected a value"),
+        ("\u{2014}, e = 1}", 3, 10, "unexpected character '\u{2014}'"),
+        ("y,", 5, 6, "expected a value"),
+        ("\"\u{1}\"", 8, 6, "control character not allowed here"),
+    ]);
+    assert!(diagnostics.iter().all(|d| tokens[d.token].kind == TokenType::Error));
+    // Everything else tokenised as normal
+    assert_eq!(tokens.iter().filter(|t| t.kind == TokenType::Integer).count(), 4);
+    assert!(tokens.iter().any(|t| *t == Token::from("b")));
+    assert!(tokens.iter().any(|t| *t == Token::from("g")));
+
+    let (tokens, diagnostics) = tokenise_recovering("a = 1\r\rb = 2\n]\n");
+    assert_eq!(tokens.iter().map(|t| t.text.as_str()).collect::<String>(), "a = 1\r\rb = 2\n]\n");
+    assert_eq!(diagnostics.len(), 3);
+    assert_eq!(diagnostics[2].message, "unmatched closing bracket");
+
+    // Brackets in strings in the bad text don't count
+    let (tokens, diagnostics) = tokenise_recovering("a = [\n  x, \"]\",\n  2,\n]\nb = 1\n");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(tokens[diagnostics[0].token].text, "x, \"]\",");
+    assert!(tokens.iter().any(|t| t.kind == TokenType::Integer && t.text == "2"));
+    // A byte order mark is skipped
+    let (tokens, diagnostics) = tokenise_recovering("\u{feff}a = ?\n");
+    assert_eq!(tokens.iter().map(|t| t.text.as_str()).collect::<String>(), "a = ?\n");
+    assert_eq!((diagnostics[0].line, diagnostics[0].col), (1, 5));
 }
 
 // #[test]
@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn tomledit(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tomledit")).args(args).output().unwrap()
+}
+
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("tomledit-{}-{}.toml", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+const SAMPLE: &str = "# Settings\n[package]\nname = \"demo\"  # the name\nversion = \"0.1.0\"\n";
+
+#[test]
+fn test_cli_get_set_delete() {
+    let path = temp_file("edit", SAMPLE);
+    let file = path.to_str().unwrap();
+
+    let out = tomledit(&["get", file, "package.name"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "demo\n");
+
+    let out = tomledit(&["set", file, "package.name", "\"other\""]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout),
+               "# Settings\n[package]\nname = \"other\"  # the name\nversion = \"0.1.0\"\n");
+    // Without --in-place the file is left alone
+    assert_eq!(fs::read_to_string(&path).unwrap(), SAMPLE);
+
+    let out = tomledit(&["set", "--diff", file, "package.version", "\"0.2.0\""]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), format!("\
+--- a/{0}
++++ b/{0}
+@@ -1,4 +1,4 @@
+ # Settings
+ [package]
+ name = \"demo\"  # the name
+-version = \"0.1.0\"
++version = \"0.2.0\"
+", file));
+    assert_eq!(fs::read_to_string(&path).unwrap(), SAMPLE);
+
+    let out = tomledit(&["delete", "--in-place", file, "package.version"]);
+    assert!(out.status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "# Settings\n[package]\nname = \"demo\"  # the name\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_cli_errors() {
+    let path = temp_file("bad", "[package]\nname = \n");
+    let file = path.to_str().unwrap();
+    let out = tomledit(&["get", file, "package.name"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&out.stderr),
+               format!("tomledit: {}:2:8: expected a value\n", file));
+    fs::remove_file(&path).unwrap();
+
+    let path = temp_file("missing", SAMPLE);
+    let file = path.to_str().unwrap();
+    assert_eq!(tomledit(&["get", file, "package.license"]).status.code(), Some(1));
+    assert_eq!(tomledit(&["set", file, "package.name", "unquoted"]).status.code(), Some(1));
+    assert_eq!(tomledit(&["frobnicate", file]).status.code(), Some(2));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_cli_apply() {
+    let path = temp_file("apply", SAMPLE);
+    let file = path.to_str().unwrap();
+    let script = env::temp_dir().join(format!("tomledit-script-{}.json", std::process::id()));
+    fs::write(&script, r#"[{"op": "set", "key": "package.version", "value": "0.2.0"},
+                          {"op": "append", "key": "package.authors", "value": "Me"}]"#).unwrap();
+    let out = tomledit(&["apply", "-i", file, script.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap(),
+               "# Settings\n[package]\nname = \"demo\"  # the name\nversion = \"0.2.0\"\nauthors = [\"Me\"]\n");
+
+    // Nothing is written if an edit fails
+    fs::write(&script, r#"[{"op": "remove", "key": "package.name"},
+                          {"op": "remove", "key": "package.name"}]"#).unwrap();
+    let out = tomledit(&["apply", "-i", file, script.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&out.stderr),
+               format!("tomledit: {}: edit 2 failed: key not found: .package.name\n", file));
+    assert!(fs::read_to_string(&path).unwrap().contains("name = \"demo\""));
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&script).unwrap();
+}
+
+#[test]
+fn test_cli_convert() {
+    let path = temp_file("convert", SAMPLE);
+    let out = tomledit(&["convert", path.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout),
+               "{\n  \"package\": {\n    \"name\": \"demo\",\n    \"version\": \"0.1.0\"\n  }\n}\n");
+    fs::remove_file(&path).unwrap();
+
+    let yaml = env::temp_dir().join(format!("tomledit-convert-{}.yaml", std::process::id()));
+    fs::write(&yaml, "package:\n  name: demo\n").unwrap();
+    let out = tomledit(&["convert", yaml.to_str().unwrap()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "[package]\nname = \"demo\"\n");
+    fs::remove_file(&yaml).unwrap();
+}
+
+#[test]
+fn test_cli_show() {
+    let path = temp_file("show", "a = 1 # n\nb = ?\n");
+    let file = path.to_str().unwrap();
+    // Not a terminal, so no colours by default
+    let out = tomledit(&["show", file]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "a = 1 # n\nb = ?\n");
+
+    let out = tomledit(&["show", "--color=always", file]);
+    assert_eq!(String::from_utf8_lossy(&out.stdout),
+               "\x1b[34ma\x1b[0m = \x1b[36m1\x1b[0m \x1b[90m# n\x1b[0m\n\x1b[34mb\x1b[0m = \x1b[4;31m?\x1b[0m\n");
+
+    let out = tomledit(&["show", "--html", file]);
+    assert!(String::from_utf8_lossy(&out.stdout).starts_with(
+        "<pre class=\"toml\"><span class=\"toml-key\">a</span> <span class=\"toml-punctuation\">=</span>"));
+    fs::remove_file(&path).unwrap();
+
+    let path = temp_file("color-diff", SAMPLE);
+    let out = tomledit(&["set", "--diff", "--color=always", path.to_str().unwrap(), "package.version", "\"0.2.0\""]);
+    let text = String::from_utf8_lossy(&out.stdout);
+    assert!(text.contains("\x1b[31m-\x1b[0m\x1b[34mversion\x1b[0m = \x1b[32m\"0.1.0\"\x1b[0m\n"));
+    assert!(text.contains("\x1b[32m+\x1b[0m\x1b[34mversion\x1b[0m = \x1b[32m\"0.2.0\"\x1b[0m\n"));
+    fs::remove_file(&path).unwrap();
+}
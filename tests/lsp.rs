@@ -0,0 +1,86 @@
+#![cfg(feature = "lsp")]
+
+extern crate rustc_serialize;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use rustc_serialize::json::Json;
+
+fn frame(body: &str) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
+
+/// Run the server on a script of messages, giving back what it sent.
+fn run_script(messages: &[&str]) -> (Vec<Json>, bool) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_tomledit-lsp"))
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    let input: String = messages.iter().map(|m| frame(m)).collect();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    let mut text = String::from_utf8(out.stdout).unwrap();
+    let mut replies = Vec::new();
+    while let Some(start) = text.find("\r\n\r\n") {
+        let length: usize = text[..start].trim_start_matches("Content-Length: ").parse().unwrap();
+        let body = text[start + 4..start + 4 + length].to_string();
+        replies.push(Json::from_str(&body).unwrap());
+        text = text[start + 4 + length..].to_string();
+    }
+    (replies, out.status.success())
+}
+
+fn find<'a>(json: &'a Json, path: &[&str]) -> &'a Json {
+    json.find_path(path).unwrap_or_else(|| panic!("{:?} not in {}", path, json))
+}
+
+#[test]
+fn test_lsp_session() {
+    let (replies, ok) = run_script(&[
+        r#"{"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}"#,
+        r#"{"jsonrpc": "2.0", "method": "initialized", "params": {}}"#,
+        r#"{"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument":
+            {"uri": "file:///c.toml", "languageId": "toml", "version": 1,
+             "text": "a = @\n[t]\nb = [1, ?]\n"}}}"#,
+        r#"{"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": {"uri": "file:///c.toml", "version": 2},
+            "contentChanges": [{"text": "a  =  1\n[t]\nb = 2\n"}]}}"#,
+        r#"{"jsonrpc": "2.0", "id": 2, "method": "textDocument/documentSymbol",
+            "params": {"textDocument": {"uri": "file:///c.toml"}}}"#,
+        r#"{"jsonrpc": "2.0", "id": 3, "method": "textDocument/formatting",
+            "params": {"textDocument": {"uri": "file:///c.toml"}, "options": {"tabSize": 4, "insertSpaces": true}}}"#,
+        r#"{"jsonrpc": "2.0", "id": 4, "method": "textDocument/rename", "params": {}}"#,
+        r#"{"jsonrpc": "2.0", "id": 5, "method": "shutdown"}"#,
+        r#"{"jsonrpc": "2.0", "method": "exit"}"#,
+    ]);
+    assert!(ok);
+    assert_eq!(replies.len(), 7);
+    assert_eq!(find(&replies[0], &["result", "capabilities", "hoverProvider"]), &Json::Boolean(true));
+
+    // Both bad values are reported, each where it is
+    let diagnostics = find(&replies[1], &["params", "diagnostics"]).as_array().unwrap();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(find(&diagnostics[0], &["message"]).as_string(), Some("unexpected character '@'"));
+    assert_eq!(find(&diagnostics[1], &["range", "start", "line"]), &Json::U64(2));
+    assert_eq!(find(&diagnostics[1], &["range", "start", "character"]), &Json::U64(8));
+    // Fixed
+    assert_eq!(find(&replies[2], &["params", "diagnostics"]), &Json::Array(Vec::new()));
+
+    let symbols = find(&replies[3], &["result"]).as_array().unwrap();
+    let names: Vec<&str> = symbols.iter().map(|s| find(s, &["name"]).as_string().unwrap()).collect();
+    assert_eq!(names, vec!["a", "t"]);
+    assert_eq!(find(&symbols[1], &["children"]).as_array().unwrap().len(), 1);
+
+    let edits = find(&replies[4], &["result"]).as_array().unwrap();
+    assert_eq!(find(&edits[0], &["newText"]).as_string(), Some("a = 1\n\n[t]\nb = 2\n"));
+    assert_eq!(find(&edits[0], &["range", "end", "line"]), &Json::U64(3));
+
+    assert_eq!(find(&replies[5], &["error", "code"]), &Json::I64(-32601));
+    assert_eq!(find(&replies[6], &["result"]), &Json::Null);
+}
+
+#[test]
+fn test_lsp_exit_without_shutdown() {
+    let (replies, ok) = run_script(&[r#"{"jsonrpc": "2.0", "method": "exit"}"#]);
+    assert!(replies.is_empty());
+    assert!(!ok);
+}
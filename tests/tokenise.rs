@@ -1,6 +1,6 @@
 extern crate tomledit;
 
-use tomledit::{Token, TokenType, tokenise};
+use tomledit::{Token, TokenType, Error, tokenise, try_tokenise};
 
 #[test]
 fn test_tokenise() {
@@ -18,3 +18,9 @@ foo="bar"
     assert_eq!(res[8], Token{kind: TokenType::BareKey, text: String::from("12")});
     assert_eq!(res[10], Token{kind: TokenType::Integer, text: String::from("34")});
 }
+
+#[test]
+fn test_try_tokenise() {
+    assert_eq!(try_tokenise("a = \u{2014}\n"), Err(Error::Parse(1, 5, String::from("unexpected character '\u{2014}'"))));
+    assert_eq!(try_tokenise("a = 1\n").unwrap(), tokenise("a = 1\n"));
+}
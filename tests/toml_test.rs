@@ -0,0 +1,175 @@
+//! Run the toml-test corpus, vendored in tests/toml-test, against the
+//! tokeniser and decoder.
+//!
+//! Valid files must decode to the tagged JSON next to them and turn back into
+//! exactly the same text; invalid files must give an `Error`, not a panic or
+//! a document. Cases we're known to get wrong are listed in
+//! tests/toml-test/known-failures.txt, and a listed case which passes is also
+//! reported, so the list only ever shrinks.
+
+extern crate rustc_serialize;
+extern crate tomledit;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::json::Json;
+use tomledit::{Document, JsonFormat, to_json};
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("toml-test")
+}
+
+/// Every .toml file under a directory, as paths relative to the corpus.
+fn toml_files(dir: &Path, res: &mut Vec<String>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            toml_files(&path, res);
+        } else if path.extension().is_some_and(|e| e == "toml") {
+            let rel = path.strip_prefix(corpus_dir()).unwrap();
+            res.push(rel.to_string_lossy().replace('\\', "/"));
+        }
+    }
+}
+
+fn known_failures() -> Vec<String> {
+    let text = fs::read_to_string(corpus_dir().join("known-failures.txt")).unwrap();
+    text.lines()
+        .map(|l| l.split('#').next().unwrap().trim())
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_float(s: &str) -> Option<f64> {
+    match s {
+        "nan" | "+nan" | "-nan" => Some(f64::NAN),
+        "inf" | "+inf" => Some(f64::INFINITY),
+        "-inf" => Some(f64::NEG_INFINITY),
+        _ => s.replace('_', "").parse().ok(),
+    }
+}
+
+/// Write dates and times the same way whichever form the file used.
+fn normalise_datetime(s: &str) -> String {
+    let mut res = s.to_uppercase().replacen(' ', "T", 1);
+    if res.ends_with("+00:00") {
+        res.truncate(res.len() - 6);
+        res.push('Z');
+    }
+    // Fractional seconds: .6 is the same as .600000
+    if let Some(dot) = res.find('.') {
+        let digits = res[dot + 1..].chars().take_while(|c| c.is_ascii_digit()).count();
+        let fraction = res[dot + 1..dot + 1 + digits].trim_end_matches('0').to_string();
+        let rest = res[dot + 1 + digits..].to_string();
+        res.truncate(dot);
+        if !fraction.is_empty() {
+            res.push('.');
+            res.push_str(&fraction);
+        }
+        res.push_str(&rest);
+    }
+    res
+}
+
+fn tagged_value(obj: &BTreeMap<String, Json>) -> Option<(&str, &str)> {
+    match (obj.len(), obj.get("type"), obj.get("value")) {
+        (2, Some(Json::String(t)), Some(Json::String(v))) => Some((t, v)),
+        _ => None,
+    }
+}
+
+fn same_json(actual: &Json, expected: &Json) -> bool {
+    match (actual, expected) {
+        (Json::Object(a), Json::Object(e)) => {
+            if let (Some((at, av)), Some((et, ev))) = (tagged_value(a), tagged_value(e)) {
+                return at == et && match et {
+                    "float" => match (parse_float(av), parse_float(ev)) {
+                        (Some(x), Some(y)) => x == y || (x.is_nan() && y.is_nan()),
+                        _ => false,
+                    },
+                    "datetime" | "datetime-local" | "date-local" | "time-local" => {
+                        normalise_datetime(av) == normalise_datetime(ev)
+                    },
+                    _ => av == ev,
+                };
+            }
+            a.len() == e.len() && a.iter().all(|(k, v)| e.get(k).is_some_and(|ev| same_json(v, ev)))
+        },
+        (Json::Array(a), Json::Array(e)) => {
+            a.len() == e.len() && a.iter().zip(e).all(|(x, y)| same_json(x, y))
+        },
+        _ => actual == expected,
+    }
+}
+
+/// Why a valid file failed, if it did.
+fn check_valid(rel: &str) -> Option<String> {
+    let path = corpus_dir().join(rel);
+    let text = match String::from_utf8(fs::read(&path).unwrap()) {
+        Ok(t) => t,
+        Err(_) => return Some(String::from("not UTF-8")),
+    };
+    let expected = Json::from_str(&fs::read_to_string(path.with_extension("json")).unwrap()).unwrap();
+    let doc = match panic::catch_unwind(|| Document::parse(&text)) {
+        Ok(Ok(doc)) => doc,
+        Ok(Err(e)) => return Some(format!("rejected: {}", e)),
+        Err(_) => return Some(String::from("panicked")),
+    };
+    if doc.to_string() != text {
+        return Some(String::from("didn't round-trip"));
+    }
+    // Every written value can be found from where it's written
+    if panic::catch_unwind(|| doc.entries().count()).is_err() {
+        return Some(String::from("entries didn't match the values"));
+    }
+    match to_json(&doc, JsonFormat::Tagged) {
+        Ok(ref json) if same_json(json, &expected) => None,
+        Ok(json) => Some(format!("decoded as {}", json)),
+        Err(e) => Some(format!("couldn't convert: {}", e)),
+    }
+}
+
+/// Why an invalid file failed, if it did.
+fn check_invalid(rel: &str) -> Option<String> {
+    let text = match String::from_utf8(fs::read(corpus_dir().join(rel)).unwrap()) {
+        Ok(t) => t,
+        Err(_) => return None,
+    };
+    match panic::catch_unwind(|| Document::parse(&text)) {
+        Ok(Ok(_)) => Some(String::from("accepted")),
+        Ok(Err(_)) => None,
+        Err(_) => Some(String::from("panicked")),
+    }
+}
+
+fn run(kind: &str, check: fn(&str) -> Option<String>) {
+    let mut files = Vec::new();
+    toml_files(&corpus_dir().join(kind), &mut files);
+    assert!(!files.is_empty());
+    let known = known_failures();
+    let mut problems = Vec::new();
+    for rel in &files {
+        match (check(rel), known.contains(rel)) {
+            (Some(why), false) => problems.push(format!("{}: {}", rel, why)),
+            (None, true) => problems.push(format!("{}: passes, so take it out of known-failures.txt", rel)),
+            _ => (),
+        }
+    }
+    assert!(problems.is_empty(), "{} of {} {} cases:\n{}", problems.len(), files.len(), kind,
+            problems.join("\n"));
+}
+
+#[test]
+fn test_toml_test_valid() {
+    run("valid", check_valid);
+}
+
+#[test]
+fn test_toml_test_invalid() {
+    run("invalid", check_invalid);
+}